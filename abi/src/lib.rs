@@ -0,0 +1,137 @@
+//! The system call ABI, shared by the kernel and user programs.
+//!
+//! A system call is made with the `syscall` instruction: the number (see
+//! [`Syscall`]) goes in `rax`, and up to six arguments in `rdi`, `rsi`, `rdx`,
+//! `r10`, `r8` and `r9`. The result comes back in `rax`, with `rcx` and `r11`
+//! clobbered; every other register is preserved.
+//!
+//! Results are `u64`s, except for the top 4095 values, which are errors
+//! (`-(error as i64)`, see [`encode`] and [`decode`]).
+
+#![no_std]
+
+use core::fmt;
+
+pub mod sys;
+
+/// Where [`Syscall::Write`] to the console goes
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// Results from here up are errors
+const FIRST_ERROR: u64 = -4095i64 as u64;
+
+macro_rules! syscalls {
+    ($($(#[$attr:meta])* $name:ident = $number:literal,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[repr(u64)]
+        pub enum Syscall {
+            $($(#[$attr])* $name = $number,)*
+        }
+
+        impl Syscall {
+            pub const ALL: &'static [Syscall] = &[$(Syscall::$name,)*];
+
+            pub fn from_number(number: u64) -> Option<Syscall> {
+                match number {
+                    $($number => Some(Syscall::$name),)*
+                    _ => None,
+                }
+            }
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Syscall::$name => stringify!($name),)*
+                }
+            }
+        }
+    };
+}
+
+syscalls! {
+    /// `exit(code: i32) -> !`: ends the calling thread
+    Exit = 0,
+    /// `write(fd: u64, buf: *const u8, len: usize) -> usize`: writes UTF-8 text
+    /// to [`STDOUT`] or [`STDERR`], returning how many bytes were written
+    Write = 1,
+    /// `yield() -> ()`: lets other threads run
+    Yield = 2,
+    /// `sleep(nanos: u64) -> ()`: blocks for at least `nanos` nanoseconds
+    Sleep = 3,
+    /// `uptime() -> u64`: nanoseconds since boot
+    Uptime = 4,
+}
+
+impl Syscall {
+    /// One more than the highest number
+    pub const COUNT: usize = Syscall::ALL.len();
+
+    pub fn number(self) -> u64 {
+        self as u64
+    }
+}
+
+impl fmt::Display for Syscall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u64)]
+pub enum Error {
+    /// No system call has that number
+    NoSuchSyscall = 1,
+    /// A pointer argument isn't to memory the caller can access
+    BadPointer = 2,
+    /// No file descriptor has that number, or it can't be used that way
+    BadDescriptor = 3,
+    /// An argument is out of range, or text isn't UTF-8
+    InvalidArgument = 4,
+}
+
+impl Error {
+    pub fn from_code(code: u64) -> Option<Error> {
+        Some(match code {
+            1 => Error::NoSuchSyscall,
+            2 => Error::BadPointer,
+            3 => Error::BadDescriptor,
+            4 => Error::InvalidArgument,
+            _ => return None,
+        })
+    }
+
+    pub fn code(self) -> u64 {
+        self as u64
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NoSuchSyscall => write!(f, "no such system call"),
+            Error::BadPointer => write!(f, "bad pointer"),
+            Error::BadDescriptor => write!(f, "bad file descriptor"),
+            Error::InvalidArgument => write!(f, "invalid argument"),
+        }
+    }
+}
+
+/// What a system call returns in `rax`. Values that would look like
+/// errors can't be returned, so they're turned into `InvalidArgument`.
+pub fn encode(result: Result<u64, Error>) -> u64 {
+    match result {
+        Ok(value) if value < FIRST_ERROR => value,
+        Ok(_) => encode(Err(Error::InvalidArgument)),
+        Err(e) => e.code().wrapping_neg(),
+    }
+}
+
+/// The result of a system call, from `rax`. Unknown errors are `InvalidArgument`.
+pub fn decode(value: u64) -> Result<u64, Error> {
+    if value < FIRST_ERROR {
+        return Ok(value);
+    }
+
+    Err(Error::from_code(value.wrapping_neg()).unwrap_or(Error::InvalidArgument))
+}
@@ -0,0 +1,54 @@
+//! System calls, for user programs
+
+use core::{arch::asm, time::Duration};
+
+use crate::{decode, Error, Syscall};
+
+/// Makes system call `syscall` with `args`, returning `rax` as is.
+///
+/// # Safety
+///
+/// The arguments must be what the system call expects, e.g. pointers to
+/// memory it may read or write.
+pub unsafe fn syscall(syscall: Syscall, args: [u64; 6]) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") syscall.number() => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+        in("r10") args[3],
+        in("r8") args[4],
+        in("r9") args[5],
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall(Syscall::Exit, [code as u64, 0, 0, 0, 0, 0]) };
+    unreachable!("exit returned");
+}
+
+/// Writes UTF-8 text to `fd` ([`STDOUT`](crate::STDOUT) or [`STDERR`](crate::STDERR))
+pub fn write(fd: u64, text: &[u8]) -> Result<usize, Error> {
+    let result = unsafe { syscall(Syscall::Write, [fd, text.as_ptr() as u64, text.len() as u64, 0, 0, 0]) };
+    decode(result).map(|written| written as usize)
+}
+
+pub fn yield_now() {
+    unsafe { syscall(Syscall::Yield, [0; 6]) };
+}
+
+pub fn sleep(duration: Duration) {
+    let nanos = duration.as_nanos().min(u64::MAX as u128) as u64;
+    unsafe { syscall(Syscall::Sleep, [nanos, 0, 0, 0, 0, 0]) };
+}
+
+pub fn uptime() -> Duration {
+    let nanos = unsafe { syscall(Syscall::Uptime, [0; 6]) };
+    Duration::from_nanos(decode(nanos).unwrap_or(0))
+}
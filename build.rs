@@ -1,4 +1,11 @@
-use std::path::PathBuf;
+use std::{fs::{self, File}, path::{Path, PathBuf}};
+
+use object::{Object, ObjectSymbol, SymbolKind};
+
+/// Must match the path read by `kernel::cmdline`
+const CMDLINE_PATH: &str = "boot/cmdline";
+/// Must match the path read by `kernel::backtrace`
+const SYMBOLS_PATH: &str = "boot/kernel.sym";
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Automatically set by cargo
@@ -11,17 +18,127 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .map(PathBuf::from)
         .unwrap();
 
-    // Create UEFI disk image
-    let uefi_path = out_dir.join("uefi.img");
-    bootloader::UefiBoot::new(&kernel).create_disk_image(&uefi_path)?;
+    // The test kernel (`kernel/src/bin/tests.rs`), booted by `cargo run -- --test`
+    let test_kernel: PathBuf = std::env::var_os("CARGO_BIN_FILE_KERNEL_tests")
+        .map(PathBuf::from)
+        .unwrap();
 
-    // Create BIOS disk image
-    let bios_path = out_dir.join("bios.img");
-    bootloader::BiosBoot::new(&kernel).create_disk_image(&bios_path)?;
+    // The screenshot kernel (`kernel/src/bin/screenshot.rs`), booted by `cargo run -- --screenshot`
+    let screenshot_kernel: PathBuf = std::env::var_os("CARGO_BIN_FILE_KERNEL_screenshot")
+        .map(PathBuf::from)
+        .unwrap();
+
+    let rootfs = rootfs_dir();
 
-    // Pass disk image path as env variables to the `main.rs`
+    // e.g. `loglevel=warn fb.scale=1 test=heap`
+    println!("cargo:rerun-if-env-changed=KLEOS_CMDLINE");
+    let cmdline = std::env::var("KLEOS_CMDLINE").unwrap_or_default();
+
+    // Each kernel gets its own ramdisk, as it carries the kernel's symbol table
+    let disk_images = |kernel: &Path, suffix: &str| {
+        let ramdisk = create_ramdisk(rootfs.as_deref(), &cmdline, kernel, &out_dir, suffix)?;
+        create_disk_images(kernel, &ramdisk, &out_dir, suffix)
+    };
+
+    let (uefi_path, bios_path) = disk_images(&kernel, "")?;
+    let (uefi_test_path, bios_test_path) = disk_images(&test_kernel, "-tests")?;
+    let (uefi_screenshot_path, bios_screenshot_path) = disk_images(&screenshot_kernel, "-screenshot")?;
+
+    // Pass the kernel ELFs (for debugger symbols) and disk image paths
+    // as env variables to the `main.rs`
+    println!("cargo:rustc-env=KERNEL_PATH={}", kernel.display());
+    println!("cargo:rustc-env=TEST_KERNEL_PATH={}", test_kernel.display());
+    println!("cargo:rustc-env=SCREENSHOT_KERNEL_PATH={}", screenshot_kernel.display());
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
+    println!("cargo:rustc-env=UEFI_TEST_PATH={}", uefi_test_path.display());
+    println!("cargo:rustc-env=BIOS_TEST_PATH={}", bios_test_path.display());
+    println!("cargo:rustc-env=UEFI_SCREENSHOT_PATH={}", uefi_screenshot_path.display());
+    println!("cargo:rustc-env=BIOS_SCREENSHOT_PATH={}", bios_screenshot_path.display());
 
     Ok(())
 }
+
+/// The rootfs directory (`rootfs/`, or `KLEOS_ROOTFS`), if it exists
+fn rootfs_dir() -> Option<PathBuf> {
+    println!("cargo:rerun-if-env-changed=KLEOS_ROOTFS");
+
+    let manifest_dir = PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let rootfs = std::env::var_os("KLEOS_ROOTFS")
+        .map(|dir| manifest_dir.join(dir))
+        .unwrap_or_else(|| manifest_dir.join("rootfs"));
+
+    // Directories are checked recursively by cargo
+    println!("cargo:rerun-if-changed={}", rootfs.display());
+
+    if !rootfs.is_dir() {
+        println!("cargo:warning=rootfs `{}` does not exist, the ramdisk only holds `boot/`", rootfs.display());
+        return None;
+    }
+
+    Some(rootfs)
+}
+
+/// Packs the rootfs, the kernel command line and `kernel`'s symbol table into a tar
+/// archive that the bootloader loads as the kernel's ramdisk (see `kernel::initrd`,
+/// `kernel::cmdline` and `kernel::backtrace`).
+fn create_ramdisk(rootfs: Option<&Path>, cmdline: &str, kernel: &Path, out_dir: &Path, suffix: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let ramdisk_path = out_dir.join(format!("initrd{suffix}.tar"));
+
+    let mut archive = tar::Builder::new(File::create(&ramdisk_path)?);
+    if let Some(rootfs) = rootfs {
+        archive.append_dir_all(".", rootfs)?;
+    }
+
+    // Written last, replacing any `boot/` files from the rootfs
+    let symbols = symbol_table(kernel)?;
+    for (path, data) in [ (CMDLINE_PATH, cmdline.as_bytes()), (SYMBOLS_PATH, symbols.as_bytes()) ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive.append_data(&mut header, path, data)?;
+    }
+
+    archive.finish()?;
+
+    Ok(ramdisk_path)
+}
+
+/// Lists `kernel`'s functions as `<address> <size> <name>` lines (hex, as linked),
+/// sorted by address, so the kernel can name its own stack frames without
+/// debug info or a heap.
+fn symbol_table(kernel: &Path) -> Result<String, Box<dyn std::error::Error>> {
+    let data = fs::read(kernel)?;
+    let elf = object::File::parse(&*data)?;
+
+    let mut symbols: Vec<_> = elf.symbols()
+        .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.size() > 0)
+        .filter_map(|symbol| Some((symbol.address(), symbol.size(), symbol.name().ok()?)))
+        .collect();
+    symbols.sort_by_key(|&(address, _, _)| address);
+    symbols.dedup_by_key(|&mut (address, _, _)| address);
+
+    let table = symbols.into_iter()
+        // `{:#}` leaves out the hash
+        .map(|(address, size, name)| format!("{address:016x} {size:x} {:#}\n", rustc_demangle::demangle(name)))
+        .collect();
+
+    Ok(table)
+}
+
+fn create_disk_images(kernel: &Path, ramdisk: &Path, out_dir: &Path, suffix: &str) -> Result<(PathBuf, PathBuf), Box<dyn std::error::Error>> {
+    // Create UEFI disk image
+    let uefi_path = out_dir.join(format!("uefi{suffix}.img"));
+    let mut uefi = bootloader::UefiBoot::new(kernel);
+    uefi.set_ramdisk(ramdisk);
+    uefi.create_disk_image(&uefi_path)?;
+
+    // Create BIOS disk image
+    let bios_path = out_dir.join(format!("bios{suffix}.img"));
+    let mut bios = bootloader::BiosBoot::new(kernel);
+    bios.set_ramdisk(ramdisk);
+    bios.create_disk_image(&bios_path)?;
+
+    Ok((uefi_path, bios_path))
+}
@@ -0,0 +1,106 @@
+//! The Fixed ACPI Description Table (signature `FACP`): power management hardware.
+
+use x86_64::instructions::port::Port;
+
+use super::{read_u16, read_u32, read_u64, AddressSpace, GenericAddress, Sdt};
+
+/// Set in `flags` if the PM timer counts with 32 bits rather than 24
+const TMR_VAL_EXT: u32 = 1 << 8;
+/// Set in `iapc_boot_arch` if there are 8042 (PS/2) controllers
+const IAPC_8042: u16 = 1 << 1;
+/// Set in `flags` if `reset_register` is supported
+const RESET_REG_SUP: u32 = 1 << 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// Physical address of the DSDT
+    pub dsdt: u64,
+    /// The interrupt (8259 IRQ) the SCI is wired to
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_control_block: u32,
+    pub pm_timer: Option<PmTimer>,
+    /// Index of the century register of the RTC, if it has one
+    pub century_register: Option<u8>,
+    pub iapc_boot_arch: u16,
+    pub flags: u32,
+    /// Writing `reset_value` here resets the system
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+/// The ACPI power management timer, which counts at 3.579545 MHz
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmTimer {
+    pub register: GenericAddress,
+    /// Whether the counter has 32 bits rather than 24
+    pub extended: bool,
+}
+
+impl PmTimer {
+    pub const FREQUENCY: u64 = 3_579_545;
+
+    /// The current count, if the timer is in I/O space (it always is on PCs)
+    pub fn read(&self) -> Option<u32> {
+        if self.register.address_space != AddressSpace::SystemIo {
+            return None;
+        }
+
+        let count: u32 = unsafe { Port::new(self.register.address as u16).read() };
+        Some(if self.extended { count } else { count & 0xFF_FFFF })
+    }
+
+    /// The value the counter wraps at
+    pub fn modulus(&self) -> u64 {
+        if self.extended { 1 << 32 } else { 1 << 24 }
+    }
+}
+
+impl Fadt {
+    pub(super) fn parse(table: &Sdt) -> Option<Self> {
+        let data = table.data;
+
+        let flags = read_u32(data, 112)?;
+
+        // ACPI 2.0 added 64 bit versions of the addresses, which take precedence if set
+        let dsdt = read_u64(data, 140)
+            .filter(|&addr| addr != 0)
+            .unwrap_or(read_u32(data, 40)? as u64);
+
+        let pm_timer = GenericAddress::parse(data, 208)
+            .filter(|register| register.address != 0)
+            .or_else(|| {
+                let port = read_u32(data, 76)?;
+                (port != 0).then_some(GenericAddress {
+                    address_space: AddressSpace::SystemIo,
+                    bit_width: 32,
+                    bit_offset: 0,
+                    access_size: 3,
+                    address: port as u64,
+                })
+            })
+            .map(|register| PmTimer { register, extended: flags & TMR_VAL_EXT != 0 });
+
+        Some(Fadt {
+            dsdt,
+            sci_interrupt: read_u16(data, 46)?,
+            smi_command_port: read_u32(data, 48)?,
+            acpi_enable: data[52],
+            acpi_disable: data[53],
+            pm1a_control_block: read_u32(data, 64)?,
+            pm_timer,
+            century_register: Some(data[108]).filter(|&index| index != 0),
+            iapc_boot_arch: read_u16(data, 109)?,
+            flags,
+            reset_register: GenericAddress::parse(data, 116).filter(|_| flags & RESET_REG_SUP != 0),
+            reset_value: data.get(128).copied().unwrap_or(0),
+        })
+    }
+
+    /// Whether there is a PS/2 controller. Firmware doesn't always set this, so it's only a hint.
+    pub fn has_8042(&self) -> bool {
+        self.iapc_boot_arch & IAPC_8042 != 0
+    }
+}
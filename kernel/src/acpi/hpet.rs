@@ -0,0 +1,32 @@
+//! The High Precision Event Timer table (signature `HPET`).
+
+use super::{read_u16, read_u32, GenericAddress, Sdt};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    /// The capabilities register of the timer block: vendor, comparator count, etc.
+    pub event_timer_block_id: u32,
+    /// Where the registers are, normally in system memory
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// Minimum period for periodic interrupts, in ticks
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub(super) fn parse(table: &Sdt) -> Option<Self> {
+        let body = table.body();
+
+        Some(Hpet {
+            event_timer_block_id: read_u32(body, 0)?,
+            base_address: GenericAddress::parse(body, 4)?,
+            hpet_number: *body.get(16)?,
+            minimum_tick: read_u16(body, 17)?,
+        })
+    }
+
+    /// Number of comparators (timers) in the block
+    pub fn comparators(&self) -> u8 {
+        ((self.event_timer_block_id >> 8) & 0x1F) as u8 + 1
+    }
+}
@@ -0,0 +1,200 @@
+//! The Multiple APIC Description Table (signature `APIC`): the interrupt controllers.
+
+use alloc::vec::Vec;
+
+use super::{read_u16, read_u32, read_u64, Sdt};
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    /// Physical address of each CPU's local APIC
+    pub local_apic_address: u64,
+    /// The system also has dual 8259 PICs, which have to be disabled
+    pub has_legacy_pics: bool,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptSourceOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+/// A CPU, with its local APIC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    /// Whether the CPU can be used now
+    pub enabled: bool,
+    /// Whether a disabled CPU can be brought online later
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    /// Physical address of the registers
+    pub address: u32,
+    /// The first global system interrupt it handles
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that isn't identity mapped to a global system interrupt,
+/// or doesn't use the ISA polarity and trigger mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptSourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+/// A local APIC input connected to NMI
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    /// `None` for every processor
+    pub processor_uid: Option<u32>,
+    /// `LINT0` or `LINT1`
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// Whatever the bus uses, active high for ISA
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Whatever the bus uses, edge for ISA
+    BusDefault,
+    Edge,
+    Level,
+}
+
+impl Madt {
+    pub(super) fn parse(table: &Sdt) -> Option<Self> {
+        let body = table.body();
+
+        let mut madt = Madt {
+            local_apic_address: read_u32(body, 0)? as u64,
+            has_legacy_pics: read_u32(body, 4)? & 1 == 1,
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut entries = body.get(8..)?;
+        while let [kind, length, ..] = *entries {
+            let length = length as usize;
+            if length < 2 || length > entries.len() {
+                break;
+            }
+
+            let (entry, rest) = entries.split_at(length);
+            entries = rest;
+
+            madt.parse_entry(kind, entry);
+        }
+
+        Some(madt)
+    }
+
+    /// Entries too short for their kind, and unknown kinds, are skipped
+    fn parse_entry(&mut self, kind: u8, entry: &[u8]) -> Option<()> {
+        match kind {
+            0 => {
+                let flags = read_u32(entry, 4)?;
+                self.local_apics.push(LocalApic {
+                    processor_uid: entry[2] as u32,
+                    apic_id: entry[3] as u32,
+                    enabled: flags & 1 == 1,
+                    online_capable: flags & 2 == 2,
+                });
+            },
+            1 => self.io_apics.push(IoApic {
+                id: *entry.get(2)?,
+                address: read_u32(entry, 4)?,
+                gsi_base: read_u32(entry, 8)?,
+            }),
+            2 => {
+                let (polarity, trigger_mode) = parse_mps_inti_flags(read_u16(entry, 8)?);
+                self.overrides.push(InterruptSourceOverride {
+                    irq: *entry.get(3)?,
+                    gsi: read_u32(entry, 4)?,
+                    polarity,
+                    trigger_mode,
+                });
+            },
+            4 => {
+                let (polarity, trigger_mode) = parse_mps_inti_flags(read_u16(entry, 3)?);
+                self.nmis.push(LocalApicNmi {
+                    processor_uid: (entry[2] != 0xFF).then_some(entry[2] as u32),
+                    lint: *entry.get(5)?,
+                    polarity,
+                    trigger_mode,
+                });
+            },
+            5 => self.local_apic_address = read_u64(entry, 4)?,
+            9 => {
+                let flags = read_u32(entry, 8)?;
+                self.local_apics.push(LocalApic {
+                    processor_uid: read_u32(entry, 12)?,
+                    apic_id: read_u32(entry, 4)?,
+                    enabled: flags & 1 == 1,
+                    online_capable: flags & 2 == 2,
+                });
+            },
+            0xA => {
+                let (polarity, trigger_mode) = parse_mps_inti_flags(read_u16(entry, 2)?);
+                let processor_uid = read_u32(entry, 4)?;
+                self.nmis.push(LocalApicNmi {
+                    processor_uid: (processor_uid != u32::MAX).then_some(processor_uid),
+                    lint: *entry.get(8)?,
+                    polarity,
+                    trigger_mode,
+                });
+            },
+            _ => { },
+        }
+
+        Some(())
+    }
+
+    /// The global system interrupt ISA `irq` is connected to, with its polarity and trigger mode
+    pub fn isa_irq(&self, irq: u8) -> (u32, Polarity, TriggerMode) {
+        self.overrides.iter()
+            .find(|o| o.irq == irq)
+            .map_or((irq as u32, Polarity::BusDefault, TriggerMode::BusDefault), |o| (o.gsi, o.polarity, o.trigger_mode))
+    }
+
+    /// The IOAPIC handling global system interrupt `gsi`, and which of its inputs that is.
+    ///
+    /// How many inputs an IOAPIC has is only in its registers, so that isn't checked.
+    pub fn io_apic_for(&self, gsi: u32) -> Option<(&IoApic, u8)> {
+        let io_apic = self.io_apics.iter()
+            .filter(|io_apic| io_apic.gsi_base <= gsi)
+            .max_by_key(|io_apic| io_apic.gsi_base)?;
+
+        Some((io_apic, u8::try_from(gsi - io_apic.gsi_base).ok()?))
+    }
+}
+
+/// Decodes the polarity (bits 0-1) and trigger mode (bits 2-3) of MPS INTI flags
+fn parse_mps_inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault,
+    };
+
+    let trigger_mode = match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault,
+    };
+
+    (polarity, trigger_mode)
+}
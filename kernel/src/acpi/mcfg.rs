@@ -0,0 +1,48 @@
+//! The PCI Express memory mapped configuration table (signature `MCFG`).
+
+use alloc::vec::Vec;
+
+use super::{read_u16, read_u64, Sdt};
+
+/// Size of each configuration space allocation
+const ENTRY_LENGTH: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+/// The memory mapped configuration space of a range of buses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// Physical address of the configuration space of bus 0 (even if it isn't in the range)
+    pub base_address: u64,
+    pub segment_group: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    pub(super) fn parse(table: &Sdt) -> Option<Self> {
+        // The entries follow 8 reserved bytes
+        let entries = table.body().get(8..)?
+            .chunks_exact(ENTRY_LENGTH)
+            .map(|entry| McfgEntry {
+                base_address: read_u64(entry, 0).unwrap(),
+                segment_group: read_u16(entry, 8).unwrap(),
+                start_bus: entry[10],
+                end_bus: entry[11],
+            })
+            .collect();
+
+        Some(Mcfg { entries })
+    }
+
+    /// Physical address of the configuration space of a PCI function
+    pub fn config_address(&self, segment_group: u16, bus: u8, device: u8, function: u8) -> Option<u64> {
+        let entry = self.entries.iter()
+            .find(|e| e.segment_group == segment_group && (e.start_bus..=e.end_bus).contains(&bus))?;
+
+        Some(entry.base_address + ((bus as u64) << 20 | (device as u64) << 15 | (function as u64) << 12))
+    }
+}
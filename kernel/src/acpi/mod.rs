@@ -0,0 +1,247 @@
+//! ACPI table discovery, starting from the RSDP the bootloader found
+
+use core::{fmt, str};
+
+use spin::Once;
+
+use crate::PHYSICAL_MEM_OFFSET;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::Madt;
+pub use mcfg::Mcfg;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Size of the ACPI 1.0 RSDP, which the first checksum covers
+const RSDP_V1_LENGTH: usize = 20;
+/// Size of the ACPI 2.0 RSDP
+const RSDP_V2_LENGTH: usize = 36;
+
+/// Size of the header common to every system description table
+const HEADER_LENGTH: usize = 36;
+
+static ACPI: Once<Acpi> = Once::new();
+
+/// Everything the kernel knows from the firmware's ACPI tables
+#[derive(Debug)]
+pub struct Acpi {
+    /// 0 for ACPI 1.0, 2 for later versions
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+}
+
+impl Acpi {
+    pub fn oem_id(&self) -> &str {
+        str::from_utf8(&self.oem_id).unwrap_or("").trim_end()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The bootloader didn't find an RSDP
+    NoRsdp,
+    InvalidRsdp,
+    /// The RSDT or XSDT has a bad signature or checksum
+    InvalidRootTable,
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AcpiError::NoRsdp => write!(f, "no RSDP"),
+            AcpiError::InvalidRsdp => write!(f, "invalid RSDP"),
+            AcpiError::InvalidRootTable => write!(f, "invalid RSDT/XSDT"),
+        }
+    }
+}
+
+/// Parses the tables reachable from the RSDP at `rsdp_addr` (physical).
+///
+/// Must run after the heap is initialized.
+pub(super) fn init(rsdp_addr: Option<u64>) -> Result<&'static Acpi, AcpiError> {
+    let rsdp_addr = rsdp_addr.ok_or(AcpiError::NoRsdp)?;
+
+    let rsdp = unsafe { physical_slice(rsdp_addr, RSDP_V1_LENGTH) };
+    if &rsdp[0..8] != RSDP_SIGNATURE || checksum(rsdp) != 0 {
+        return Err(AcpiError::InvalidRsdp);
+    }
+
+    let oem_id = rsdp[9..15].try_into().unwrap();
+    let revision = rsdp[15];
+
+    // From ACPI 2.0, the RSDP is longer and can point to the XSDT, with 64 bit entries
+    let xsdt_addr = if revision >= 2 {
+        let rsdp = unsafe { physical_slice(rsdp_addr, RSDP_V2_LENGTH) };
+        if checksum(rsdp) != 0 {
+            return Err(AcpiError::InvalidRsdp);
+        }
+
+        read_u64(rsdp, 24).filter(|&addr| addr != 0)
+    } else {
+        None
+    };
+
+    let root = match xsdt_addr {
+        Some(addr) => RootTable::Xsdt(unsafe { Sdt::at(addr) }.ok_or(AcpiError::InvalidRootTable)?),
+        None => {
+            let rsdt_addr = read_u32(rsdp, 16).unwrap() as u64;
+            RootTable::Rsdt(unsafe { Sdt::at(rsdt_addr) }.ok_or(AcpiError::InvalidRootTable)?)
+        },
+    };
+
+    let valid_signature = match &root {
+        RootTable::Rsdt(sdt) => sdt.signature() == *b"RSDT",
+        RootTable::Xsdt(sdt) => sdt.signature() == *b"XSDT",
+    };
+    if !valid_signature {
+        return Err(AcpiError::InvalidRootTable);
+    }
+
+    let mut acpi = Acpi { revision, oem_id, madt: None, fadt: None, hpet: None, mcfg: None };
+
+    for table in root.tables() {
+        match &table.signature() {
+            b"APIC" => acpi.madt = Madt::parse(&table),
+            b"FACP" => acpi.fadt = Fadt::parse(&table),
+            b"HPET" => acpi.hpet = Hpet::parse(&table),
+            b"MCFG" => acpi.mcfg = Mcfg::parse(&table),
+            _ => { },
+        }
+    }
+
+    Ok(ACPI.call_once(|| acpi))
+}
+
+/// The parsed ACPI tables, if they were found
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.get()
+}
+
+/// A system description table, with a valid checksum
+struct Sdt {
+    data: &'static [u8],
+}
+
+impl Sdt {
+    /// # Safety
+    ///
+    /// `addr` must be the physical address of a table, or at least of `HEADER_LENGTH` readable bytes
+    unsafe fn at(addr: u64) -> Option<Self> {
+        let header = physical_slice(addr, HEADER_LENGTH);
+        let length = read_u32(header, 4)? as usize;
+        if length < HEADER_LENGTH {
+            return None;
+        }
+
+        let data = physical_slice(addr, length);
+        (checksum(data) == 0).then_some(Sdt { data })
+    }
+
+    fn signature(&self) -> [u8; 4] {
+        self.data[0..4].try_into().unwrap()
+    }
+
+    /// The table after the common header
+    fn body(&self) -> &'static [u8] {
+        &self.data[HEADER_LENGTH..]
+    }
+}
+
+enum RootTable {
+    Rsdt(Sdt),
+    Xsdt(Sdt),
+}
+
+impl RootTable {
+    /// Every valid table listed
+    fn tables(&self) -> impl Iterator<Item = Sdt> {
+        let (body, entry_size) = match self {
+            RootTable::Rsdt(sdt) => (sdt.body(), 4),
+            RootTable::Xsdt(sdt) => (sdt.body(), 8),
+        };
+
+        body.chunks_exact(entry_size)
+            .map(move |entry| match entry_size {
+                4 => read_u32(entry, 0).unwrap() as u64,
+                _ => read_u64(entry, 0).unwrap(),
+            })
+            .filter_map(|addr| unsafe { Sdt::at(addr) })
+    }
+}
+
+/// An ACPI generic address structure: a register in some address space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    /// 1 = byte, 2 = word, 3 = dword, 4 = qword, 0 = undefined
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+impl GenericAddress {
+    const LENGTH: usize = 12;
+
+    fn parse(data: &[u8], offset: usize) -> Option<Self> {
+        let data = data.get(offset..offset + Self::LENGTH)?;
+
+        let address_space = match data[0] {
+            0 => AddressSpace::SystemMemory,
+            1 => AddressSpace::SystemIo,
+            2 => AddressSpace::PciConfig,
+            other => AddressSpace::Other(other),
+        };
+
+        Some(GenericAddress {
+            address_space,
+            bit_width: data[1],
+            bit_offset: data[2],
+            access_size: data[3],
+            address: read_u64(data, 4)?,
+        })
+    }
+}
+
+/// # Safety
+///
+/// `len` bytes at physical `addr` must be readable, and stay unmodified
+unsafe fn physical_slice(addr: u64, len: usize) -> &'static [u8] {
+    let physical_offset = *PHYSICAL_MEM_OFFSET.get().unwrap();
+
+    core::slice::from_raw_parts((physical_offset + addr) as *const u8, len)
+}
+
+/// ACPI structures are valid if all of their bytes sum to 0
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(offset..offset + 2)?.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(offset..offset + 8)?.try_into().unwrap()))
+}
@@ -1,8 +1,8 @@
 use alloc::alloc::Layout;
 
-use x86_64::{structures::paging::{Mapper, Size4KiB, FrameAllocator, Page, mapper::MapToError, PageTableFlags}, VirtAddr};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
 
-use crate::Locked;
+use crate::{paging::{self, ReserveError}, Locked};
 
 pub mod fixed_size_block;
 use fixed_size_block::FixedSizeBlockAllocator;
@@ -18,33 +18,12 @@ fn alloc_error_handler(layout: Layout) -> ! {
     panic!("allocation error: {layout:#?}");
 }
 
-pub(crate) fn init_heap<M, F>(mapper: &mut M, frame_allocator: &mut F) -> Result<(), MapToError<Size4KiB>>
-where 
-    M: Mapper<Size4KiB>,
-    F: FrameAllocator<Size4KiB>
-{
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe {
-            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
-        }
-    }
+/// Reserves the heap, which is backed by frames as it's used
+pub(crate) fn init_heap() -> Result<(), ReserveError> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    paging::reserve("heap", VirtAddr::new(HEAP_START as u64), HEAP_SIZE as u64, flags)?;
 
     unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE); }
 
     Ok(())
 }
-
@@ -1,10 +1,20 @@
 use pic8259::ChainedPics;
-use spin::{Lazy, Mutex};
-use x2apic::{lapic::{LocalApicBuilder, LocalApic}, ioapic::{IoApic, IrqMode, IrqFlags}};
+use core::cell::RefCell;
 
-use crate::PHYSICAL_MEM_OFFSET;
+use spin::Lazy;
+use x2apic::lapic::{LocalApicBuilder, LocalApic};
+use x86_64::registers::model_specific::Msr;
 
-pub static LAPIC: Lazy<Mutex<LocalApic>> = Lazy::new(|| {
+use crate::{percpu, PHYSICAL_MEM_OFFSET};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+
+percpu! {
+    /// Each CPU's own local APIC, see [`with_lapic`]
+    static LAPIC: Lazy<RefCell<LocalApic>> = Lazy::new(build_lapic);
+}
+
+fn build_lapic() -> RefCell<LocalApic> {
     let phys_addr = unsafe { x2apic::lapic::xapic_base() };
     let virt_addr = phys_addr + *PHYSICAL_MEM_OFFSET.get().unwrap();
 
@@ -17,35 +27,50 @@ pub static LAPIC: Lazy<Mutex<LocalApic>> = Lazy::new(|| {
         .expect("Failed to build LocalApic");
 
     lapic.into()
-});
+}
 
-const IOAPIC_IRQ_OFFSET: u8 = 0x20;
+/// Runs `f` with this CPU's local APIC, with interrupts disabled
+pub fn with_lapic<R>(f: impl FnOnce(&mut LocalApic) -> R) -> R {
+    LAPIC.with(|lapic| f(&mut lapic.borrow_mut()))
+}
 
-pub static mut IOAPIC: Lazy<Mutex<IoApic>> = Lazy::new(|| unsafe {
-    let phys_addr = 0xFEC0_0000;
-    let virt_addr = phys_addr + *PHYSICAL_MEM_OFFSET.get().unwrap();
+/// Legacy IRQs are delivered at this vector onwards
+pub const IOAPIC_IRQ_OFFSET: u8 = 0x20;
 
-    let mut ioapic = IoApic::new(virt_addr);
-    ioapic.init(IOAPIC_IRQ_OFFSET);
+/// Number of legacy ISA IRQs
+pub const LEGACY_IRQS: u8 = 16;
 
-    let apic_id = LAPIC.lock().id();
+/// The vector legacy `irq` is delivered at
+pub const fn irq_vector(irq: u8) -> u8 {
+    IOAPIC_IRQ_OFFSET + irq
+}
 
-    let mut keyboard_entry = ioapic.table_entry(IrqVector::Keyboard as u8);
-    keyboard_entry.set_mode(IrqMode::Fixed);
-    keyboard_entry.set_flags(IrqFlags::MASKED);
-    keyboard_entry.set_dest(apic_id as u8);
-    ioapic.set_table_entry(IrqVector::Keyboard as u8, keyboard_entry);
+/// The local APIC ID of the CPU this runs on
+pub fn id() -> u32 {
+    with_lapic(|lapic| unsafe { lapic.id() })
+}
 
-    ioapic.enable_irq(IrqVector::Keyboard as u8);
+/// The count of this CPU's APIC timer, which `LocalApic` doesn't expose
+pub fn timer_current() -> u32 {
+    /// In `IA32_APIC_BASE`, set when the local APIC is in x2APIC mode
+    const X2APIC_ENABLE: u64 = 1 << 10;
+    const X2APIC_TIMER_CURRENT: u32 = 0x839;
+    const XAPIC_TIMER_CURRENT: u64 = 0x390;
 
-    ioapic.into()
-});
+    unsafe {
+        if Msr::new(IA32_APIC_BASE).read() & X2APIC_ENABLE != 0 {
+            Msr::new(X2APIC_TIMER_CURRENT).read() as u32
+        } else {
+            let base = x2apic::lapic::xapic_base() + *PHYSICAL_MEM_OFFSET.get().unwrap();
+            ((base + XAPIC_TIMER_CURRENT) as *const u32).read_volatile()
+        }
+    }
+}
 
 pub fn init() {
     unsafe {
         disable_pic();
-        LAPIC.lock().enable();
-        // TODO: remove IOAPIC.init();
+        with_lapic(|lapic| lapic.enable());
         x86_64::instructions::interrupts::enable();
     }
 }
@@ -56,15 +81,10 @@ unsafe fn disable_pic() {
     pics.disable();
 }
 
+/// Vectors of the local APIC's own interrupts, clear of the legacy IRQs
 #[repr(usize)]
 pub enum ApicInterruptIndex {
-    Timer = 32,
-    Keyboard = 33,
+    Timer = 59,
     Error = 60,
     Spurious = 61,
 }
-
-#[repr(u8)]
-pub enum IrqVector {
-    Keyboard = 1
-}
@@ -0,0 +1,151 @@
+//! Stack backtraces, following the frame pointers. Nothing here allocates.
+
+use core::{fmt::Write, str};
+
+use spin::Once;
+use x86_64::VirtAddr;
+
+use crate::{initrd, memory, serial::EmergencyWriter};
+
+/// Must match `SYMBOLS_PATH` in the runner's `build.rs`
+const SYMBOLS_PATH: &str = "boot/kernel.sym";
+
+/// Stops runaway walks over a corrupted stack
+const MAX_FRAMES: usize = 64;
+
+static SYMBOLS: Once<Option<SymbolTable>> = Once::new();
+
+/// The frame pointer of the caller
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+
+    rbp
+}
+
+/// Return addresses of the frames starting at `rbp`, innermost first
+pub fn frames(rbp: u64) -> Frames {
+    Frames { rbp, remaining: MAX_FRAMES }
+}
+
+pub struct Frames {
+    rbp: u64,
+    remaining: usize,
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let rbp = self.rbp;
+
+        if self.remaining == 0 || rbp == 0 || rbp % 8 != 0 || !is_readable(rbp) || !is_readable(rbp + 8) {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let (caller_rbp, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read_volatile(), frame.add(1).read_volatile())
+        };
+
+        // The stack grows down, so callers' frames are always further up
+        self.rbp = if caller_rbp > rbp { caller_rbp } else { 0 };
+
+        (return_address != 0).then_some(return_address)
+    }
+}
+
+fn is_readable(addr: u64) -> bool {
+    VirtAddr::try_new(addr).map_or(false, memory::is_mapped)
+}
+
+/// A function in the kernel's symbol table
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    /// Offset of the address into the function
+    pub offset: u64,
+}
+
+/// Finds the function containing `addr` (as loaded)
+pub fn symbolize(addr: u64) -> Option<Symbol> {
+    let symbols = SYMBOLS.call_once(SymbolTable::load).as_ref()?;
+    let addr = addr.wrapping_sub(symbols.load_offset);
+
+    let (start, size, name) = symbols.find(addr)?;
+    (addr < start + size).then_some(Symbol { name, offset: addr - start })
+}
+
+/// Prints a backtrace to serial, without waiting on locks the code that
+/// crashed may hold.
+///
+/// `pc`, if given, is where execution was (e.g. the faulting instruction)
+/// when the frame at `rbp` was entered.
+pub fn print(pc: Option<u64>, rbp: u64) {
+    let mut out = EmergencyWriter;
+    let _ = writeln!(out, "backtrace:");
+
+    for (i, addr) in pc.into_iter().chain(frames(rbp)).enumerate() {
+        let _ = match symbolize(addr) {
+            Some(Symbol { name, offset }) => writeln!(out, "  {i:>2}: {addr:#018x} {name}+{offset:#x}"),
+            None => writeln!(out, "  {i:>2}: {addr:#018x} <unknown>"),
+        };
+    }
+}
+
+struct SymbolTable {
+    /// `<start> <size> <name>` lines (hex, as linked), sorted by start
+    text: &'static str,
+    /// Difference between where the kernel was loaded and where it was linked
+    load_offset: u64,
+}
+
+impl SymbolTable {
+    fn load() -> Option<Self> {
+        let text = initrd::archive()?.read(SYMBOLS_PATH)?;
+        let text = str::from_utf8(text).ok()?;
+
+        // Where this function is, against where the table says it should be
+        let anchor = symbolize as fn(u64) -> Option<Symbol> as usize as u64;
+        let (start, _, _) = text.lines()
+            .filter_map(parse_line)
+            .find(|&(_, _, name)| name == "kernel::backtrace::symbolize")?;
+
+        Some(SymbolTable { text, load_offset: anchor.wrapping_sub(start) })
+    }
+
+    /// The last function starting at or before `addr` (as linked), by
+    /// bisecting the text on the line around the middle byte
+    fn find(&self, addr: u64) -> Option<(u64, u64, &'static str)> {
+        let text = self.text;
+        // Every line starting in `low..high` is still a candidate
+        let (mut low, mut high) = (0, text.len());
+        let mut found = None;
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let start = text.as_bytes()[..middle].iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+            let end = text[start..].find('\n').map_or(text.len(), |i| start + i);
+
+            match parse_line(&text[start..end]) {
+                Some(symbol) if symbol.0 <= addr => {
+                    found = Some(symbol);
+                    low = end + 1;
+                },
+                _ => high = start,
+            }
+        }
+
+        found
+    }
+}
+
+/// `(address, size, name)` of a function in the table
+fn parse_line(line: &str) -> Option<(u64, u64, &str)> {
+    let (start, rest) = line.split_once(' ')?;
+    let (size, name) = rest.split_once(' ')?;
+
+    Some((u64::from_str_radix(start, 16).ok()?, u64::from_str_radix(size, 16).ok()?, name))
+}
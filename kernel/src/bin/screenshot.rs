@@ -0,0 +1,69 @@
+#![no_std]
+#![no_main]
+
+//! Draws a fixed script of text and colours for the runner's golden image
+//! tests (`cargo run -- --screenshot`). Changing the script means the golden
+//! images have to be re-blessed.
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+
+use kernel::{
+    color::{ANSI_ESCAPES, ColorName},
+    framebuffer,
+    print, println, serial_println,
+    testing::{self, SCREENSHOT_READY},
+};
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    testing::test_panic_handler(info)
+}
+
+entry_point!(screenshot_main, config = &kernel::BOOTLOADER_CONFIG);
+
+const COLORS: &[ColorName] = &[
+    ColorName::Black,
+    ColorName::Red,
+    ColorName::Green,
+    ColorName::Yellow,
+    ColorName::Blue,
+    ColorName::Magenta,
+    ColorName::Cyan,
+    ColorName::White,
+];
+
+fn screenshot_main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+    framebuffer::clear_screen();
+
+    let fg = ANSI_ESCAPES[ColorName::Foreground as usize];
+
+    // Printable ASCII
+    for c in ' '..='~' {
+        print!("{c}");
+    }
+    println!("\n");
+
+    // Every foreground colour
+    for &color in COLORS {
+        print!("{}fg{} ", ANSI_ESCAPES[color as usize], color as u8);
+    }
+    println!("{fg}\n");
+
+    // Every background colour
+    for &color in COLORS {
+        print!("\x1B[4{}m bg{} \x1B[4{}m ", color as u8, color as u8, ColorName::Background as u8);
+    }
+    println!("\n");
+
+    // A line long enough to wrap
+    for i in 0..20 {
+        print!("wrap-{i:02} ");
+    }
+    println!();
+
+    serial_println!("{SCREENSHOT_READY}");
+    kernel::hlt_loop()
+}
@@ -0,0 +1,1101 @@
+#![no_std]
+#![no_main]
+#![feature(asm_const)]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+
+use bootloader_api::{entry_point, BootInfo};
+
+use kernel::testing::{self, Testable};
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    testing::test_panic_handler(info)
+}
+
+entry_point!(test_main, config = &kernel::BOOTLOADER_CONFIG);
+
+const TESTS: &[&dyn Testable] = &[
+    &heap::simple_allocation,
+    &heap::large_vec,
+    &heap::many_boxes,
+    &heap::many_boxes_long_lived,
+    &interrupts::breakpoint_exception,
+    &interrupts::exception_policy,
+    &interrupts::saves_registers,
+    &interrupts::registered_handler,
+    &framebuffer::println_many,
+    &initrd::contains_rootfs,
+    &cmdline::parses_options,
+    &backtrace::walks_frames,
+    &backtrace::symbolizes_functions,
+    &paging::maps_on_first_access,
+    &paging::rejects_overlapping_regions,
+    &acpi::parses_tables,
+    &ioapic::rtc_irq_arrives,
+    &smp::runs_work_on_every_cpu,
+    &percpu::separate_values,
+    &percpu::counts_interrupt_depth,
+    &clock::measures_time,
+    &clock::ticks_advance,
+    &thread::spawn_and_join,
+    &thread::preempts_busy_threads,
+    &thread::sleeps_and_yields,
+    &thread::exits_and_kills,
+    &task::runs_tasks,
+    &task::channel_passes_values,
+    &task::timers_wake_tasks,
+    &task::interrupts_wake_tasks,
+    &ps2::decodes_set1,
+    &ps2::decodes_set2,
+    &ps2::loads_keymaps,
+    &ps2::types_with_modifiers,
+    &ps2::toggles_locks,
+    &ps2::sets_leds,
+    &ps2::decodes_mouse_packets,
+    &syscall::dispatches_by_number,
+    &syscall::checks_pointers,
+    &process::runs_programs,
+    &process::checks_syscall_pointers,
+    &process::kills_faulting_programs,
+];
+
+fn test_main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init(boot_info);
+
+    testing::test_runner(TESTS)
+}
+
+mod heap {
+    use alloc::{boxed::Box, vec::Vec};
+
+    use kernel::allocator::HEAP_SIZE;
+
+    pub fn simple_allocation() {
+        let heap_value_1 = Box::new(41);
+        let heap_value_2 = Box::new(13);
+        assert_eq!(*heap_value_1, 41);
+        assert_eq!(*heap_value_2, 13);
+    }
+
+    pub fn large_vec() {
+        let n = 1000;
+        let mut vec = Vec::new();
+        for i in 0..n {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    }
+
+    pub fn many_boxes() {
+        for i in 0..HEAP_SIZE {
+            let x = Box::new(i);
+            assert_eq!(*x, i);
+        }
+    }
+
+    pub fn many_boxes_long_lived() {
+        let long_lived = Box::new(1);
+        for i in 0..HEAP_SIZE {
+            let x = Box::new(i);
+            assert_eq!(*x, i);
+        }
+        assert_eq!(*long_lived, 1);
+    }
+}
+
+mod interrupts {
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+    use kernel::{apic, exceptions::{self, Action, Exception, ExceptionInfo}, interrupts::{self, RegisterError}};
+
+    pub fn breakpoint_exception() {
+        // Execution should continue after the breakpoint handler returns
+        x86_64::instructions::interrupts::int3();
+    }
+
+    pub fn exception_policy() {
+        static BREAKPOINTS: AtomicUsize = AtomicUsize::new(0);
+
+        fn counting_policy(info: &ExceptionInfo) -> Action {
+            if info.exception == Exception::Breakpoint {
+                BREAKPOINTS.fetch_add(1, Ordering::SeqCst);
+            }
+
+            exceptions::default_policy(info)
+        }
+
+        exceptions::set_policy(counting_policy);
+        x86_64::instructions::interrupts::int3();
+        exceptions::set_policy(exceptions::default_policy);
+
+        assert_eq!(BREAKPOINTS.load(Ordering::SeqCst), 1);
+    }
+
+    pub fn saves_registers() {
+        const VALUE: u64 = 0x1122_3344_5566_7788;
+        static R14: AtomicU64 = AtomicU64::new(0);
+
+        fn recording_policy(info: &ExceptionInfo) -> Action {
+            R14.store(info.registers.r14, Ordering::SeqCst);
+            exceptions::default_policy(info)
+        }
+
+        let after: u64;
+        exceptions::set_policy(recording_policy);
+        unsafe { core::arch::asm!("int3", inout("r14") VALUE => after) };
+        exceptions::set_policy(exceptions::default_policy);
+
+        assert_eq!(R14.load(Ordering::SeqCst), VALUE);
+        // Restored on the way back
+        assert_eq!(after, VALUE);
+    }
+
+    pub fn registered_handler() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        let vector = interrupts::register(|_: &_| { CALLS.fetch_add(1, Ordering::SeqCst); }).unwrap();
+        assert_eq!(interrupts::register_vector(vector, |_: &_| { }), Err(RegisterError::InUse(vector)));
+        assert_eq!(interrupts::register_vector(3, |_: &_| { }), Err(RegisterError::Reserved(3)));
+
+        // The second interrupt is only delivered if the first was acknowledged
+        send_self_ipi(vector);
+        send_self_ipi(vector);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+        assert!(interrupts::unregister(vector).is_some());
+        send_self_ipi(vector);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    }
+
+    /// Sends `vector` to this CPU, and gives it time to be handled
+    fn send_self_ipi(vector: u8) {
+        // The dispatcher needs the local APIC to acknowledge the interrupt
+        apic::with_lapic(|lapic| unsafe {
+            let id = lapic.id();
+            lapic.send_ipi(vector, id);
+        });
+
+        for _ in 0..10_000 {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+mod framebuffer {
+    use kernel::println;
+
+    pub fn println_many() {
+        // Enough lines to wrap the framebuffer at least once
+        for i in 0..200 {
+            println!("println_many output line {i}");
+        }
+    }
+}
+
+mod initrd {
+    use kernel::initrd::{self, EntryKind};
+
+    pub fn contains_rootfs() {
+        let archive = initrd::archive().expect("booted without an initrd");
+
+        let etc = archive.get("etc").expect("`etc` is missing");
+        assert_eq!(etc.kind, EntryKind::Directory);
+
+        let motd = archive.read("/etc/motd").expect("`etc/motd` is missing");
+        assert!(!motd.is_empty());
+    }
+}
+
+mod cmdline {
+    use kernel::cmdline::Cmdline;
+    use tracing::level_filters::LevelFilter;
+
+    pub fn parses_options() {
+        let cmdline = Cmdline::new("  loglevel=warn fb.scale=1 quiet test=heap test=initrd fb.scale=0 init.skip=apic,tracing\n");
+
+        assert_eq!(cmdline.log_level(), Some(LevelFilter::WARN));
+        assert_eq!(cmdline.fb_scale(), None); // Last one wins, and 0 is invalid
+        assert_eq!(cmdline.test_filter(), Some("initrd"));
+        assert!(cmdline.flag("quiet"));
+        assert!(!cmdline.flag("loglevel"));
+        assert!(cmdline.skips_init("apic"));
+        assert!(!cmdline.skips_init("heap"));
+        assert_eq!(cmdline.get("missing"), None);
+
+        assert!(Cmdline::new(" \n").is_empty());
+    }
+}
+
+mod backtrace {
+    use kernel::backtrace;
+
+    #[inline(never)]
+    pub fn walks_frames() {
+        assert!(called_from_walks_frames());
+    }
+
+    #[inline(never)]
+    fn called_from_walks_frames() -> bool {
+        backtrace::frames(backtrace::frame_pointer())
+            .filter_map(backtrace::symbolize)
+            .any(|symbol| symbol.name.ends_with("backtrace::walks_frames"))
+    }
+
+    pub fn symbolizes_functions() {
+        let functions = [
+            (kernel::hlt_loop as fn() -> ! as usize as u64, "kernel::hlt_loop"),
+            (kernel::percpu::cpu as fn() -> usize as usize as u64, "kernel::percpu::cpu"),
+            (backtrace::symbolize as fn(u64) -> _ as usize as u64, "kernel::backtrace::symbolize"),
+            (symbolizes_functions as fn() as usize as u64, "tests::backtrace::symbolizes_functions"),
+        ];
+
+        for (addr, name) in functions {
+            let symbol = backtrace::symbolize(addr + 1).expect("no symbol found");
+            assert_eq!((symbol.name, symbol.offset), (name, 1));
+        }
+    }
+}
+
+mod paging {
+    use kernel::{memory, paging::{self, ReserveError}};
+    use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+    const FLAGS: PageTableFlags = PageTableFlags::WRITABLE;
+
+    pub fn maps_on_first_access() {
+        // Far more than the machine has, only the pages touched are backed
+        let start = VirtAddr::new(0x5555_0000_0000);
+        let region = paging::reserve("test", start, 1 << 40, FLAGS).unwrap();
+
+        let addr = start + region.size / 2 + 8u64;
+        assert!(!memory::is_mapped(addr));
+
+        let ptr = addr.as_mut_ptr::<u64>();
+        unsafe {
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(0xdead_beef);
+            assert_eq!(ptr.read_volatile(), 0xdead_beef);
+        }
+
+        assert!(memory::is_mapped(addr));
+        assert_eq!(paging::region(addr), Some(region));
+    }
+
+    pub fn rejects_overlapping_regions() {
+        let start = VirtAddr::new(0x6666_0000_0000);
+        paging::reserve("first", start, 0x3000, FLAGS).unwrap();
+
+        assert_eq!(paging::reserve("second", start + 0x2000u64, 0x1000, FLAGS), Err(ReserveError::Overlaps("first")));
+        assert_eq!(paging::reserve("second", start + 0x3001u64, 0x1000, FLAGS), Err(ReserveError::InvalidRange));
+        assert!(paging::reserve("second", start + 0x3000u64, 0x1000, FLAGS).is_ok());
+    }
+}
+
+mod acpi {
+    use kernel::acpi;
+
+    pub fn parses_tables() {
+        let acpi = acpi::get().expect("no ACPI tables");
+
+        let madt = acpi.madt.as_ref().expect("no MADT");
+        assert!(madt.local_apics.iter().any(|lapic| lapic.enabled));
+        assert!(!madt.io_apics.is_empty());
+
+        // QEMU connects the PIT (IRQ 0) to GSI 2
+        let (gsi, _, _) = madt.isa_irq(0);
+        assert!(madt.io_apic_for(gsi).is_some());
+
+        let fadt = acpi.fadt.as_ref().expect("no FADT");
+        assert!(fadt.pm_timer.is_some());
+
+        let hpet = acpi.hpet.as_ref().expect("no HPET");
+        assert!(hpet.comparators() > 0);
+    }
+}
+
+mod ioapic {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use kernel::interrupts;
+    use x86_64::instructions::{self, port::Port};
+
+    const RTC_IRQ: u8 = 8;
+
+    /// The RTC's status register C, which has to be read to acknowledge its interrupts
+    const RTC_STATUS_C: u8 = 0x0C;
+    const RTC_STATUS_B: u8 = 0x0B;
+    /// Periodic interrupt enable, in status register B
+    const RTC_PIE: u8 = 1 << 6;
+
+    pub fn rtc_irq_arrives() {
+        static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+        let vector = interrupts::register_irq(RTC_IRQ, |_: &_| {
+            TICKS.fetch_add(1, Ordering::SeqCst);
+            read_cmos(RTC_STATUS_C);
+        }).unwrap();
+
+        let status_b = instructions::interrupts::without_interrupts(|| {
+            let status_b = read_cmos(RTC_STATUS_B);
+            write_cmos(RTC_STATUS_B, status_b | RTC_PIE);
+            read_cmos(RTC_STATUS_C);
+            status_b
+        });
+
+        // The RTC interrupts at 1024 Hz, the APIC timer wakes us up if it doesn't
+        for _ in 0..100 {
+            if TICKS.load(Ordering::SeqCst) > 0 {
+                break;
+            }
+            instructions::hlt();
+        }
+
+        instructions::interrupts::without_interrupts(|| write_cmos(RTC_STATUS_B, status_b));
+        assert!(interrupts::unregister(vector).is_some());
+
+        assert!(TICKS.load(Ordering::SeqCst) > 0);
+    }
+
+    fn read_cmos(register: u8) -> u8 {
+        unsafe {
+            // The top bit disables NMIs while the register is selected
+            Port::new(0x70).write(register | 0x80);
+            Port::new(0x71).read()
+        }
+    }
+
+    fn write_cmos(register: u8, value: u8) {
+        unsafe {
+            Port::new(0x70).write(register | 0x80);
+            Port::new(0x71).write(value);
+        }
+    }
+}
+
+mod smp {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    use kernel::smp::{self, SmpError};
+
+    /// Only checks the boot CPU with a single CPU, run with `--profile smp4` for more
+    pub fn runs_work_on_every_cpu() {
+        let cpus = smp::cpus().len();
+        assert_eq!(smp::online_count(), cpus);
+        assert_eq!(smp::current(), 0);
+
+        // Each CPU adds its index (plus one, so the boot CPU counts)
+        let sum = Arc::new(AtomicUsize::new(0));
+        for cpu in 0..cpus {
+            let sum = sum.clone();
+            smp::run_on(cpu, move || { sum.fetch_add(smp::current() + 1, Ordering::SeqCst); }).unwrap();
+        }
+
+        let expected = (1..=cpus).sum();
+        for _ in 0..1_000 {
+            if sum.load(Ordering::SeqCst) == expected {
+                break;
+            }
+            x86_64::instructions::hlt();
+        }
+        assert_eq!(sum.load(Ordering::SeqCst), expected);
+
+        assert_eq!(smp::run_on(cpus, || { }), Err(SmpError::NoSuchCpu(cpus)));
+    }
+}
+
+mod percpu {
+    use alloc::sync::Arc;
+    use core::{cell::Cell, sync::atomic::{AtomicUsize, Ordering}};
+
+    use kernel::{exceptions::{self, Action, ExceptionInfo}, percpu, smp};
+
+    percpu! {
+        static VALUE: Cell<usize> = Cell::new(0);
+    }
+
+    pub fn separate_values() {
+        assert_eq!(percpu::cpu(), 0);
+        VALUE.with(|value| value.set(42));
+
+        // Every AP starts out with its own zero
+        let seen = Arc::new(AtomicUsize::new(0));
+        for cpu in 1..smp::cpus().len() {
+            let seen = seen.clone();
+            smp::run_on(cpu, move || {
+                let value = VALUE.with(|value| value.replace(cpu));
+                seen.fetch_add(value + 1, Ordering::SeqCst);
+            }).unwrap();
+        }
+
+        let expected = smp::cpus().len().saturating_sub(1);
+        for _ in 0..1_000 {
+            if seen.load(Ordering::SeqCst) == expected {
+                break;
+            }
+            x86_64::instructions::hlt();
+        }
+
+        assert_eq!(seen.load(Ordering::SeqCst), expected);
+        assert_eq!(VALUE.with(Cell::get), 42);
+    }
+
+    pub fn counts_interrupt_depth() {
+        static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+        fn recording_policy(info: &ExceptionInfo) -> Action {
+            DEPTH.store(percpu::interrupt_depth(), Ordering::SeqCst);
+            exceptions::default_policy(info)
+        }
+
+        assert!(!percpu::in_interrupt());
+
+        exceptions::set_policy(recording_policy);
+        x86_64::instructions::interrupts::int3();
+        exceptions::set_policy(exceptions::default_policy);
+
+        assert_eq!(DEPTH.load(Ordering::SeqCst), 1);
+        assert!(!percpu::in_interrupt());
+    }
+}
+
+mod clock {
+    use core::time::Duration;
+
+    use kernel::clock;
+
+    pub fn measures_time() {
+        let tsc = clock::tsc_frequency().expect("TSC not calibrated");
+        assert!(tsc > 100_000_000, "TSC at {tsc} Hz");
+
+        let start = clock::now();
+        clock::sleep(Duration::from_millis(20));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(20), "slept for {elapsed:?}");
+        assert!(elapsed < Duration::from_secs(1), "slept for {elapsed:?}");
+
+        let later = start + Duration::from_millis(5);
+        assert!(later > start);
+        assert!(later - start <= Duration::from_millis(5));
+        assert_eq!(start - later, Duration::ZERO);
+    }
+
+    pub fn ticks_advance() {
+        assert!(clock::apic_timer_frequency().is_some());
+
+        // A few ticks, going by the TSC. Only a lower bound, as a slow host
+        // can deliver the timer late and then in a burst.
+        let ticks = clock::ticks();
+        clock::sleep(Duration::from_millis(5 * 1000 / clock::TICK_HZ));
+        let elapsed = clock::ticks() - ticks;
+
+        assert!(elapsed >= 2, "{elapsed} ticks in 5 tick periods");
+        assert!(clock::uptime() > Duration::ZERO);
+    }
+}
+
+mod thread {
+    use core::{sync::atomic::{AtomicBool, AtomicUsize, Ordering}, time::Duration};
+
+    use kernel::{clock, exceptions::{self, Action, Exception, ExceptionInfo}, smp, thread};
+
+    pub fn spawn_and_join() {
+        let handle = thread::spawn("adder", || 2 + 2);
+        assert_eq!(handle.join(), Some(4));
+
+        // Threads on the other CPUs (with `--profile smp4`)
+        for cpu in 1..smp::cpus().len() {
+            let handle = thread::spawn_on(cpu, "remote", move || (cpu, smp::current())).unwrap();
+            assert_eq!(handle.join(), Some((cpu, cpu)));
+        }
+    }
+
+    pub fn preempts_busy_threads() {
+        static SPINS: AtomicUsize = AtomicUsize::new(0);
+        static STOP: AtomicBool = AtomicBool::new(false);
+
+        let spinner = thread::spawn("spinner", || {
+            while !STOP.load(Ordering::SeqCst) {
+                SPINS.fetch_add(1, Ordering::SeqCst);
+                core::hint::spin_loop();
+            }
+        });
+
+        // Never yields, so the spinner only runs if this thread is preempted
+        clock::sleep(Duration::from_millis(100));
+        assert!(SPINS.load(Ordering::SeqCst) > 0);
+
+        STOP.store(true, Ordering::SeqCst);
+        assert_eq!(spinner.join(), Some(()));
+    }
+
+    pub fn sleeps_and_yields() {
+        let start = clock::now();
+        thread::sleep(Duration::from_millis(20));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        let sleeper = thread::spawn("sleeper", || {
+            let start = clock::now();
+            thread::sleep(Duration::from_millis(30));
+            start.elapsed()
+        });
+
+        while !sleeper.is_finished() {
+            thread::yield_now();
+        }
+        assert!(sleeper.join().unwrap() >= Duration::from_millis(30));
+    }
+
+    pub fn exits_and_kills() {
+        let exited = thread::spawn("exiter", || -> u32 { thread::exit() });
+        assert_eq!(exited.join(), None);
+
+        fn killing_policy(info: &ExceptionInfo) -> Action {
+            match info.exception {
+                Exception::PageFault => Action::KillTask,
+                _ => exceptions::default_policy(info),
+            }
+        }
+
+        exceptions::set_policy(killing_policy);
+        let faulting = thread::spawn("faulter", || unsafe { core::ptr::null::<u8>().read_volatile() });
+        let result = faulting.join();
+        exceptions::set_policy(exceptions::default_policy);
+
+        assert_eq!(result, None);
+    }
+}
+
+mod task {
+    use alloc::{sync::Arc, vec::Vec};
+    use core::{sync::atomic::{AtomicUsize, Ordering}, time::Duration};
+
+    use kernel::{
+        apic, clock, interrupts,
+        task::{self, channel::{self, TryRecvError}, timer, Executor, Stream},
+    };
+
+    pub fn runs_tasks() {
+        static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut executor = Executor::new();
+        let spawner = executor.spawner();
+
+        for _ in 0..3 {
+            executor.spawn(async {
+                task::yield_now().await;
+                POLLS.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        executor.spawn(async move {
+            spawner.spawn(async {
+                POLLS.fetch_add(1, Ordering::SeqCst);
+            });
+        });
+
+        executor.run();
+        assert_eq!(POLLS.load(Ordering::SeqCst), 4);
+    }
+
+    pub fn channel_passes_values() {
+        let received = Arc::new(spin::Mutex::new(Vec::new()));
+
+        // Smaller than what's sent, so the sender has to wait
+        let (sender, mut receiver) = channel::channel(2);
+
+        let mut executor = Executor::new();
+        executor.spawn(async move {
+            for i in 0..10 {
+                sender.send(i).await.unwrap();
+            }
+        });
+
+        let values = received.clone();
+        executor.spawn(async move {
+            while let Some(value) = receiver.next().await {
+                values.lock().push(value);
+            }
+        });
+
+        executor.run();
+        assert_eq!(*received.lock(), (0..10).collect::<Vec<_>>());
+
+        let (sender, mut receiver) = channel::channel(1);
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Empty));
+        assert!(sender.try_send(1).is_ok());
+        assert!(matches!(sender.try_send(2), Err(channel::TrySendError::Full(2))));
+
+        drop(sender);
+        assert_eq!(receiver.try_recv(), Ok(1));
+        assert_eq!(receiver.try_recv(), Err(TryRecvError::Closed));
+    }
+
+    pub fn timers_wake_tasks() {
+        let start = clock::now();
+
+        let mut executor = Executor::new();
+        executor.spawn(async {
+            task::sleep(Duration::from_millis(30)).await;
+        });
+        executor.spawn(async {
+            let mut interval = timer::interval(Duration::from_millis(10));
+            for _ in 0..3 {
+                interval.next().await;
+            }
+        });
+
+        executor.run();
+        assert!(start.elapsed() >= Duration::from_millis(30));
+    }
+
+    pub fn interrupts_wake_tasks() {
+        let (sender, mut receiver) = channel::channel(4);
+
+        let vector = interrupts::register(move |_: &_| {
+            sender.try_send(42).unwrap();
+        }).unwrap();
+
+        let mut executor = Executor::new();
+        executor.spawn(async move {
+            assert_eq!(receiver.recv().await, Some(42));
+        });
+
+        // Sent once the executor is halted, waiting for it
+        executor.spawn(async move {
+            task::sleep(Duration::from_millis(10)).await;
+            apic::with_lapic(|lapic| unsafe {
+                let id = lapic.id();
+                lapic.send_ipi(vector, id);
+            });
+        });
+
+        executor.run();
+        assert!(interrupts::unregister(vector).is_some());
+    }
+}
+
+mod ps2 {
+    use alloc::vec::Vec;
+
+    use kernel::ps2::{
+        keyboard::Keyboard,
+        keymap::{Keymap, KeymapError},
+        mouse::{MouseButton, MouseEvent, MouseKind, PacketDecoder},
+        scancode::{Decoder, KeyCode, KeyState, ScancodeSet},
+    };
+
+    fn decode(set: ScancodeSet, bytes: &[u8]) -> Vec<(KeyCode, KeyState)> {
+        let mut decoder = Decoder::new(set);
+        bytes.iter().filter_map(|&byte| decoder.advance(byte)).collect()
+    }
+
+    pub fn decodes_set1() {
+        use KeyCode::*;
+        use KeyState::*;
+
+        assert_eq!(decode(ScancodeSet::Set1, &[0x1E, 0x9E]), [(KeyA, Pressed), (KeyA, Released)]);
+        assert_eq!(decode(ScancodeSet::Set1, &[0xE0, 0x48, 0xE0, 0xC8]), [(ArrowUp, Pressed), (ArrowUp, Released)]);
+        assert_eq!(decode(ScancodeSet::Set1, &[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5, 0x01]), [(Pause, Pressed), (Escape, Pressed)]);
+        // With the fake shifts
+        assert_eq!(decode(ScancodeSet::Set1, &[0xE0, 0x2A, 0xE0, 0x37]), [(PrintScreen, Pressed)]);
+    }
+
+    pub fn decodes_set2() {
+        use KeyCode::*;
+        use KeyState::*;
+
+        assert_eq!(decode(ScancodeSet::Set2, &[0x1C, 0xF0, 0x1C]), [(KeyA, Pressed), (KeyA, Released)]);
+        assert_eq!(decode(ScancodeSet::Set2, &[0xE0, 0x75, 0xE0, 0xF0, 0x75]), [(ArrowUp, Pressed), (ArrowUp, Released)]);
+        assert_eq!(
+            decode(ScancodeSet::Set2, &[0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77, 0x76]),
+            [(Pause, Pressed), (Escape, Pressed)],
+        );
+        assert_eq!(decode(ScancodeSet::Set2, &[0xE0, 0x12, 0xE0, 0x7C]), [(PrintScreen, Pressed)]);
+    }
+
+    pub fn loads_keymaps() {
+        let us = Keymap::load("us").unwrap();
+        assert_eq!(us.get(KeyCode::KeyY, false, false), Some('y'));
+        assert_eq!(us.get(KeyCode::Digit2, true, false), Some('@'));
+        // Falls back to the level without AltGr
+        assert_eq!(us.get(KeyCode::KeyQ, false, true), Some('q'));
+
+        let de = Keymap::load("de").unwrap();
+        assert_eq!(de.get(KeyCode::KeyY, false, false), Some('z'));
+        assert_eq!(de.get(KeyCode::Digit7, false, true), Some('{'));
+        assert_eq!(de.get(KeyCode::Semicolon, true, false), Some('Ö'));
+
+        assert_eq!(Keymap::load("uk").unwrap().get(KeyCode::Digit3, true, false), Some('£'));
+        assert_eq!(Keymap::load("dvorak").unwrap().get(KeyCode::KeyS, false, false), Some('o'));
+        assert_eq!(Keymap::load("missing"), Err(KeymapError::NotFound));
+
+        let custom = Keymap::parse("custom", "# comment\nKeyA U+263A none\n").unwrap();
+        assert_eq!(custom.get(KeyCode::KeyA, false, false), Some('\u{263A}'));
+        assert_eq!(custom.get(KeyCode::KeyA, true, false), Some('\u{263A}'));
+        assert_eq!(Keymap::parse("bad", "KeyA a\nNoSuchKey b"), Err(KeymapError::UnknownKey(2)));
+        assert_eq!(Keymap::parse("bad", "KeyA ab"), Err(KeymapError::InvalidLevels(1)));
+    }
+
+    /// Feeds `bytes` (in set 1) to a keyboard, returning what each press
+    /// typed and the bytes written back to set the LEDs
+    fn type_keys(keyboard: &mut Keyboard, bytes: &[u8]) -> (Vec<Option<char>>, Vec<u8>) {
+        let mut typed = Vec::new();
+        let mut written = Vec::new();
+
+        for &byte in bytes {
+            let event = keyboard.process(byte, |byte| { written.push(byte); Ok(()) });
+            if let Some(event) = event.filter(|event| event.state == KeyState::Pressed) {
+                typed.push(event.char);
+            }
+        }
+
+        (typed, written)
+    }
+
+    pub fn types_with_modifiers() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, Keymap::us());
+
+        // a, Shift+a, then Shift released
+        assert_eq!(type_keys(&mut keyboard, &[0x1E, 0x9E, 0x2A, 0x1E, 0x9E, 0xAA, 0x1E]).0, [
+            Some('a'), None, Some('A'), Some('a'),
+        ]);
+
+        // Ctrl+C, and Ctrl with a key that isn't a letter
+        assert_eq!(type_keys(&mut keyboard, &[0x1D, 0x2E, 0x02, 0x9D]).0, [None, Some('\x03'), Some('1')]);
+
+        // Held keys repeat, with the modifiers in the event
+        let event = keyboard.process(0x2A, |_| Ok(())).unwrap();
+        assert!(event.modifiers.shift_left && event.modifiers.shift());
+        assert!(keyboard.process(0x2A, |_| Ok(())).unwrap().repeat);
+        keyboard.process(0xAA, |_| Ok(()));
+
+        // AltGr+7 and AltGr+q on a German layout
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, Keymap::load("de").unwrap());
+        assert_eq!(type_keys(&mut keyboard, &[0x08, 0xE0, 0x38, 0x08, 0x10, 0xE0, 0xB8, 0x08]).0, [
+            Some('7'), None, Some('{'), Some('@'), Some('7'),
+        ]);
+    }
+
+    pub fn toggles_locks() {
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, Keymap::us());
+
+        // Caps lock shifts letters, not digits, and Shift undoes it
+        let (typed, _) = type_keys(&mut keyboard, &[0x3A, 0xBA, 0x1E, 0x02, 0x2A, 0x1E, 0x02, 0xAA]);
+        assert_eq!(typed, [None, Some('A'), Some('1'), None, Some('a'), Some('!')]);
+        assert!(keyboard.process(0x1E, |_| Ok(())).unwrap().locks.caps_lock);
+
+        // Again to turn it off, holding it doesn't toggle it back
+        let (typed, _) = type_keys(&mut keyboard, &[0x3A, 0x3A, 0xBA, 0x1E]);
+        assert_eq!(typed, [None, None, Some('a')]);
+
+        // The numpad types digits with num lock only
+        let (typed, _) = type_keys(&mut keyboard, &[0x4F, 0xCF, 0x45, 0xC5, 0x4F, 0xCF, 0x45, 0xC5, 0x4F]);
+        assert_eq!(typed, [None, None, Some('1'), None, None]);
+    }
+
+    pub fn sets_leds() {
+        const SET_LEDS: u8 = 0xED;
+        const ACK: u8 = 0xFA;
+        const RESEND: u8 = 0xFE;
+        const CAPS_LOCK: u8 = 1 << 2;
+        const NUM_LOCK: u8 = 1 << 1;
+
+        let mut keyboard = Keyboard::new(ScancodeSet::Set1, Keymap::us());
+
+        // The command, then the LEDs once it's acknowledged
+        assert_eq!(type_keys(&mut keyboard, &[0x3A]).1, [SET_LEDS]);
+        assert_eq!(type_keys(&mut keyboard, &[ACK]).1, [CAPS_LOCK]);
+        assert_eq!(type_keys(&mut keyboard, &[ACK]).1, []);
+
+        // Resent when asked, at either step
+        assert_eq!(type_keys(&mut keyboard, &[0xBA, 0x45, RESEND]).1, [SET_LEDS, SET_LEDS]);
+        assert_eq!(type_keys(&mut keyboard, &[ACK, RESEND, ACK]).1, [CAPS_LOCK | NUM_LOCK, CAPS_LOCK | NUM_LOCK]);
+
+        // A change after the LEDs were sent is sent again, and the
+        // acknowledgements aren't taken as keys
+        let (typed, written) = type_keys(&mut keyboard, &[0xC5, 0x3A, 0xBA, ACK, 0x45, 0xC5, ACK, ACK, ACK]);
+        assert_eq!(typed, [None, None]);
+        assert_eq!(written, [SET_LEDS, NUM_LOCK, SET_LEDS, 0]);
+
+        // Given up on if the keyboard can't take them, and tried again on the next change
+        let event = keyboard.process(0x3A, |_| Err(kernel::ps2::Ps2Error::Timeout));
+        assert!(event.unwrap().locks.caps_lock);
+        assert_eq!(type_keys(&mut keyboard, &[ACK, 0xBA, 0x3A]).1, [SET_LEDS]);
+    }
+
+    fn decode_packets(kind: MouseKind, bytes: &[u8]) -> Vec<MouseEvent> {
+        let mut decoder = PacketDecoder::new(kind);
+        let mut events = Vec::new();
+        for &byte in bytes {
+            decoder.advance(byte, |event| events.push(event));
+        }
+        events
+    }
+
+    pub fn decodes_mouse_packets() {
+        use MouseEvent::*;
+
+        assert_eq!(
+            decode_packets(MouseKind::Standard, &[0x09, 5, 3, 0x08, 0, 0]),
+            [Motion { dx: 5, dy: -3 }, ButtonPressed(MouseButton::Left), ButtonReleased(MouseButton::Left)],
+        );
+        // Negative motion, after a byte that can't start a packet
+        assert_eq!(decode_packets(MouseKind::Standard, &[0x00, 0x38, 0xFB, 0xFE]), [Motion { dx: -5, dy: 2 }]);
+        // Overflowed motion is dropped
+        assert_eq!(decode_packets(MouseKind::Standard, &[0x4A, 0xFF, 0]), [ButtonPressed(MouseButton::Right)]);
+        assert_eq!(decode_packets(MouseKind::IntelliMouse, &[0x08, 0, 0, 0xFF]), [Wheel(-1)]);
+    }
+}
+
+mod syscall {
+    use kernel::{process::{AddressSpace, USER_START}, syscall::{self, Error, Syscall}};
+    use kleos_abi::STDOUT;
+    use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+    fn call(syscall: Syscall, args: &[u64]) -> Result<u64, Error> {
+        let mut all = [0; 6];
+        all[..args.len()].copy_from_slice(args);
+        syscall::dispatch(syscall.number(), all)
+    }
+
+    pub fn dispatches_by_number() {
+        assert_eq!(syscall::dispatch(Syscall::COUNT as u64, [0; 6]), Err(Error::NoSuchSyscall));
+        assert_eq!(syscall::dispatch(u64::MAX, [0; 6]), Err(Error::NoSuchSyscall));
+
+        let before = call(Syscall::Uptime, &[]).unwrap();
+        assert_eq!(call(Syscall::Sleep, &[20_000_000]), Ok(0));
+        assert!(call(Syscall::Uptime, &[]).unwrap() > before);
+        assert_eq!(call(Syscall::Yield, &[]), Ok(0));
+
+        assert_eq!(kleos_abi::decode(kleos_abi::encode(Err(Error::BadPointer))), Err(Error::BadPointer));
+        assert_eq!(kleos_abi::decode(kleos_abi::encode(Ok(42))), Ok(42));
+    }
+
+    pub fn checks_pointers() {
+        // In a page table of its own, so the user memory goes away with it
+        let text = b"written by a system call\n";
+        let start = VirtAddr::new(USER_START);
+        let size = 0x2000;
+        let read_only = start + 0x10000u64;
+
+        let mut address_space = AddressSpace::new().unwrap();
+        address_space.map(start, size, PageTableFlags::WRITABLE, text).unwrap();
+        address_space.map(read_only, 0x1000, PageTableFlags::empty(), &[]).unwrap();
+
+        address_space.with_active(|| {
+            let ptr = start.as_u64();
+            let len = text.len() as u64;
+            assert_eq!(call(Syscall::Write, &[STDOUT, ptr, len]), Ok(len));
+            assert_eq!(call(Syscall::Write, &[STDOUT, ptr, 0]), Ok(0));
+            assert_eq!(call(Syscall::Write, &[7, ptr, len]), Err(Error::BadDescriptor));
+
+            // Kernel memory, past the end of the mapping, and wrapping around
+            let kernel_text = "kernel";
+            assert_eq!(call(Syscall::Write, &[STDOUT, kernel_text.as_ptr() as u64, 6]), Err(Error::BadPointer));
+            assert_eq!(call(Syscall::Write, &[STDOUT, ptr + size - 4, 8]), Err(Error::BadPointer));
+            assert_eq!(call(Syscall::Write, &[STDOUT, u64::MAX - 2, 8]), Err(Error::BadPointer));
+
+            // Writable and read-only user memory
+            assert_eq!(syscall::check_user_range(ptr, 8, true), Ok(()));
+            assert_eq!(syscall::check_user_range(read_only.as_u64(), 8, false), Ok(()));
+            assert_eq!(syscall::check_user_range(read_only.as_u64(), 8, true), Err(Error::BadPointer));
+        });
+    }
+}
+
+mod process {
+    use core::arch::global_asm;
+
+    use kernel::{
+        allocator::HEAP_START,
+        exceptions::Exception,
+        memory, paging,
+        process::{self, ExitStatus, USER_START},
+        syscall::{Error, Syscall},
+    };
+    use kleos_abi::STDOUT;
+    use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+    /// Past the end of user space, still in the lower half
+    const LOWER_HALF_KERNEL: u64 = 0x4800_0000_0000;
+
+    /// The code between two labels in `global_asm!`, as a program image
+    macro_rules! image {
+        ($start:ident, $end:ident) => {{
+            extern "C" {
+                static $start: u8;
+                static $end: u8;
+            }
+
+            unsafe {
+                let start = core::ptr::addr_of!($start);
+                let end = core::ptr::addr_of!($end);
+                core::slice::from_raw_parts(start, end as usize - start as usize)
+            }
+        }};
+    }
+
+    fn run(name: &'static str, image: &[u8]) -> ExitStatus {
+        process::spawn(name, image).unwrap().wait()
+    }
+
+    pub fn runs_programs() {
+        assert_eq!(run("hello", image!(program_hello, program_hello_end)), ExitStatus::Exited(42));
+        assert_eq!(run("breakpoint", image!(program_breakpoint, program_breakpoint_end)), ExitStatus::Exited(7));
+
+        // Only mapped in the processes' page tables
+        assert!(!memory::is_mapped(VirtAddr::new(USER_START)));
+    }
+
+    pub fn checks_syscall_pointers() {
+        let bad_pointer = ExitStatus::Exited(-(Error::BadPointer.code() as i32));
+        assert_eq!(run("bad pointer", image!(program_bad_pointer, program_bad_pointer_end)), bad_pointer);
+
+        // Kernel memory the user can access, outside of user space but
+        // shared with every process
+        let start = VirtAddr::new(LOWER_HALF_KERNEL);
+        paging::reserve("user accessible", start, 0x1000, PageTableFlags::USER_ACCESSIBLE).unwrap();
+        unsafe { start.as_ptr::<u8>().read_volatile() };
+
+        let status = run("lower half pointer", image!(program_lower_half_pointer, program_lower_half_pointer_end));
+        paging::release(start).unwrap();
+        assert_eq!(status, bad_pointer);
+        assert!(!memory::is_mapped(start));
+    }
+
+    pub fn kills_faulting_programs() {
+        let programs = [
+            ("reads kernel", image!(program_reads_kernel, program_reads_kernel_end), Exception::PageFault),
+            ("writes code", image!(program_writes_code, program_writes_code_end), Exception::PageFault),
+            ("privileged", image!(program_privileged, program_privileged_end), Exception::GeneralProtection),
+            ("invalid opcode", image!(program_invalid_opcode, program_invalid_opcode_end), Exception::InvalidOpcode),
+        ];
+
+        for (name, image, exception) in programs {
+            assert_eq!(run(name, image), ExitStatus::Killed(exception), "{name}");
+        }
+    }
+
+    global_asm!(r#"
+    // Spins in user mode (to be interrupted there) for 30 ms, sleeps, yields,
+    // checks that registers survive all that, then exits with 42
+    .global program_hello, program_hello_end
+    program_hello:
+        mov eax, {write}
+        mov edi, {stdout}
+        lea rsi, [rip + program_hello_message]
+        lea rdx, [rip + program_hello_message_end]
+        sub rdx, rsi
+        syscall
+
+        mov r12, 0x1234
+        mov eax, {uptime}
+        syscall
+        mov rbx, rax
+        add rbx, 30000000
+    program_hello_spin:
+        mov ecx, 100000
+    program_hello_inner:
+        dec ecx
+        jnz program_hello_inner
+        mov eax, {uptime}
+        syscall
+        cmp rax, rbx
+        jb program_hello_spin
+
+        mov eax, {sleep}
+        mov edi, 10000000
+        syscall
+        mov eax, {yield_now}
+        syscall
+
+        mov edi, 1
+        cmp r12, 0x1234
+        jne program_hello_exit
+        mov edi, 42
+    program_hello_exit:
+        mov eax, {exit}
+        syscall
+        ud2
+    program_hello_message:
+        .ascii "Hello from user mode\n"
+    program_hello_message_end:
+    program_hello_end:
+
+    // Resumes after the trap
+    .global program_breakpoint, program_breakpoint_end
+    program_breakpoint:
+        int3
+        mov eax, {exit}
+        mov edi, 7
+        syscall
+        ud2
+    program_breakpoint_end:
+
+    // Exits with the error of writing from a kernel pointer
+    .global program_bad_pointer, program_bad_pointer_end
+    program_bad_pointer:
+        mov eax, {write}
+        mov edi, {stdout}
+        movabs rsi, 0xffff800000000000
+        mov edx, 8
+        syscall
+        mov rdi, rax
+        mov eax, {exit}
+        syscall
+        ud2
+    program_bad_pointer_end:
+
+    // Same, with a pointer to `LOWER_HALF_KERNEL`
+    .global program_lower_half_pointer, program_lower_half_pointer_end
+    program_lower_half_pointer:
+        mov eax, {write}
+        mov edi, {stdout}
+        movabs rsi, {lower_half_kernel}
+        mov edx, 8
+        syscall
+        mov rdi, rax
+        mov eax, {exit}
+        syscall
+        ud2
+    program_lower_half_pointer_end:
+
+    .global program_reads_kernel, program_reads_kernel_end
+    program_reads_kernel:
+        movabs rax, {heap}
+        mov rax, [rax]
+        ud2
+    program_reads_kernel_end:
+
+    .global program_writes_code, program_writes_code_end
+    program_writes_code:
+        lea rax, [rip + program_writes_code]
+        mov byte ptr [rax], 0
+        ud2
+    program_writes_code_end:
+
+    .global program_privileged, program_privileged_end
+    program_privileged:
+        hlt
+        ud2
+    program_privileged_end:
+
+    .global program_invalid_opcode, program_invalid_opcode_end
+    program_invalid_opcode:
+        ud2
+    program_invalid_opcode_end:
+    "#,
+        write = const Syscall::Write as u64,
+        yield_now = const Syscall::Yield as u64,
+        sleep = const Syscall::Sleep as u64,
+        uptime = const Syscall::Uptime as u64,
+        exit = const Syscall::Exit as u64,
+        stdout = const STDOUT,
+        heap = const HEAP_START,
+        lower_half_kernel = const LOWER_HALF_KERNEL,
+    );
+}
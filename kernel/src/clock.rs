@@ -0,0 +1,292 @@
+//! Timekeeping with the TSC and the APIC timer, calibrated at boot
+
+use core::{
+    fmt,
+    ops::{Add, Sub},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use x2apic::lapic::{TimerDivide, TimerMode};
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{acpi::{self, AddressSpace}, apic::{self, ApicInterruptIndex}, percpu, task, thread, PHYSICAL_MEM_OFFSET};
+
+/// How often the APIC timer interrupts each CPU
+pub const TICK_HZ: u64 = 100;
+
+/// How long the TSC and APIC timer are measured for
+const CALIBRATION_MS: u64 = 10;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+const PIT_HZ: u64 = 1_193_182;
+
+/// General capabilities (the counter period, in femtoseconds, is the top half)
+const HPET_CAPABILITIES: usize = 0x00;
+const HPET_CONFIG: usize = 0x10;
+const HPET_ENABLE: u64 = 1;
+const HPET_COUNTER: usize = 0xF0;
+/// The HPET can't tick slower than this (100 ns)
+const HPET_MAX_PERIOD_FS: u64 = 100_000_000;
+
+/// 0 until calibrated
+static TSC_HZ: AtomicU64 = AtomicU64::new(0);
+static APIC_TIMER_HZ: AtomicU64 = AtomicU64::new(0);
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A point in time, measured with the TSC.
+///
+/// Instants can be taken before the clock is calibrated,
+/// but durations between them are zero until it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    tsc: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant { tsc: unsafe { core::arch::x86_64::_rdtsc() } }
+    }
+
+    /// Zero if `earlier` is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        tsc_to_duration(self.tsc.saturating_sub(earlier.tsc))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        Some(Instant { tsc: self.tsc.checked_add(duration_to_tsc(duration)?)? })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        Some(Instant { tsc: self.tsc.checked_sub(duration_to_tsc(duration)?)? })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration).expect("overflow when adding a duration to an instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration).expect("overflow when subtracting a duration from an instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+/// Busy-waits for `duration`. Returns right away before the clock is calibrated.
+pub fn sleep(duration: Duration) {
+    let Some(end) = now().checked_add(duration) else {
+        return;
+    };
+
+    while Instant::now() < end {
+        core::hint::spin_loop();
+    }
+}
+
+/// Timer interrupts on the boot CPU since the clock was initialized
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time since the clock was initialized, in whole ticks
+pub fn uptime() -> Duration {
+    Duration::from_nanos(ticks() * (NANOS_PER_SEC / TICK_HZ))
+}
+
+/// In Hz, once calibrated
+pub fn tsc_frequency() -> Option<u64> {
+    Some(TSC_HZ.load(Ordering::Relaxed)).filter(|&hz| hz != 0)
+}
+
+/// In Hz (after the divider), once calibrated
+pub fn apic_timer_frequency() -> Option<u64> {
+    Some(APIC_TIMER_HZ.load(Ordering::Relaxed)).filter(|&hz| hz != 0)
+}
+
+fn tsc_to_duration(tsc: u64) -> Duration {
+    match tsc_frequency() {
+        Some(hz) => Duration::from_nanos((tsc as u128 * NANOS_PER_SEC as u128 / hz as u128) as u64),
+        None => Duration::ZERO,
+    }
+}
+
+fn duration_to_tsc(duration: Duration) -> Option<u64> {
+    let hz = tsc_frequency().unwrap_or(0);
+
+    (duration.as_nanos() * hz as u128 / NANOS_PER_SEC as u128).try_into().ok()
+}
+
+/// What the clock was calibrated against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference {
+    Hpet,
+    Pit,
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reference::Hpet => write!(f, "HPET"),
+            Reference::Pit => write!(f, "PIT"),
+        }
+    }
+}
+
+/// Calibrates the TSC and APIC timer, then starts ticking on this (the boot) CPU.
+///
+/// Must run after `acpi::init` and the heap, before the APs are started.
+pub(super) fn init() -> Reference {
+    let hpet = Hpet::find();
+    let reference = if hpet.is_some() { Reference::Hpet } else { Reference::Pit };
+
+    let (tsc_ticks, apic_ticks) = interrupts::without_interrupts(|| {
+        apic::with_lapic(|lapic| unsafe {
+            lapic.disable_timer();
+            lapic.set_timer_divide(TimerDivide::Div16);
+            lapic.set_timer_mode(TimerMode::OneShot);
+            lapic.set_timer_initial(u32::MAX);
+        });
+
+        let tsc_start = Instant::now().tsc;
+        let apic_start = apic::timer_current();
+
+        match &hpet {
+            Some(hpet) => hpet.wait_ms(CALIBRATION_MS),
+            None => pit_wait_ms(CALIBRATION_MS),
+        }
+
+        let apic_ticks = apic_start.saturating_sub(apic::timer_current());
+        (Instant::now().tsc - tsc_start, apic_ticks as u64)
+    });
+
+    TSC_HZ.store(tsc_ticks * 1000 / CALIBRATION_MS, Ordering::Relaxed);
+    APIC_TIMER_HZ.store(apic_ticks * 1000 / CALIBRATION_MS, Ordering::Relaxed);
+
+    crate::interrupts::register_vector(ApicInterruptIndex::Timer as u8, |_: &_| {
+        if percpu::cpu() == 0 {
+            TICKS.fetch_add(1, Ordering::Relaxed);
+            task::timer::tick();
+        }
+
+        thread::tick();
+    }).expect("the timer vector is taken");
+
+    init_ap();
+
+    reference
+}
+
+/// Starts the calibrated APIC timer on this CPU
+pub(crate) fn init_ap() {
+    let initial = (APIC_TIMER_HZ.load(Ordering::Relaxed) / TICK_HZ).clamp(1, u32::MAX as u64) as u32;
+
+    apic::with_lapic(|lapic| unsafe {
+        lapic.set_timer_divide(TimerDivide::Div16);
+        lapic.set_timer_mode(TimerMode::Periodic);
+        lapic.set_timer_initial(initial);
+        lapic.enable_timer();
+    });
+}
+
+/// The HPET's main counter
+struct Hpet {
+    registers: *mut u64,
+    period_fs: u64,
+}
+
+impl Hpet {
+    /// The HPET from ACPI, with its counter running
+    fn find() -> Option<Self> {
+        let physical_offset = *PHYSICAL_MEM_OFFSET.get().unwrap();
+        let table = acpi::get()?.hpet.as_ref()?;
+        if table.base_address.address_space != AddressSpace::SystemMemory {
+            return None;
+        }
+
+        let hpet = Hpet {
+            registers: (physical_offset + table.base_address.address) as *mut u64,
+            period_fs: 0,
+        };
+
+        let period_fs = unsafe { hpet.read(HPET_CAPABILITIES) } >> 32;
+        if period_fs == 0 || period_fs > HPET_MAX_PERIOD_FS {
+            return None;
+        }
+
+        unsafe {
+            let config = hpet.read(HPET_CONFIG);
+            hpet.write(HPET_CONFIG, config | HPET_ENABLE);
+        }
+
+        Some(Hpet { period_fs, ..hpet })
+    }
+
+    fn wait_ms(&self, ms: u64) {
+        let ticks = ms * 1_000_000_000_000 / self.period_fs;
+        let start = unsafe { self.read(HPET_COUNTER) };
+
+        while unsafe { self.read(HPET_COUNTER) }.wrapping_sub(start) < ticks {
+            core::hint::spin_loop();
+        }
+    }
+
+    unsafe fn read(&self, offset: usize) -> u64 {
+        self.registers.add(offset / 8).read_volatile()
+    }
+
+    unsafe fn write(&self, offset: usize, value: u64) {
+        self.registers.add(offset / 8).write_volatile(value)
+    }
+}
+
+/// Busy-waits with PIT channel 2 (the speaker's), which can be polled
+/// without interrupts. At most 54 ms.
+fn pit_wait_ms(ms: u64) {
+    let count = (PIT_HZ * ms / 1000).min(u16::MAX as u64) as u16;
+
+    let mut command = Port::<u8>::new(0x43);
+    let mut channel_2 = Port::<u8>::new(0x42);
+    // Bit 0 gates channel 2, bit 1 connects it to the speaker, bit 5 is its output
+    let mut control = Port::<u8>::new(0x61);
+
+    unsafe {
+        let gate = control.read() & !0b10;
+        control.write(gate & !1);
+
+        // Channel 2, low then high byte, mode 0 (output goes high at the end of the count)
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        control.write(gate | 1);
+        while control.read() & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+
+        control.write(gate & !1);
+    }
+}
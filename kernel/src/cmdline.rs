@@ -0,0 +1,98 @@
+//! The kernel command line, e.g. `loglevel=warn fb.scale=1 test=allocator`
+
+use core::fmt;
+
+use spin::Once;
+use tracing::level_filters::LevelFilter;
+
+/// Path of the command line in the initrd
+const CMDLINE_PATH: &str = "boot/cmdline";
+
+static CMDLINE: Once<Cmdline> = Once::new();
+
+/// Reads the command line from the initrd, if there is one.
+///
+/// Must run after `initrd::init`, and before anything that is configured by it.
+pub(super) fn init() {
+    let raw = crate::initrd::archive()
+        .and_then(|initrd| initrd.get(CMDLINE_PATH))
+        .and_then(|entry| entry.as_str())
+        .unwrap_or_default();
+
+    CMDLINE.call_once(|| Cmdline::new(raw));
+}
+
+/// The command line the kernel was booted with (empty if there was none)
+pub fn get() -> &'static Cmdline {
+    CMDLINE.get().unwrap_or(&Cmdline::EMPTY)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Cmdline {
+    raw: &'static str,
+}
+
+impl Cmdline {
+    const EMPTY: Cmdline = Cmdline::new("");
+
+    pub const fn new(raw: &'static str) -> Self {
+        Cmdline { raw }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.trim().is_empty()
+    }
+
+    /// Every option, in order, as `(key, value)`. Flags have no value.
+    pub fn options(&self) -> impl Iterator<Item = (&'static str, Option<&'static str>)> {
+        self.raw.split_whitespace().map(|option| match option.split_once('=') {
+            Some((key, value)) => (key, Some(value)),
+            None => (option, None),
+        })
+    }
+
+    /// The value of the last `key=value` option
+    pub fn get(&self, key: &str) -> Option<&'static str> {
+        self.options()
+            .filter(|(k, _)| *k == key)
+            .filter_map(|(_, value)| value)
+            .last()
+    }
+
+    /// Whether `key` was given as a flag
+    pub fn flag(&self, key: &str) -> bool {
+        self.options().any(|option| option == (key, None))
+    }
+
+    /// `loglevel=<off|error|warn|info|debug|trace>`: the most verbose events that are logged
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.get("loglevel")?.parse().ok()
+    }
+
+    /// `fb.scale=<n>`: how many pixels wide each font pixel is drawn
+    pub fn fb_scale(&self) -> Option<u16> {
+        self.get("fb.scale")?.parse().ok().filter(|&scale| scale > 0)
+    }
+
+    /// `test=<filter>`: only run tests whose name contains `filter`
+    pub fn test_filter(&self) -> Option<&'static str> {
+        self.get("test")
+    }
+
+    /// `keymap=<name>`: the keyboard's keymap, built in or `etc/keymaps/<name>.map` in the initrd
+    pub fn keymap(&self) -> Option<&'static str> {
+        self.get("keymap")
+    }
+
+    /// `init.skip=<stage>,...`: optional init stages (`apic`, `smp`, `ps2`, `tracing`) to skip
+    pub fn skips_init(&self, stage: &str) -> bool {
+        self.get("init.skip")
+            .map_or(false, |stages| stages.split(',').any(|s| s == stage))
+    }
+}
+
+impl fmt::Display for Cmdline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.raw.trim())
+    }
+}
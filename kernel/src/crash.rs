@@ -0,0 +1,157 @@
+//! Crash records on serial, for the runner to symbolize
+
+use core::{arch::asm, fmt::{self, Write}, sync::atomic::{AtomicBool, Ordering}};
+
+use x86_64::{
+    registers::{control::{Cr2, Cr3}, rflags},
+    structures::idt::InterruptStackFrame,
+    VirtAddr,
+};
+
+use crate::{backtrace, emergency_println, exceptions::Registers, memory, serial::EmergencyWriter};
+
+/// Must match `CRASH_BEGIN` in the runner's `crash.rs`
+pub const CRASH_BEGIN: &str = "[kleos: crash begin]";
+/// Must match `CRASH_END` in the runner's `crash.rs`
+pub const CRASH_END: &str = "[kleos: crash end]";
+
+/// Number of 8 byte words of the stack included in the record
+const STACK_WORDS: u64 = 32;
+
+/// Only the first crash is reported, a second one is most likely caused by the first
+static CRASHED: AtomicBool = AtomicBool::new(false);
+
+/// Reports a crash at the caller, e.g. from the panic handler
+#[inline(always)]
+pub fn report(reason: fmt::Arguments) {
+    let (rip, rsp, rbp): (u64, u64, u64);
+    unsafe {
+        asm!(
+            "lea {rip}, [rip]",
+            "mov {rsp}, rsp",
+            "mov {rbp}, rbp",
+            rip = out(reg) rip,
+            rsp = out(reg) rsp,
+            rbp = out(reg) rbp,
+            options(nomem, nostack, preserves_flags),
+        );
+    }
+
+    emit(reason, &[
+        ("rip", rip),
+        ("rsp", rsp),
+        ("rbp", rbp),
+        ("rflags", rflags::read_raw()),
+    ], None, rsp, rbp);
+}
+
+/// Reports a fatal exception, with the state of the interrupted code
+pub fn report_exception(
+    reason: fmt::Arguments,
+    stack_frame: &InterruptStackFrame,
+    registers: &Registers,
+    error_code: Option<u64>,
+) {
+    let mut named = [("", 0); 20];
+    named[..15].copy_from_slice(&registers.named());
+    named[15..].copy_from_slice(&[
+        ("rip", stack_frame.instruction_pointer.as_u64()),
+        ("rsp", stack_frame.stack_pointer.as_u64()),
+        ("rflags", stack_frame.cpu_flags),
+        ("cs", stack_frame.code_segment),
+        ("ss", stack_frame.stack_segment),
+    ]);
+
+    emit(reason, &named, error_code, stack_frame.stack_pointer.as_u64(), registers.rbp);
+}
+
+fn emit(
+    reason: fmt::Arguments,
+    registers: &[(&str, u64)],
+    error_code: Option<u64>,
+    stack_pointer: u64,
+    frame_pointer: u64,
+) {
+    if CRASHED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    emergency_println!("KERNEL CRASH: {reason}");
+
+    // Whatever was printing when the kernel crashed may still hold the lock
+    let _ = write_record(&mut EmergencyWriter, reason, registers, error_code, stack_pointer, frame_pointer);
+
+    let rip = registers.iter().find(|(name, _)| *name == "rip").map(|(_, rip)| *rip);
+    backtrace::print(rip, frame_pointer);
+}
+
+/// Writes the record (everything from [`CRASH_BEGIN`] to [`CRASH_END`]) to `out`
+fn write_record(
+    out: &mut impl Write,
+    reason: fmt::Arguments,
+    registers: &[(&str, u64)],
+    error_code: Option<u64>,
+    stack_pointer: u64,
+    frame_pointer: u64,
+) -> fmt::Result {
+    writeln!(out)?;
+    writeln!(out, "{CRASH_BEGIN}")?;
+
+    write!(out, "reason: ")?;
+    SingleLine(&mut *out).write_fmt(reason)?;
+    writeln!(out)?;
+
+    if let Some(error_code) = error_code {
+        writeln!(out, "error: {error_code:#x}")?;
+    }
+
+    for (name, value) in registers {
+        writeln!(out, "register: {name} {value:#018x}")?;
+    }
+    writeln!(out, "register: cr2 {:#018x}", Cr2::read_raw())?;
+    writeln!(out, "register: cr3 {:#018x}", Cr3::read().0.start_address().as_u64())?;
+
+    // Stop at the first unmapped page rather than faulting again
+    let mut mapped_page = None;
+    for i in 0..STACK_WORDS {
+        let addr = VirtAddr::new_truncate(stack_pointer.wrapping_add(i * 8));
+        if addr.as_u64() % 8 != 0 {
+            break;
+        }
+
+        let page = addr.align_down(4096u64);
+        if mapped_page != Some(page) {
+            if !memory::is_mapped(page) {
+                break;
+            }
+            mapped_page = Some(page);
+        }
+
+        let value = unsafe { addr.as_ptr::<u64>().read_volatile() };
+        writeln!(out, "stack: {:#018x} {value:#018x}", addr.as_u64())?;
+    }
+
+    for return_address in backtrace::frames(frame_pointer) {
+        writeln!(out, "frame: {return_address:#018x}")?;
+    }
+
+    writeln!(out, "{CRASH_END}")?;
+
+    Ok(())
+}
+
+/// Writes to `W` with newlines escaped, so the reason fits on one line of the record
+struct SingleLine<W>(W);
+
+impl<W: Write> Write for SingleLine<W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_str("\\n")?;
+            }
+            self.0.write_str(line)?;
+        }
+
+        Ok(())
+    }
+}
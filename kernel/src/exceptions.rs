@@ -0,0 +1,547 @@
+//! Handlers for every CPU exception
+
+use core::{arch::global_asm, fmt::{self, Write}};
+
+use spin::RwLock;
+use x86_64::{
+    registers::control::Cr2,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    PrivilegeLevel, VirtAddr,
+};
+
+use crate::{crash, emergency_print, gdt, memory, paging, percpu, print, println, process, thread};
+
+/// Number of instruction bytes shown at the faulting address
+const INSTRUCTION_BYTES: u64 = 16;
+
+/// Size of each entry stub, see `exception_stubs`
+const STUB_SIZE: u64 = 16;
+
+static POLICY: RwLock<Policy> = RwLock::new(default_policy);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivideError,
+    Debug,
+    NonMaskableInterrupt,
+    Breakpoint,
+    Overflow,
+    BoundRangeExceeded,
+    InvalidOpcode,
+    DeviceNotAvailable,
+    DoubleFault,
+    InvalidTss,
+    SegmentNotPresent,
+    StackSegmentFault,
+    GeneralProtection,
+    PageFault,
+    X87FloatingPoint,
+    AlignmentCheck,
+    MachineCheck,
+    SimdFloatingPoint,
+    Virtualization,
+    VmmCommunication,
+    Security,
+}
+
+impl Exception {
+    pub fn vector(self) -> u8 {
+        match self {
+            Exception::DivideError          => 0x00,
+            Exception::Debug                => 0x01,
+            Exception::NonMaskableInterrupt => 0x02,
+            Exception::Breakpoint           => 0x03,
+            Exception::Overflow             => 0x04,
+            Exception::BoundRangeExceeded   => 0x05,
+            Exception::InvalidOpcode        => 0x06,
+            Exception::DeviceNotAvailable   => 0x07,
+            Exception::DoubleFault          => 0x08,
+            Exception::InvalidTss           => 0x0A,
+            Exception::SegmentNotPresent    => 0x0B,
+            Exception::StackSegmentFault    => 0x0C,
+            Exception::GeneralProtection    => 0x0D,
+            Exception::PageFault            => 0x0E,
+            Exception::X87FloatingPoint     => 0x10,
+            Exception::AlignmentCheck       => 0x11,
+            Exception::MachineCheck         => 0x12,
+            Exception::SimdFloatingPoint    => 0x13,
+            Exception::Virtualization       => 0x14,
+            Exception::VmmCommunication     => 0x1D,
+            Exception::Security             => 0x1E,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Exception::DivideError          => "divide error",
+            Exception::Debug                => "debug",
+            Exception::NonMaskableInterrupt => "non-maskable interrupt",
+            Exception::Breakpoint           => "breakpoint",
+            Exception::Overflow             => "overflow",
+            Exception::BoundRangeExceeded   => "bound range exceeded",
+            Exception::InvalidOpcode        => "invalid opcode",
+            Exception::DeviceNotAvailable   => "device not available",
+            Exception::DoubleFault          => "double fault",
+            Exception::InvalidTss           => "invalid TSS",
+            Exception::SegmentNotPresent    => "segment not present",
+            Exception::StackSegmentFault    => "stack-segment fault",
+            Exception::GeneralProtection    => "general protection fault",
+            Exception::PageFault            => "page fault",
+            Exception::X87FloatingPoint     => "x87 floating-point exception",
+            Exception::AlignmentCheck       => "alignment check",
+            Exception::MachineCheck         => "machine check",
+            Exception::SimdFloatingPoint    => "SIMD floating-point exception",
+            Exception::Virtualization       => "virtualization exception",
+            Exception::VmmCommunication     => "VMM communication exception",
+            Exception::Security             => "security exception",
+        }
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Exception::DivideError          => "#DE",
+            Exception::Debug                => "#DB",
+            Exception::NonMaskableInterrupt => "NMI",
+            Exception::Breakpoint           => "#BP",
+            Exception::Overflow             => "#OF",
+            Exception::BoundRangeExceeded   => "#BR",
+            Exception::InvalidOpcode        => "#UD",
+            Exception::DeviceNotAvailable   => "#NM",
+            Exception::DoubleFault          => "#DF",
+            Exception::InvalidTss           => "#TS",
+            Exception::SegmentNotPresent    => "#NP",
+            Exception::StackSegmentFault    => "#SS",
+            Exception::GeneralProtection    => "#GP",
+            Exception::PageFault            => "#PF",
+            Exception::X87FloatingPoint     => "#MF",
+            Exception::AlignmentCheck       => "#AC",
+            Exception::MachineCheck         => "#MC",
+            Exception::SimdFloatingPoint    => "#XM",
+            Exception::Virtualization       => "#VE",
+            Exception::VmmCommunication     => "#VC",
+            Exception::Security             => "#SX",
+        }
+    }
+
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        Some(match vector {
+            0x00 => Exception::DivideError,
+            0x01 => Exception::Debug,
+            0x02 => Exception::NonMaskableInterrupt,
+            0x03 => Exception::Breakpoint,
+            0x04 => Exception::Overflow,
+            0x05 => Exception::BoundRangeExceeded,
+            0x06 => Exception::InvalidOpcode,
+            0x07 => Exception::DeviceNotAvailable,
+            0x08 => Exception::DoubleFault,
+            0x0A => Exception::InvalidTss,
+            0x0B => Exception::SegmentNotPresent,
+            0x0C => Exception::StackSegmentFault,
+            0x0D => Exception::GeneralProtection,
+            0x0E => Exception::PageFault,
+            0x10 => Exception::X87FloatingPoint,
+            0x11 => Exception::AlignmentCheck,
+            0x12 => Exception::MachineCheck,
+            0x13 => Exception::SimdFloatingPoint,
+            0x14 => Exception::Virtualization,
+            0x1D => Exception::VmmCommunication,
+            0x1E => Exception::Security,
+            _ => return None,
+        })
+    }
+
+    /// Whether the CPU pushes an error code (must match `exception_stubs`)
+    fn has_error_code(self) -> bool {
+        matches!(
+            self,
+            Exception::DoubleFault
+                | Exception::InvalidTss
+                | Exception::SegmentNotPresent
+                | Exception::StackSegmentFault
+                | Exception::GeneralProtection
+                | Exception::PageFault
+                | Exception::AlignmentCheck
+                | Exception::VmmCommunication
+                | Exception::Security
+        )
+    }
+
+    /// Aborts leave the CPU in a state that can't be returned to
+    fn is_abort(self) -> bool {
+        matches!(self, Exception::DoubleFault | Exception::MachineCheck)
+    }
+
+    /// Whether the error code is a segment selector error code
+    fn has_selector_error_code(self) -> bool {
+        matches!(
+            self,
+            Exception::InvalidTss
+                | Exception::SegmentNotPresent
+                | Exception::StackSegmentFault
+                | Exception::GeneralProtection
+        )
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}, vector {})", self.name(), self.mnemonic(), self.vector())
+    }
+}
+
+/// What a handler does after reporting an exception
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Panic,
+    /// Kill the thread that caused the exception. Panics if it's the idle
+    /// thread, or the exception happened in an interrupt handler.
+    KillTask,
+    /// Return to the interrupted code. For faults, the faulting instruction
+    /// is retried, so the policy should only resume once the cause is fixed.
+    /// Aborts (`#DF`, `#MC`) panic instead.
+    Resume,
+}
+
+/// The interrupted code's general purpose registers, as saved by the entry stub
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl Registers {
+    /// `(name, value)` of every register, in order
+    pub fn named(&self) -> [(&'static str, u64); 15] {
+        [
+            ("rax", self.rax),
+            ("rbx", self.rbx),
+            ("rcx", self.rcx),
+            ("rdx", self.rdx),
+            ("rsi", self.rsi),
+            ("rdi", self.rdi),
+            ("rbp", self.rbp),
+            ("r8", self.r8),
+            ("r9", self.r9),
+            ("r10", self.r10),
+            ("r11", self.r11),
+            ("r12", self.r12),
+            ("r13", self.r13),
+            ("r14", self.r14),
+            ("r15", self.r15),
+        ]
+    }
+}
+
+/// What the entry stubs leave on the stack, lowest address first
+#[repr(C)]
+struct ExceptionFrame {
+    registers: Registers,
+    vector: u64,
+    /// 0 if the exception has none
+    error_code: u64,
+    stack_frame: InterruptStackFrame,
+}
+
+/// Everything known about an exception when the policy is consulted
+#[derive(Debug)]
+pub struct ExceptionInfo<'a> {
+    pub exception: Exception,
+    pub stack_frame: &'a InterruptStackFrame,
+    pub registers: &'a Registers,
+    pub error_code: Option<u64>,
+    /// The address that was accessed, for page faults (CR2)
+    pub fault_address: Option<VirtAddr>,
+}
+
+/// Decides what happens after an exception in the kernel. Faults resolved by
+/// demand paging and exceptions in user mode don't consult it.
+pub type Policy = fn(&ExceptionInfo) -> Action;
+
+/// Replaces the policy deciding what happens after an exception
+pub fn set_policy(policy: Policy) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *POLICY.write() = policy;
+    })
+}
+
+/// Resumes after traps and NMIs, panics on everything else
+pub fn default_policy(info: &ExceptionInfo) -> Action {
+    match info.exception {
+        Exception::Debug
+            | Exception::Breakpoint
+            | Exception::Overflow
+            | Exception::NonMaskableInterrupt => Action::Resume,
+        _ => Action::Panic,
+    }
+}
+
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    let stub = |exception: Exception| {
+        VirtAddr::new(exception_stubs as usize as u64 + u64::from(exception.vector()) * STUB_SIZE)
+    };
+
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(Exception::DivideError));
+        idt.debug.set_handler_addr(stub(Exception::Debug));
+        idt.non_maskable_interrupt.set_handler_addr(stub(Exception::NonMaskableInterrupt))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        // User programs may trap into debuggers too
+        idt.breakpoint.set_handler_addr(stub(Exception::Breakpoint))
+            .set_privilege_level(PrivilegeLevel::Ring3);
+        idt.overflow.set_handler_addr(stub(Exception::Overflow));
+        idt.bound_range_exceeded.set_handler_addr(stub(Exception::BoundRangeExceeded));
+        idt.invalid_opcode.set_handler_addr(stub(Exception::InvalidOpcode));
+        idt.device_not_available.set_handler_addr(stub(Exception::DeviceNotAvailable));
+        idt.double_fault.set_handler_addr(stub(Exception::DoubleFault))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(stub(Exception::InvalidTss));
+        idt.segment_not_present.set_handler_addr(stub(Exception::SegmentNotPresent));
+        idt.stack_segment_fault.set_handler_addr(stub(Exception::StackSegmentFault));
+        idt.general_protection_fault.set_handler_addr(stub(Exception::GeneralProtection));
+        idt.page_fault.set_handler_addr(stub(Exception::PageFault));
+        idt.x87_floating_point.set_handler_addr(stub(Exception::X87FloatingPoint));
+        idt.alignment_check.set_handler_addr(stub(Exception::AlignmentCheck));
+        idt.machine_check.set_handler_addr(stub(Exception::MachineCheck))
+            .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        idt.simd_floating_point.set_handler_addr(stub(Exception::SimdFloatingPoint));
+        idt.virtualization.set_handler_addr(stub(Exception::Virtualization));
+        idt.vmm_communication_exception.set_handler_addr(stub(Exception::VmmCommunication));
+        idt.security_exception.set_handler_addr(stub(Exception::Security));
+    }
+}
+
+/// Called by `exception_common`. Reports the exception and carries out the
+/// policy's decision, returning if the interrupted code should resume.
+extern "C" fn handle(frame: &ExceptionFrame) {
+    let exception = Exception::from_vector(frame.vector as u8)
+        .expect("entry stub installed for an unknown exception");
+    let error_code = exception.has_error_code().then_some(frame.error_code);
+    let ExceptionFrame { registers, stack_frame, .. } = frame;
+
+    let _gs = percpu::swap_gs();
+    let _interrupt = percpu::enter_interrupt();
+
+    let fault_address = (exception == Exception::PageFault)
+        .then(|| VirtAddr::new_truncate(Cr2::read_raw()));
+
+    // Demand paging: map the page and retry the access
+    let fault_error = match (fault_address, error_code) {
+        (Some(addr), Some(error_code)) => {
+            match paging::handle_fault(addr, PageFaultErrorCode::from_bits_truncate(error_code)) {
+                Ok(()) => return,
+                Err(e) => Some(e),
+            }
+        },
+        _ => None,
+    };
+
+    let info = ExceptionInfo { exception, stack_frame, registers, error_code, fault_address };
+
+    let emergency = matches!(exception, Exception::NonMaskableInterrupt | Exception::MachineCheck);
+    let _ = write_report(&mut Report { emergency }, &info);
+
+    if gdt::is_user_mode(stack_frame) {
+        if default_policy(&info) == Action::Resume {
+            return;
+        }
+
+        // Returns if the thread isn't a process'
+        process::kill_current(exception);
+    }
+
+    // An NMI may have interrupted the policy being replaced
+    let policy = if emergency {
+        POLICY.try_read().map_or(default_policy as Policy, |policy| *policy)
+    } else {
+        *POLICY.read()
+    };
+    let action = policy(&info);
+
+    if action == Action::Resume && !exception.is_abort() {
+        return;
+    }
+
+    if action == Action::KillTask && !exception.is_abort() {
+        if let Some((id, name)) = thread::current() {
+            println!("Killing thread {id} `{name}`");
+        }
+
+        // Returns if there's nothing to kill
+        thread::kill_current();
+    }
+
+    match (fault_address, fault_error) {
+        (Some(addr), Some(e)) => crash::report_exception(
+            format_args!("{exception} accessing {:#x} ({e})", addr.as_u64()),
+            stack_frame,
+            registers,
+            error_code,
+        ),
+        _ => crash::report_exception(format_args!("{exception}"), stack_frame, registers, error_code),
+    }
+
+    panic!("{exception}");
+}
+
+/// Where reports are printed. NMIs and machine checks can interrupt code
+/// holding the output locks, so their reports don't wait for them.
+struct Report {
+    emergency: bool,
+}
+
+impl Write for Report {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.emergency {
+            emergency_print!("{s}");
+        } else {
+            print!("{s}");
+        }
+
+        Ok(())
+    }
+}
+
+fn write_report(out: &mut impl Write, info: &ExceptionInfo) -> fmt::Result {
+    let ExceptionInfo { exception, stack_frame, registers, error_code, fault_address } = info;
+    let rip = stack_frame.instruction_pointer;
+
+    writeln!(out, "EXCEPTION: {exception}")?;
+    writeln!(out, "  rip: {:#018x}  rsp: {:#018x}", rip.as_u64(), stack_frame.stack_pointer.as_u64())?;
+    writeln!(out, "  cs:  {:#06x}  ss: {:#06x}  rflags: {:#x}",
+        stack_frame.code_segment, stack_frame.stack_segment, stack_frame.cpu_flags)?;
+
+    for line in registers.named().chunks(3) {
+        for (name, value) in line {
+            write!(out, "  {name:<3}: {value:#018x}")?;
+        }
+        writeln!(out)?;
+    }
+
+    if let Some(error_code) = *error_code {
+        writeln!(out, "  error code: {error_code:#x}")?;
+
+        if exception.has_selector_error_code() {
+            write_selector_error_code(out, error_code)?;
+        }
+    }
+
+    if let Some(fault_address) = fault_address {
+        writeln!(out, "  accessed address (cr2): {:#018x}", fault_address.as_u64())?;
+
+        if let Some(error_code) = *error_code {
+            writeln!(out, "  cause: {:?}", PageFaultErrorCode::from_bits_truncate(error_code))?;
+        }
+    }
+
+    write!(out, "  instruction bytes:")?;
+    for i in 0..INSTRUCTION_BYTES {
+        let addr = rip + i;
+        if !memory::is_mapped(addr) {
+            write!(out, " <unmapped>")?;
+            break;
+        }
+
+        write!(out, " {:02x}", unsafe { addr.as_ptr::<u8>().read_volatile() })?;
+    }
+    writeln!(out)
+}
+
+/// Decodes the error code of `#TS`, `#NP`, `#SS` and `#GP`
+fn write_selector_error_code(out: &mut impl Write, error_code: u64) -> fmt::Result {
+    // Zero if the exception isn't related to a particular segment
+    if error_code == 0 {
+        return Ok(());
+    }
+
+    let table = match (error_code >> 1) & 0b11 {
+        0b00 => "GDT",
+        0b10 => "LDT",
+        _ => "IDT",
+    };
+
+    writeln!(out, "  segment selector:")?;
+    writeln!(out, "    external: {}", if error_code & 1 == 1 { "yes" } else { "no" })?;
+    writeln!(out, "    table: {table}")?;
+    writeln!(out, "    index: {}", (error_code >> 3) & 0x1FFF)
+}
+
+extern "C" {
+    /// The first of the entry stubs, one every [`STUB_SIZE`] bytes, indexed by vector
+    fn exception_stubs();
+}
+
+// Each stub pushes a 0 in place of the error code if the CPU doesn't push one,
+// then the vector, so every exception leaves an `ExceptionFrame`. The CPU
+// aligns the stack to 16 bytes before its frame, which keeps it aligned for
+// the call: 5 words of frame, error code, vector and 15 registers.
+global_asm!(r#"
+.p2align 4
+.global exception_stubs
+exception_stubs:
+.set vector, 0
+.rept 32
+    .p2align 4
+    .if !(vector == 8 || (vector >= 10 && vector <= 14) || vector == 17 || vector == 21 || vector == 29 || vector == 30)
+    push 0
+    .endif
+    push vector
+    jmp exception_common
+    .set vector, vector + 1
+.endr
+
+exception_common:
+    // The `Registers`, backwards
+    push r15
+    push r14
+    push r13
+    push r12
+    push r11
+    push r10
+    push r9
+    push r8
+    push rbp
+    push rdi
+    push rsi
+    push rdx
+    push rcx
+    push rbx
+    push rax
+
+    cld
+    mov rdi, rsp
+    call {handle}
+
+    pop rax
+    pop rbx
+    pop rcx
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rbp
+    pop r8
+    pop r9
+    pop r10
+    pop r11
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+
+    // The vector and error code
+    add rsp, 16
+    iretq
+"#, handle = sym handle);
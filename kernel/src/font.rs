@@ -11,6 +11,7 @@ pub struct Glyph {
 pub const FONT_WIDTH: u16 = 6;
 pub const FONT_HEIGHT: u16 = 13;
 
+/// Default for the `fb.scale` command line option
 pub const FONT_SCALE: u16 = 2;
 
 pub const FONT: &[Glyph] = &[
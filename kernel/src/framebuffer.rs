@@ -7,8 +7,23 @@ use crate::{font, color::{ColorName, Color, THEME}};
 
 pub static FB_WRITER: Once<Mutex<FrameBufferWriter>> = Once::new();
 
-pub(super) fn init(buffer: &'static mut [u8], info: FrameBufferInfo) {
-    FB_WRITER.call_once(|| FrameBufferWriter::new(buffer, info).into());
+/// `scale` is how many pixels wide each font pixel is drawn
+pub(super) fn init(buffer: &'static mut [u8], info: FrameBufferInfo, scale: u16) {
+    FB_WRITER.call_once(|| FrameBufferWriter::new(buffer, info, scale).into());
+}
+
+/// Clears the screen and moves the cursor back to the top left
+pub fn clear_screen() {
+    use ::x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(fb) = FB_WRITER.get() {
+            let mut fb = fb.lock();
+            fb.clear();
+            fb.cell = (0, 0);
+            fb.text_style = TextStyle::default();
+        }
+    })
 }
 
 pub struct TextStyle {
@@ -31,16 +46,18 @@ pub struct FrameBufferWriter {
     cell: (u16, u16),
     text_style: TextStyle,
     bytes_per_pixel: usize,
+    scale: u16,
 }
 
 impl FrameBufferWriter {
-    fn new(buffer: &'static mut [u8], info: FrameBufferInfo) -> Self {
+    fn new(buffer: &'static mut [u8], info: FrameBufferInfo, scale: u16) -> Self {
         let mut fb = FrameBufferWriter {
             buffer,
             info,
             cell: (0, 0),
             text_style: TextStyle::default(),
-            bytes_per_pixel: info.bytes_per_pixel
+            bytes_per_pixel: info.bytes_per_pixel,
+            scale,
         };
 
         fb.clear();
@@ -67,7 +84,7 @@ impl FrameBufferWriter {
     }
 
     fn newline(&mut self) {
-        if self.cell.1 + 1 >= self.info.height as u16 / (font::FONT_HEIGHT * font::FONT_SCALE) {
+        if self.cell.1 + 1 >= self.info.height as u16 / (font::FONT_HEIGHT * self.scale) {
             self.clear();
             self.cell = (0, 0);
         } else {
@@ -108,35 +125,36 @@ impl FrameBufferWriter {
             }
 
             let glyph = &font::FONT[char as usize];
+            let scale = self.scale;
 
             // (0, 0) is at the bottom left of the glyph,
             // while `glyph.raster` starts at the top left,
             // so the glyph has to be offset accordingly
-            let cell_offset_y = font::FONT_SCALE * (font::FONT_HEIGHT.max(glyph.height) - glyph.height);
+            let cell_offset_y = scale * (font::FONT_HEIGHT.max(glyph.height) - glyph.height);
 
             for y in 0..glyph.height {
                 for x in 0..glyph.width {
                     let index = y * glyph.width + x;
                     let pixel = glyph.raster[index as usize];
 
-                    let x = x * font::FONT_SCALE;
-                    let y = y * font::FONT_SCALE;
+                    let x = x * scale;
+                    let y = y * scale;
                     
                     let (sx, sy) = self.cell;
 
-                    let cell_x = font::FONT_SCALE * sx * font::FONT_WIDTH;
-                    let cell_y = font::FONT_SCALE * sy * font::FONT_HEIGHT;
+                    let cell_x = scale * sx * font::FONT_WIDTH;
+                    let cell_y = scale * sy * font::FONT_HEIGHT;
 
-                    let draw_x = (cell_x + x) as isize + (font::FONT_SCALE as isize * glyph.offset_x);
-                    let draw_y = (cell_y + y + cell_offset_y) as isize - (font::FONT_SCALE as isize * glyph.offset_y);
+                    let draw_x = (cell_x + x) as isize + (scale as isize * glyph.offset_x);
+                    let draw_y = (cell_y + y + cell_offset_y) as isize - (scale as isize * glyph.offset_y);
 
                     let color = if pixel { self.text_style.fg } else { self.text_style.bg };
 
-                    self.draw_scaled_pixel(color, font::FONT_SCALE, (draw_x as u16, draw_y as u16));
+                    self.draw_scaled_pixel(color, scale, (draw_x as u16, draw_y as u16));
                 }
             }
 
-            if self.cell.0 + 1 >= self.info.width as u16 / (font::FONT_WIDTH * font::FONT_SCALE) {
+            if self.cell.0 + 1 >= self.info.width as u16 / (font::FONT_WIDTH * self.scale) {
                 self.newline();
             } else {
                 self.cell.0 += 1;
@@ -191,6 +209,19 @@ pub fn _print(args: ::core::fmt::Arguments) {
     })
 }
 
+/// Like `print!`, but never waits for a lock: serial output goes through
+/// [`EmergencyWriter`](crate::serial::EmergencyWriter), and the framebuffer
+/// is skipped while it's in use. For code that may have interrupted the
+/// holder of the lock (NMIs, machine checks, crashes).
+#[doc(hidden)]
+pub fn _emergency_print(args: ::core::fmt::Arguments) {
+    let _ = crate::serial::EmergencyWriter.write_fmt(args);
+
+    if let Some(fb) = FB_WRITER.get() && let Some(mut fb) = fb.try_lock() {
+        let _ = fb.write_fmt(args);
+    }
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::framebuffer::_print(format_args!($($arg)*)));
@@ -202,3 +233,13 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+#[macro_export]
+macro_rules! emergency_print {
+    ($($arg:tt)*) => ($crate::framebuffer::_emergency_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! emergency_println {
+    ()            => ($crate::emergency_print!("\n"));
+    ($($arg:tt)*) => ($crate::emergency_print!("{}\n", format_args!($($arg)*)));
+}
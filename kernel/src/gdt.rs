@@ -1,42 +1,123 @@
+use alloc::boxed::Box;
+use core::{ptr::{addr_of, addr_of_mut}, sync::atomic::{AtomicPtr, Ordering}};
+
 use spin::Lazy;
 use x86_64::instructions::tables::load_tss;
 use x86_64::registers::segmentation::{CS, Segment, DS, ES, SS};
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector}; 
-use x86_64::{structures::tss::TaskStateSegment, VirtAddr};
-
-pub static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| {
-    let mut gdt = GlobalDescriptorTable::new();
-    let kernel_cs = gdt.add_entry(Descriptor::kernel_code_segment());
-    let kernel_ds = gdt.add_entry(Descriptor::kernel_data_segment());
-    let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::{structures::tss::TaskStateSegment, PrivilegeLevel, VirtAddr};
 
-    (gdt, Selectors { kernel_cs, kernel_ds, tss })
-});
+use crate::percpu::{self, MAX_CPUS};
 
-pub(crate) static TSS: Lazy<TaskStateSegment> = Lazy::new(|| {
-    let mut tss = TaskStateSegment::new();
+/// The boot CPU's GDT, every other CPU gets its own (see [`init_ap`])
+pub static GDT: Lazy<(GlobalDescriptorTable, Selectors)> = Lazy::new(|| new_gdt(unsafe { &*addr_of!(TSS) }));
 
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 4096 * 5;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// The boot CPU's TSS. The CPU reads the privilege stack from it, which
+/// changes with every thread switch, so it's only written through [`TSSES`].
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
 
-        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-        // let stack_end = 
-        stack_start + STACK_SIZE
-    };
+/// Each CPU's TSS, null until its GDT is loaded
+static TSSES: [AtomicPtr<TaskStateSegment>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<TaskStateSegment> = AtomicPtr::new(core::ptr::null_mut());
+    [NONE; MAX_CPUS]
+};
 
-    tss
-});
+/// Each AP's GDT, null until made by the boot CPU (see [`prepare_ap`])
+static AP_GDTS: [AtomicPtr<(GlobalDescriptorTable, Selectors)>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const NONE: AtomicPtr<(GlobalDescriptorTable, Selectors)> = AtomicPtr::new(core::ptr::null_mut());
+    [NONE; MAX_CPUS]
+};
 
+/// The interrupt stacks in each CPU's TSS, for exceptions that can't be
+/// handled on the interrupted stack: it may have overflowed (`#DF`), or be
+/// the user's, right after `syscall` (NMIs and `#MC` can arrive anywhere).
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const NMI_IST_INDEX: u16 = 1;
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+pub const IST_STACKS: usize = 3;
+
+/// Size of each interrupt stack
+pub const IST_STACK_SIZE: usize = 4096 * 5;
 
 /// # Safety
 ///
-/// This function may only be called once.
+/// This function may only be called once, on the boot CPU.
 pub unsafe fn init() {
+    static mut STACKS: [[u8; IST_STACK_SIZE]; IST_STACKS] = [[0; IST_STACK_SIZE]; IST_STACKS];
+
+    let tss = addr_of_mut!(TSS);
+    for (index, stack) in (*addr_of!(STACKS)).iter().enumerate() {
+        (*tss).interrupt_stack_table[index] = VirtAddr::from_ptr(stack) + IST_STACK_SIZE;
+    }
+    TSSES[0].store(tss, Ordering::Relaxed);
+
     let (gdt, selectors) = &*GDT;
+    load(gdt, selectors);
+}
+
+/// Makes the GDT and TSS of application processor `cpu`, for [`init_ap`] to load.
+/// `ist_stacks` are the tops of its interrupt stacks, by IST index.
+///
+/// Runs on the boot CPU: the heap is mapped on first access, and the AP can't
+/// take that page fault before it has an IDT.
+pub(crate) fn prepare_ap(cpu: usize, ist_stacks: [VirtAddr; IST_STACKS]) {
+    let mut tss = TaskStateSegment::new();
+    for (index, top) in ist_stacks.into_iter().enumerate() {
+        tss.interrupt_stack_table[index] = top;
+    }
+
+    let tss = Box::leak(Box::new(tss));
+    TSSES[cpu].store(tss, Ordering::Release);
+    AP_GDTS[cpu].store(Box::into_raw(Box::new(new_gdt(tss))), Ordering::Release);
+}
+
+/// Loads the GDT and TSS made by [`prepare_ap`] on application processor `cpu`.
+///
+/// # Safety
+///
+/// This function may only be called once per AP, after [`prepare_ap`].
+pub(crate) unsafe fn init_ap(cpu: usize) {
+    let (gdt, selectors) = &*AP_GDTS[cpu].load(Ordering::Acquire);
+    load(gdt, selectors);
+}
+
+/// `syscall` and `sysret` find the segments by their offsets from each other,
+/// so the order matters: kernel code and data, then user data and code.
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_cs = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_ds = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_ds = gdt.add_entry(Descriptor::user_data_segment());
+    let user_cs = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+
+    (gdt, Selectors { kernel_cs, kernel_ds, user_cs, user_ds, tss })
+}
+
+/// Sets the stack this CPU switches to when interrupted in user mode
+/// (the TSS' privilege stack for ring 0). Called with interrupts disabled,
+/// as threads are switched.
+pub(crate) fn set_privilege_stack(top: Option<VirtAddr>) {
+    let tss = TSSES[percpu::cpu()].load(Ordering::Relaxed);
+    if tss.is_null() {
+        return;
+    }
+
+    let top = top.unwrap_or_else(VirtAddr::zero);
+    unsafe { addr_of_mut!((*tss).privilege_stack_table[0]).write(top) };
+}
+
+/// Whether an interrupt or exception came from user mode (ring 3)
+pub fn is_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    SegmentSelector(stack_frame.code_segment as u16).rpl() == PrivilegeLevel::Ring3
+}
+
+unsafe fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
     gdt.load();
-    
+
     CS::set_reg(selectors.kernel_cs);
     DS::set_reg(selectors.kernel_ds);
     ES::set_reg(selectors.kernel_ds);
@@ -48,5 +129,25 @@ pub unsafe fn init() {
 pub struct Selectors {
     kernel_cs: SegmentSelector,
     kernel_ds: SegmentSelector,
+    user_cs: SegmentSelector,
+    user_ds: SegmentSelector,
     tss: SegmentSelector,
 }
+
+impl Selectors {
+    pub fn kernel_cs(&self) -> SegmentSelector {
+        self.kernel_cs
+    }
+
+    pub fn kernel_ds(&self) -> SegmentSelector {
+        self.kernel_ds
+    }
+
+    pub fn user_cs(&self) -> SegmentSelector {
+        self.user_cs
+    }
+
+    pub fn user_ds(&self) -> SegmentSelector {
+        self.user_ds
+    }
+}
@@ -0,0 +1,205 @@
+//! Read-only access to the initial ramdisk, a tar archive of the host's `rootfs/`
+
+use core::{fmt, str};
+
+use spin::Once;
+
+static INITRD: Once<Archive> = Once::new();
+
+const BLOCK_SIZE: usize = 512;
+
+/// # Safety
+///
+/// `addr` and `len` must describe the ramdisk mapped by the bootloader,
+/// which must stay mapped (and unmodified) for the rest of the kernel's lifetime.
+pub(super) unsafe fn init(addr: u64, len: u64) {
+    let data = core::slice::from_raw_parts(addr as *const u8, len as usize);
+
+    INITRD.call_once(|| Archive::new(data));
+}
+
+/// The initrd, if the kernel was booted with one
+pub fn archive() -> Option<&'static Archive> {
+    INITRD.get()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other(u8),
+}
+
+/// Path of an entry relative to the archive root, without a leading `./` or `/`.
+///
+/// ustar headers split long paths into a prefix and a name,
+/// which aren't contiguous in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct EntryPath {
+    prefix: &'static str,
+    name: &'static str,
+}
+
+impl PartialEq<&str> for EntryPath {
+    fn eq(&self, other: &&str) -> bool {
+        if self.prefix.is_empty() {
+            return self.name == *other;
+        }
+
+        other.strip_prefix(self.prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .map_or(false, |rest| rest == self.name)
+    }
+}
+
+impl fmt::Display for EntryPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.prefix.is_empty() {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}/{}", self.prefix, self.name)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub path: EntryPath,
+    pub kind: EntryKind,
+    pub data: &'static [u8],
+}
+
+impl Entry {
+    /// The entry's contents, if they are valid UTF-8
+    pub fn as_str(&self) -> Option<&'static str> {
+        str::from_utf8(self.data).ok()
+    }
+}
+
+/// A (ustar or GNU) tar archive
+pub struct Archive {
+    data: &'static [u8],
+}
+
+impl Archive {
+    pub const fn new(data: &'static [u8]) -> Self {
+        Archive { data }
+    }
+
+    pub fn entries(&self) -> Entries {
+        Entries { data: self.data, offset: 0 }
+    }
+
+    /// Looks up the entry at `path` (`etc/motd`, `/etc/motd` and `./etc/motd` are equivalent).
+    ///
+    /// As when extracting the archive, later entries replace earlier ones with the same path.
+    pub fn get(&self, path: &str) -> Option<Entry> {
+        let path = normalize(path);
+        self.entries().filter(|e| e.path == path).last()
+    }
+
+    /// Returns the contents of the file at `path`
+    pub fn read(&self, path: &str) -> Option<&'static [u8]> {
+        self.get(path)
+            .filter(|e| e.kind == EntryKind::File)
+            .map(|e| e.data)
+    }
+}
+
+pub struct Entries {
+    data: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Entries {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // GNU tar stores paths longer than 100 bytes in a separate `L` entry
+        // that precedes the entry it names.
+        let mut long_name: Option<&'static str> = None;
+
+        loop {
+            let header = self.data.get(self.offset..self.offset + BLOCK_SIZE)?;
+
+            // The archive ends with (at least) two zeroed blocks
+            if header.iter().all(|&b| b == 0) || !is_valid_header(header) {
+                return None;
+            }
+
+            let size = parse_octal(&header[124..136])? as usize;
+            let data_start = self.offset + BLOCK_SIZE;
+            let data = self.data.get(data_start..data_start + size)?;
+
+            // Entry data is padded to a whole number of blocks
+            self.offset = data_start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+
+            let kind = match header[156] {
+                b'0' | 0 => EntryKind::File,
+                b'5' => EntryKind::Directory,
+                b'2' => EntryKind::Symlink,
+                b'L' => {
+                    long_name = Some(cstr(data));
+                    continue;
+                },
+                other => EntryKind::Other(other),
+            };
+
+            let path = match long_name {
+                Some(name) => EntryPath { prefix: "", name: normalize(name) },
+                // GNU headers use the prefix field for other things
+                None if is_ustar(header) && header[345] != 0 => EntryPath {
+                    prefix: normalize(cstr(&header[345..500])),
+                    name: cstr(&header[0..100]).trim_end_matches('/'),
+                },
+                None => EntryPath { prefix: "", name: normalize(cstr(&header[0..100])) },
+            };
+
+            if path.prefix.is_empty() && path.name.is_empty() {
+                // The archive root (`./`)
+                continue;
+            }
+
+            return Some(Entry { path, kind, data });
+        }
+    }
+}
+
+fn is_ustar(header: &[u8]) -> bool {
+    &header[257..263] == b"ustar\0"
+}
+
+/// Checks the header's checksum, which is the sum of all header bytes with
+/// the checksum field itself taken to be spaces.
+fn is_valid_header(header: &[u8]) -> bool {
+    let Some(checksum) = parse_octal(&header[148..156]) else { return false };
+
+    let sum: u64 = header.iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum();
+
+    sum == checksum
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let s = str::from_utf8(field).ok()?;
+    let s = s.trim_matches(|c: char| c == '\0' || c == ' ');
+
+    if s.is_empty() {
+        return Some(0);
+    }
+
+    u64::from_str_radix(s, 8).ok()
+}
+
+fn cstr(bytes: &'static [u8]) -> &'static str {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+fn normalize(path: &str) -> &str {
+    let path = path.trim_start_matches("./").trim_start_matches('/');
+    path.strip_suffix('/').unwrap_or(path)
+}
@@ -1,28 +1,43 @@
-use spin::Lazy;
-use x86_64::structures::idt::InterruptDescriptorTable;
+//! The IDT, and registration of interrupt handlers at runtime
 
-use crate::{gdt, apic::ApicInterruptIndex};
+use alloc::boxed::Box;
+
+use spin::{Lazy, Mutex};
+use x86_64::{instructions::interrupts, structures::idt::{InterruptDescriptorTable, InterruptStackFrame}};
+
+use crate::{apic::{self, ApicInterruptIndex}, exceptions, ioapic::{self, IoApicError}, percpu, thread};
+
+/// Vectors below this are either exceptions or legacy IRQs,
+/// so they're never handed out by [`register`]
+const FIRST_FREE_VECTOR: u8 = apic::IOAPIC_IRQ_OFFSET + apic::LEGACY_IRQS;
 
 static IDT: Lazy<InterruptDescriptorTable> = Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
 
-    idt.breakpoint.set_handler_fn(handlers::breakpoint);
-    idt.page_fault.set_handler_fn(handlers::page_fault);
-    idt.general_protection_fault.set_handler_fn(handlers::general_protection);
+    exceptions::set_handlers(&mut idt);
 
-    unsafe { 
-        idt.double_fault.set_handler_fn(handlers::double_fault)
-            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    macro_rules! set_dispatchers {
+        ($($high:literal)*) => {
+            $( set_dispatchers!(@row $high, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15); )*
+        };
+        (@row $high:literal, $($low:literal)*) => {
+            $( idt[$high * 16 + $low].set_handler_fn(dispatch::<{ $high * 16 + $low }>); )*
+        };
     }
+    set_dispatchers!(2 3 4 5 6 7 8 9 10 11 12 13 14 15);
 
-    idt[ApicInterruptIndex::Timer as usize].set_handler_fn(handlers::timer);
-    idt[ApicInterruptIndex::Keyboard as usize].set_handler_fn(handlers::keyboard);
     idt[ApicInterruptIndex::Error as usize].set_handler_fn(handlers::error);
     idt[ApicInterruptIndex::Spurious as usize].set_handler_fn(handlers::spurious);
 
     idt
 });
 
+static HANDLERS: [Mutex<Option<Registration>>; 256] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Mutex<Option<Registration>> = Mutex::new(None);
+    [EMPTY; 256]
+};
+
 pub fn init() {
     unsafe {
         crate::gdt::init();
@@ -31,77 +46,156 @@ pub fn init() {
     IDT.load();
 }
 
-mod handlers {
-    use x86_64::{structures::idt::{InterruptStackFrame, PageFaultErrorCode}, instructions::port::Port};
+/// Loads the IDT on an application processor, which has its GDT loaded already
+pub(crate) fn init_ap() {
+    IDT.load();
+}
 
-    use crate::{println, apic::LAPIC, hlt_loop};
+/// Something that handles an interrupt. Implemented for closures.
+pub trait Handler: Send {
+    fn handle(&mut self, stack_frame: &InterruptStackFrame);
+}
 
-    pub extern "x86-interrupt" fn breakpoint(stack_frame: InterruptStackFrame) {
-        println!("EXCEPTION: BREAKPOINT");
-        println!("{stack_frame:#?}");
+impl<F: FnMut(&InterruptStackFrame) + Send> Handler for F {
+    fn handle(&mut self, stack_frame: &InterruptStackFrame) {
+        self(stack_frame)
     }
+}
 
-    pub extern "x86-interrupt" fn timer(_: InterruptStackFrame) {
-        // print!(".");
-        unsafe { LAPIC.lock().end_of_interrupt() }
-    }
-    
-    pub extern "x86-interrupt" fn keyboard(_: InterruptStackFrame) {
-        let mut port = Port::new(0x60);
-        let scancode: u8 = unsafe { port.read() };
-        println!("{scancode}");
+struct Registration {
+    handler: Box<dyn Handler>,
+    /// The legacy IRQ delivered at this vector, unmasked while registered
+    irq: Option<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// The vector already has a handler
+    InUse(u8),
+    /// The vector is an exception, or handled by the kernel itself
+    Reserved(u8),
+    /// Only legacy ISA IRQs (0-15) can be registered
+    InvalidIrq(u8),
+    NoFreeVectors,
+    /// The IRQ couldn't be routed to this CPU
+    IoApic(IoApicError),
+}
 
-        unsafe { LAPIC.lock().end_of_interrupt() }
+/// Installs `handler` at a free vector, returning the vector
+pub fn register(handler: impl Handler + 'static) -> Result<u8, RegisterError> {
+    let mut handler = Some(Box::new(handler) as Box<dyn Handler>);
+
+    interrupts::without_interrupts(|| {
+        (FIRST_FREE_VECTOR..=u8::MAX)
+            // Left for the clock, which registers it with `register_vector`
+            .filter(|&vector| !is_reserved(vector) && vector != ApicInterruptIndex::Timer as u8)
+            .find(|&vector| {
+                let mut slot = HANDLERS[vector as usize].lock();
+                if slot.is_some() {
+                    return false;
+                }
+
+                *slot = handler.take().map(|handler| Registration { handler, irq: None });
+                true
+            })
+            .ok_or(RegisterError::NoFreeVectors)
+    })
+}
+
+/// Installs `handler` at `vector`
+pub fn register_vector(vector: u8, handler: impl Handler + 'static) -> Result<(), RegisterError> {
+    install(vector, Registration { handler: Box::new(handler), irq: None })
+}
+
+/// Installs `handler` for legacy ISA `irq`, then routes the IRQ to this CPU
+/// and unmasks it. Returns the vector it's delivered at.
+pub fn register_irq(irq: u8, handler: impl Handler + 'static) -> Result<u8, RegisterError> {
+    if irq >= apic::LEGACY_IRQS {
+        return Err(RegisterError::InvalidIrq(irq));
     }
-    
-    pub extern "x86-interrupt" fn error(stack_frame: InterruptStackFrame) {
-        println!("RECEIVED ERROR INTERRUPT: {stack_frame:#?}");
-        unsafe { LAPIC.lock().end_of_interrupt() }
+
+    let vector = apic::irq_vector(irq);
+    install(vector, Registration { handler: Box::new(handler), irq: Some(irq) })?;
+
+    let routed = ioapic::route_irq(irq, vector, apic::id()).and_then(|_| ioapic::unmask(irq));
+    if let Err(e) = routed {
+        interrupts::without_interrupts(|| HANDLERS[vector as usize].lock().take());
+        return Err(RegisterError::IoApic(e));
     }
-    
-    pub extern "x86-interrupt" fn spurious(stack_frame: InterruptStackFrame) {
-        println!("RECEIVED SPURIOUS INTERRUPT: {stack_frame:#?}");
-        unsafe { LAPIC.lock().end_of_interrupt() }
+
+    Ok(vector)
+}
+
+/// Removes the handler at `vector` (masking its IRQ, if it was registered
+/// with [`register_irq`]) and returns it.
+///
+/// Must not be called from the handler being removed.
+pub fn unregister(vector: u8) -> Option<Box<dyn Handler>> {
+    let registration = interrupts::without_interrupts(|| HANDLERS[vector as usize].lock().take())?;
+
+    if let Some(irq) = registration.irq {
+        // It was unmasked when registered, so the IOAPIC is there
+        let _ = ioapic::mask(irq);
     }
 
-    pub extern "x86-interrupt" fn page_fault(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
-        println!("PAGE_FAULT:");
-        println!("error code {error_code:?}");
-        println!("{stack_frame:#?}");
+    Some(registration.handler)
+}
+
+fn install(vector: u8, registration: Registration) -> Result<(), RegisterError> {
+    if is_reserved(vector) {
+        return Err(RegisterError::Reserved(vector));
     }
-    
-    pub extern "x86-interrupt" fn general_protection(_stack_frame: InterruptStackFrame, error_code: u64) {
-        println!("GENERAL PROTECTION FAULT:");
-
-        if error_code > 0 {
-            let ssi = error_code;
-
-            println!("  Segment Selector:");
-            println!("    External: {}", if ssi & 1 == 1 { "yes" } else { "no" });
-            println!("    Table: {}", 
-                match (ssi & 0b110) >> 1 {
-                    0b00 => "GDT",
-                    0b01 => "IDT",
-                    0b10 => "LDT",
-                    0b11 => "IDT",
-                    _ => unreachable!()
-                }
-            );
-            println!("    Index: {}", (ssi & 0b1_1111_1111_1111) >> 3);
-        } else {
-            println!("error code {error_code:?}");
+
+    interrupts::without_interrupts(|| {
+        let mut slot = HANDLERS[vector as usize].lock();
+        if slot.is_some() {
+            return Err(RegisterError::InUse(vector));
         }
 
-        hlt_loop();
+        *slot = Some(registration);
+        Ok(())
+    })
+}
+
+fn is_reserved(vector: u8) -> bool {
+    vector < 32
+        || vector == ApicInterruptIndex::Error as u8
+        || vector == ApicInterruptIndex::Spurious as u8
+}
+
+extern "x86-interrupt" fn dispatch<const VECTOR: u8>(stack_frame: InterruptStackFrame) {
+    // Dropped last, after switching back to this thread
+    let _gs = percpu::swap_gs();
+
+    {
+        let _interrupt = percpu::enter_interrupt();
 
-        // println!("{stack_frame:#?}");
+        if let Some(registration) = &mut *HANDLERS[VECTOR as usize].lock() {
+            registration.handler.handle(&stack_frame);
+        }
+
+        apic::with_lapic(|lapic| unsafe { lapic.end_of_interrupt() })
     }
 
-    pub extern "x86-interrupt" fn double_fault(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
-        println!("DOUBLE FAULT:");
-        println!("error code {error_code:?}");
-        println!("{stack_frame:#?}");
+    // Only once the interrupt is acknowledged, or it would block the
+    // timer until this thread runs again
+    thread::preempt();
+}
+
+mod handlers {
+    use x86_64::structures::idt::InterruptStackFrame;
+
+    use crate::{println, apic, percpu};
 
-        panic!("double fault");
+    pub extern "x86-interrupt" fn error(stack_frame: InterruptStackFrame) {
+        let _gs = percpu::swap_gs();
+        println!("RECEIVED ERROR INTERRUPT: {stack_frame:#?}");
+        apic::with_lapic(|lapic| unsafe { lapic.end_of_interrupt() })
+    }
+
+    pub extern "x86-interrupt" fn spurious(stack_frame: InterruptStackFrame) {
+        let _gs = percpu::swap_gs();
+        println!("RECEIVED SPURIOUS INTERRUPT: {stack_frame:#?}");
+        apic::with_lapic(|lapic| unsafe { lapic.end_of_interrupt() })
     }
 }
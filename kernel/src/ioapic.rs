@@ -0,0 +1,138 @@
+//! The IOAPICs, which deliver device interrupts to the local APICs
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use spin::{Mutex, Once};
+use x2apic::ioapic::{IoApic, IrqFlags, IrqMode};
+use x86_64::instructions::interrupts;
+
+use crate::{acpi::{self, madt::{Polarity, TriggerMode}}, apic, PHYSICAL_MEM_OFFSET};
+
+/// Where the IOAPIC usually is, if ACPI doesn't say
+const DEFAULT_IOAPIC_ADDRESS: u64 = 0xFEC0_0000;
+
+static IOAPICS: Once<Mutex<Vec<Controller>>> = Once::new();
+
+struct Controller {
+    regs: IoApic,
+    gsi_base: u32,
+    /// Number of inputs (redirection table entries)
+    inputs: u32,
+}
+
+// The registers are only accessed with the lock held
+unsafe impl Send for Controller {}
+
+impl Controller {
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.inputs).contains(&gsi)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoApicError {
+    /// `init` hasn't run
+    Uninitialized,
+    /// No IOAPIC handles the GSI the IRQ is wired to
+    NoIoApic { irq: u8, gsi: u32 },
+}
+
+impl fmt::Display for IoApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoApicError::Uninitialized => write!(f, "IOAPICs not initialized"),
+            IoApicError::NoIoApic { irq, gsi } => write!(f, "no IOAPIC for IRQ {irq} (GSI {gsi})"),
+        }
+    }
+}
+
+/// Sets up every IOAPIC, with all inputs masked, and returns how many there are.
+///
+/// Must run after `acpi::init`, and the heap.
+pub(super) fn init() -> usize {
+    let physical_offset = *PHYSICAL_MEM_OFFSET.get().unwrap();
+
+    let mut io_apics: Vec<(u64, u32)> = acpi::get()
+        .and_then(|acpi| acpi.madt.as_ref())
+        .map(|madt| madt.io_apics.iter().map(|io_apic| (io_apic.address as u64, io_apic.gsi_base)).collect())
+        .unwrap_or_default();
+
+    if io_apics.is_empty() {
+        io_apics.push((DEFAULT_IOAPIC_ADDRESS, 0));
+    }
+
+    let controllers: Vec<_> = io_apics.into_iter()
+        .map(|(address, gsi_base)| unsafe {
+            let mut regs = IoApic::new(physical_offset + address);
+
+            // Inputs start out delivering GSI n at vector `IOAPIC_IRQ_OFFSET + n`, masked
+            regs.init(apic::IOAPIC_IRQ_OFFSET.wrapping_add(gsi_base as u8));
+            let inputs = regs.max_table_entry() as u32 + 1;
+
+            Controller { regs, gsi_base, inputs }
+        })
+        .collect();
+
+    IOAPICS.call_once(|| Mutex::new(controllers)).lock().len()
+}
+
+/// Routes legacy ISA `irq` to `vector` on the CPU with local APIC ID `cpu`.
+///
+/// The IRQ is left masked, see [`unmask`].
+pub fn route_irq(irq: u8, vector: u8, cpu: u32) -> Result<(), IoApicError> {
+    let (gsi, polarity, trigger_mode) = isa_irq(irq);
+
+    // ISA interrupts are active high and edge triggered, unless overridden
+    let mut flags = IrqFlags::MASKED;
+    if polarity == Polarity::ActiveLow {
+        flags |= IrqFlags::LOW_ACTIVE;
+    }
+    if trigger_mode == TriggerMode::Level {
+        flags |= IrqFlags::LEVEL_TRIGGERED;
+    }
+
+    with_input(irq, gsi, |regs, input| unsafe {
+        let mut entry = regs.table_entry(input);
+        entry.set_vector(vector);
+        entry.set_mode(IrqMode::Fixed);
+        entry.set_flags(flags);
+        entry.set_dest(cpu as u8);
+        regs.set_table_entry(input, entry);
+    })
+}
+
+/// Stops legacy ISA `irq` from being delivered
+pub fn mask(irq: u8) -> Result<(), IoApicError> {
+    let (gsi, _, _) = isa_irq(irq);
+    with_input(irq, gsi, |regs, input| unsafe { regs.disable_irq(input) })
+}
+
+/// Delivers legacy ISA `irq` to wherever it was routed
+pub fn unmask(irq: u8) -> Result<(), IoApicError> {
+    let (gsi, _, _) = isa_irq(irq);
+    with_input(irq, gsi, |regs, input| unsafe { regs.enable_irq(input) })
+}
+
+/// The GSI legacy ISA `irq` is wired to, with its polarity and trigger mode
+fn isa_irq(irq: u8) -> (u32, Polarity, TriggerMode) {
+    acpi::get()
+        .and_then(|acpi| acpi.madt.as_ref())
+        .map_or((irq as u32, Polarity::BusDefault, TriggerMode::BusDefault), |madt| madt.isa_irq(irq))
+}
+
+fn with_input(irq: u8, gsi: u32, f: impl FnOnce(&mut IoApic, u8)) -> Result<(), IoApicError> {
+    let io_apics = IOAPICS.get().ok_or(IoApicError::Uninitialized)?;
+
+    interrupts::without_interrupts(|| {
+        let mut io_apics = io_apics.lock();
+        let controller = io_apics.iter_mut()
+            .find(|controller| controller.handles(gsi))
+            .ok_or(IoApicError::NoIoApic { irq, gsi })?;
+
+        let input = (gsi - controller.gsi_base) as u8;
+        f(&mut controller.regs, input);
+
+        Ok(())
+    })
+}
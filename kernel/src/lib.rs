@@ -2,6 +2,7 @@
 #![feature(
     abi_x86_interrupt,
     alloc_error_handler,
+    asm_const,
     const_mut_refs,
     let_chains,
     slice_as_chunks
@@ -9,21 +10,39 @@
 
 extern crate alloc;
 
-use bootloader_api::BootInfo;
+use core::ops::{Deref, DerefMut};
+
+use bootloader_api::{BootInfo, BootloaderConfig, config::Mapping};
 use spin::{Once, Mutex, MutexGuard};
 use x86_64::VirtAddr;
 
-use crate::memory::BootInfoFrameAllocator;
-
+pub mod acpi;
 pub mod allocator;
 pub mod apic;
+pub mod backtrace;
+pub mod clock;
+pub mod cmdline;
 pub mod color;
+pub mod crash;
+pub mod exceptions;
 pub mod font;
 pub mod framebuffer;
 pub mod gdt;
+pub mod initrd;
 pub mod interrupts;
+pub mod ioapic;
 pub mod memory;
+pub mod paging;
+pub mod percpu;
+pub mod process;
+pub mod ps2;
+pub mod queue;
 pub mod serial;
+pub mod smp;
+pub mod syscall;
+pub mod task;
+pub mod testing;
+pub mod thread;
 pub mod tracing;
 
 pub struct Locked<T> {
@@ -35,52 +54,195 @@ impl<T> Locked<T> {
         Locked { inner: Mutex::new(inner) }
     }
 
-    fn lock(&self) -> MutexGuard<T> {
-        self.inner.lock()
+    /// Interrupts stay disabled while the lock is held,
+    /// so the holder can't be preempted by a thread wanting it too
+    fn lock(&self) -> LockedGuard<T> {
+        let interrupts_enabled = x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::disable();
+
+        LockedGuard { guard: Some(self.inner.lock()), interrupts_enabled }
+    }
+}
+
+struct LockedGuard<'a, T> {
+    guard: Option<MutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> Deref for LockedGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for LockedGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for LockedGuard<'_, T> {
+    fn drop(&mut self) {
+        // Unlock first
+        self.guard.take();
+
+        if self.interrupts_enabled {
+            x86_64::instructions::interrupts::enable();
+        }
     }
 }
 
+/// Shared by every kernel binary (the kernel itself and the test kernel)
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x0000_f000_0000_0000));
+    config
+};
+
 pub static PHYSICAL_MEM_OFFSET: Once<u64> = Once::new();
 
 pub fn init(boot_info: &'static mut BootInfo) {
     PHYSICAL_MEM_OFFSET.call_once(|| *boot_info.physical_memory_offset.as_ref().unwrap());
+    let start = clock::now();
+
+    // Interrupt handlers use per-CPU data
+    unsafe { percpu::init(0) };
 
     let green = color::ANSI_ESCAPES[color::ColorName::Green as usize];
+    let red = color::ANSI_ESCAPES[color::ColorName::Red as usize];
     let clear = color::ANSI_ESCAPES[color::ColorName::Foreground as usize];
 
+    // The command line (read from the initrd) configures the other stages,
+    // so it has to be available before anything else runs
+    if let Some(addr) = boot_info.ramdisk_addr.into_option() {
+        unsafe { initrd::init(addr, boot_info.ramdisk_len) };
+    }
+    cmdline::init();
+    let cmdline = cmdline::get();
+
     // Interrupts
     print!("INIT: Interrupts.... ");
     interrupts::init();
     println!("[{green}OK{clear}]");
 
     // APIC
-    print!("INIT: APIC.......... ");
-    apic::init();
-    println!("[{green}OK{clear}]");
+    if !cmdline.skips_init("apic") {
+        print!("INIT: APIC.......... ");
+        apic::init();
+        println!("[{green}OK{clear}]");
+    }
 
     // Heap
     print!("INIT: Heap.......... ");
     let physical_mem_offset = VirtAddr::new(*PHYSICAL_MEM_OFFSET.get().unwrap());
-    let mut mapper = unsafe { memory::init(physical_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootInfoFrameAllocator::init(&boot_info.memory_regions)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    unsafe { memory::init(physical_mem_offset, &boot_info.memory_regions) };
+    allocator::init_heap()
         .expect("Heap initialization failed");
     println!("[{green}OK{clear}]");
+
+    // ACPI (the kernel can do without, using the legacy defaults)
+    print!("INIT: ACPI.......... ");
+    match acpi::init(boot_info.rsdp_addr.into_option()) {
+        Ok(_) => println!("[{green}OK{clear}]"),
+        Err(e) => println!("[{red}FAILED{clear}] {e}"),
+    }
+
+    // IOAPICs, found through ACPI
+    if !cmdline.skips_init("apic") {
+        print!("INIT: IOAPIC........ ");
+        let count = ioapic::init();
+        println!("[{green}OK{clear}] {count} found");
+    }
+
+    // Timekeeping, calibrated against the HPET (found through ACPI) or the PIT.
+    // Without the APIC, there's no timer to calibrate or tick.
+    if !cmdline.skips_init("apic") {
+        print!("INIT: Clock......... ");
+        let reference = clock::init();
+        let mhz = |hz: Option<u64>| hz.unwrap_or(0) / 1_000_000;
+        println!("[{green}OK{clear}] TSC {} MHz, APIC timer {} MHz ({reference})",
+            mhz(clock::tsc_frequency()), mhz(clock::apic_timer_frequency()));
+    }
+
+    // Threads, preempted by the APIC timer
+    print!("INIT: Threads....... ");
+    match thread::init_cpu("main") {
+        Ok(()) => println!("[{green}OK{clear}]"),
+        Err(e) => println!("[{red}FAILED{clear}] {e}"),
+    }
+
+    // System calls, every AP enables them as it starts
+    print!("INIT: Syscalls...... ");
+    syscall::init();
+    println!("[{green}OK{clear}] {} calls", syscall::Syscall::COUNT);
+
+    // Application processors, found through ACPI
+    if !cmdline.skips_init("apic") && !cmdline.skips_init("smp") {
+        print!("INIT: SMP........... ");
+        match smp::init() {
+            Ok(online) => println!("[{green}OK{clear}] {online} CPUs online"),
+            Err(e) => println!("[{red}FAILED{clear}] {e}, {} of {} CPUs online", smp::online_count(), smp::cpus().len()),
+        }
+    }
     
+    // PS/2 controller, keyboard and mouse
+    if !cmdline.skips_init("ps2") {
+        print!("INIT: PS/2.......... ");
+        match ps2::init() {
+            Ok(()) => println!("[{green}OK{clear}]"),
+            Err(e) => println!("[{red}FAILED{clear}] {e}"),
+        }
+
+        if ps2::is_initialized() {
+            print!("INIT: Keyboard...... ");
+            match ps2::keyboard::init() {
+                Ok(set) => {
+                    let keymap = cmdline.keymap().map(|name| (name, ps2::keyboard::set_keymap(name)));
+                    print!("[{green}OK{clear}] scancode set {set}, keymap `{}`", ps2::keyboard::keymap().name());
+                    match keymap {
+                        Some((name, Err(e))) => println!(" (`{name}`: {e})"),
+                        _ => println!(),
+                    }
+                },
+                Err(e) => println!("[{red}FAILED{clear}] {e}"),
+            }
+        }
+
+        if ps2::has_second_port() {
+            print!("INIT: Mouse......... ");
+            match ps2::mouse::init() {
+                Ok(kind) => println!("[{green}OK{clear}] {kind}"),
+                Err(e) => println!("[{red}FAILED{clear}] {e}"),
+            }
+        }
+    }
+
     // Framebuffer Output
     let fb = boot_info.framebuffer.as_mut().unwrap();
     let fb_info = fb.info();
-    framebuffer::init(fb.buffer_mut(), fb_info);
+    let scale = cmdline.fb_scale().unwrap_or(font::FONT_SCALE);
+    framebuffer::init(fb.buffer_mut(), fb_info, scale);
     println!("INIT: Framebuffer... [{green}OK{clear}]");
 
+    // Initial ramdisk (already loaded above)
+    if initrd::archive().is_some() {
+        println!("INIT: Initrd........ [{green}OK{clear}]");
+    }
+
+    if !cmdline.is_empty() {
+        println!("INIT: Cmdline....... {cmdline}");
+    }
+
     // Tracing
-    print!("INIT: Tracing....... ");
-    tracing::init_tracing();
-    println!("[{green}OK{clear}]");
+    if !cmdline.skips_init("tracing") {
+        print!("INIT: Tracing....... ");
+        tracing::init_tracing();
+        println!("[{green}OK{clear}]");
+    }
 
-    println!("Finished Initialization!\n");
+    println!("Finished Initialization in {} ms!\n", start.elapsed().as_millis());
 }
 
 pub fn hlt_loop() -> ! {
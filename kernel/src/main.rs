@@ -8,23 +8,18 @@ extern crate tracing;
 
 use core::panic::PanicInfo;
 
-use bootloader_api::{entry_point, config::Mapping, BootloaderConfig, BootInfo};
+use bootloader_api::{entry_point, BootInfo};
 
-use kernel::println;
+use kernel::{print, println, ps2::{self, keyboard::KeyEvent, InputEvent}, task::{Executor, Stream}};
 
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
-    println!("{info}");
+    kernel::crash::report(format_args!("{info}"));
 
     kernel::hlt_loop()
 }
 
-pub static BOOTLOADER_CONFIG: BootloaderConfig = {
-    let mut config = BootloaderConfig::new_default();
-    config.mappings.physical_memory = Some(Mapping::FixedAddress(0x0000_f000_0000_0000));
-    config
-};
-entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+entry_point!(kernel_main, config = &kernel::BOOTLOADER_CONFIG);
 
 #[instrument]
 fn test_tracing(a: u64, b: bool) {
@@ -34,6 +29,14 @@ fn test_tracing(a: u64, b: bool) {
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     kernel::init(boot_info);
 
+    let motd = kernel::initrd::archive()
+        .and_then(|initrd| initrd.get("etc/motd"))
+        .and_then(|motd| motd.as_str());
+
+    if let Some(motd) = motd {
+        println!("{motd}");
+    }
+
     debug!("Hello, World!");
     info!("Hello, Again!");
     warn!("Hello, Again!");
@@ -43,5 +46,22 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     test_tracing(42, false);
 
     debug!("It did not crash!");
+
+    let mut executor = Executor::new();
+    executor.spawn(echo_keys());
+    executor.run();
+
     kernel::hlt_loop()
 }
+
+/// Prints what's typed on the keyboard
+async fn echo_keys() {
+    let mut events = ps2::events();
+
+    while let Some(event) = events.next().await {
+        match event {
+            InputEvent::Key(KeyEvent { char: Some(c), .. }) if c == '\n' || !c.is_control() => print!("{c}"),
+            _ => { },
+        }
+    }
+}
@@ -1,20 +1,76 @@
-use bootloader_api::info::{MemoryRegions, MemoryRegionKind};
+use bootloader_api::info::{MemoryRegion, MemoryRegions, MemoryRegionKind};
+use spin::{Mutex, Once};
 use x86_64::structures::paging::{
-    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
 };
 use x86_64::{PhysAddr, VirtAddr};
 
-/// Intialize a new OffsetPageTable
+static MEMORY: Once<Mutex<Memory>> = Once::new();
+/// The level 4 table the bootloader left in CR3, process page tables share its entries
+static KERNEL_PAGE_TABLE: Once<PhysFrame> = Once::new();
+
+/// The kernel's page table and the allocator for the frames it maps
+pub struct Memory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootInfoFrameAllocator,
+}
+
+/// Set up the kernel's page table and frame allocator
 ///
 /// # Safety
 ///
 /// The caller must guarantee that the complete physical memory
-/// is mapped to virtual memory at the passed `physical_offset`.
+/// is mapped to virtual memory at the passed `physical_offset`,
+/// and that all frames marked `USABLE` in `memory_map` are really unused.
 /// Additionally, this function must only be called *once* to
 /// avoid aliasing `&mut` references (which is undefined behavior).
-pub unsafe fn init(physical_offset: VirtAddr) -> OffsetPageTable<'static> {
+pub unsafe fn init(physical_offset: VirtAddr, memory_map: &'static MemoryRegions) {
     let lvl4_table = active_lvl4_table(physical_offset);
-    OffsetPageTable::new(lvl4_table, physical_offset)
+    KERNEL_PAGE_TABLE.call_once(|| x86_64::registers::control::Cr3::read().0);
+
+    MEMORY.call_once(|| Mutex::new(Memory {
+        mapper: OffsetPageTable::new(lvl4_table, physical_offset),
+        frame_allocator: BootInfoFrameAllocator::init(memory_map),
+    }));
+}
+
+/// The kernel's page table and frame allocator, once `init` has run.
+///
+/// Lock it with interrupts disabled: the page fault handler needs it too.
+pub fn get() -> Option<&'static Mutex<Memory>> {
+    MEMORY.get()
+}
+
+/// The kernel's level 4 table, once `init` has run
+pub fn kernel_page_table() -> Option<PhysFrame> {
+    KERNEL_PAGE_TABLE.get().copied()
+}
+
+/// Copies the kernel's level 4 entry for `addr` into the active page table,
+/// if that's a process' and doesn't have one there yet. Processes otherwise
+/// only get new entries as their threads are switched to.
+///
+/// Called from the page fault handler, after mapping `addr` in the kernel's table.
+pub(crate) fn sync_kernel_entry(addr: VirtAddr) {
+    use x86_64::registers::control::Cr3;
+
+    let (Some(&physical_offset), Some(kernel)) = (crate::PHYSICAL_MEM_OFFSET.get(), kernel_page_table()) else {
+        return;
+    };
+    let (active, _flags) = Cr3::read();
+    if active == kernel {
+        return;
+    }
+
+    let table = |frame: PhysFrame| (physical_offset + frame.start_address().as_u64()) as *mut PageTable;
+    let index = addr.p4_index();
+
+    unsafe {
+        let entry = &mut (*table(active))[index];
+        if entry.is_unused() {
+            *entry = (*table(kernel))[index].clone();
+        }
+    }
 }
 
 /// Returns a mutable reference to the active level 4 table.
@@ -37,6 +93,50 @@ unsafe fn active_lvl4_table(physical_offset: VirtAddr) -> &'static mut PageTable
     &mut *page_table_ptr
 }
 
+/// Whether `addr` is mapped in the active page table.
+///
+/// Only reads the page tables, so it is safe to use while the mapper may be
+/// in use, e.g. from an exception handler.
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    page_flags(addr).is_some()
+}
+
+/// The flags that apply to the page containing `addr`, if it is mapped.
+/// `WRITABLE` and `USER_ACCESSIBLE` are only set if every level of the page
+/// table allows it, like the CPU checks them.
+pub fn page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    use x86_64::registers::control::Cr3;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let &physical_offset = crate::PHYSICAL_MEM_OFFSET.get()?;
+
+    let (lvl4_table_frame, _flags) = Cr3::read();
+    let mut table_addr = lvl4_table_frame.start_address();
+    let mut allowed = Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+    let mut flags = Flags::empty();
+
+    let indices = [ addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index() ];
+    for (level, index) in indices.into_iter().enumerate() {
+        let table_ptr = (physical_offset + table_addr.as_u64()) as *const PageTable;
+        let entry = unsafe { &(*table_ptr)[index] };
+
+        if !entry.flags().contains(Flags::PRESENT) {
+            return None;
+        }
+        flags = entry.flags();
+        allowed &= flags;
+
+        // 1GiB and 2MiB pages end the walk early
+        if level > 0 && flags.contains(Flags::HUGE_PAGE) {
+            break;
+        }
+
+        table_addr = entry.addr();
+    }
+
+    Some((flags - (Flags::WRITABLE | Flags::USER_ACCESSIBLE)) | allowed)
+}
+
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
@@ -59,11 +159,16 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/// Frames below this are kept for code that has to run in real mode,
+/// see [`BootInfoFrameAllocator::allocate_low_frame`]
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
 /// A FrameAllocator that returns usable
 /// frames from the bootloader's memory map.
 pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
+    memory_map: &'static [MemoryRegion],
     next: usize,
+    next_low: usize,
 }
 
 impl BootInfoFrameAllocator {
@@ -78,6 +183,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            next_low: 0,
         }
     }
 
@@ -95,11 +201,23 @@ impl BootInfoFrameAllocator {
             // Create `PhysFrame` types from the start addrs
             .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Allocates a frame below 1 MiB, e.g. for the AP trampoline,
+    /// which starts out in real mode
+    pub fn allocate_low_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.usable_frames()
+            .filter(|frame| (1..LOW_MEMORY_END).contains(&frame.start_address().as_u64()))
+            .nth(self.next_low);
+        self.next_low += 1;
+        frame
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
+        let frame = self.usable_frames()
+            .filter(|frame| frame.start_address().as_u64() >= LOW_MEMORY_END)
+            .nth(self.next);
         self.next += 1;
         frame
     }
@@ -0,0 +1,246 @@
+//! Demand paging of reserved kernel regions. Nothing here allocates.
+
+use core::fmt;
+
+use spin::{Mutex, MutexGuard};
+use x86_64::{
+    instructions::interrupts,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB},
+    },
+    VirtAddr,
+};
+
+use crate::{memory, PHYSICAL_MEM_OFFSET};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Maximum number of regions that can be reserved
+const MAX_REGIONS: usize = 32;
+
+static REGIONS: Mutex<[Option<Region>; MAX_REGIONS]> = Mutex::new([None; MAX_REGIONS]);
+
+/// A range of virtual memory whose pages are mapped on first access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub name: &'static str,
+    pub start: VirtAddr,
+    /// In bytes, a multiple of the page size
+    pub size: u64,
+    /// Flags of the pages once they're mapped (`PRESENT` is implied)
+    pub flags: PageTableFlags,
+}
+
+impl Region {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr.as_u64().wrapping_sub(self.start.as_u64()) < self.size
+    }
+
+    /// The last byte of the region, as the end may not be a valid address
+    fn last(&self) -> u64 {
+        self.start.as_u64() + (self.size - 1)
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.start.as_u64() <= other.last() && other.start.as_u64() <= self.last()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReserveError {
+    /// The start isn't page aligned, or the size is zero
+    InvalidRange,
+    /// Part of the range is already reserved by the given region
+    Overlaps(&'static str),
+    /// `MAX_REGIONS` are already reserved
+    TooManyRegions,
+}
+
+/// Why a page fault couldn't be resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultError {
+    NotInRegion,
+    /// The page is mapped, but the access isn't allowed
+    ProtectionViolation(&'static str),
+    /// The region is read-only or kernel-only
+    AccessDenied(&'static str),
+    /// The page table and frame allocator aren't set up yet,
+    /// or the fault happened while they were being changed
+    MemoryUnavailable,
+    OutOfMemory(&'static str),
+    /// The page table couldn't be changed, e.g. a huge page is in the way
+    MapFailed(&'static str),
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FaultError::NotInRegion => write!(f, "not in any reserved region"),
+            FaultError::ProtectionViolation(name) => write!(f, "protection violation in region `{name}`"),
+            FaultError::AccessDenied(name) => write!(f, "access not allowed by region `{name}`"),
+            FaultError::MemoryUnavailable => write!(f, "page table unavailable"),
+            FaultError::OutOfMemory(name) => write!(f, "out of memory backing region `{name}`"),
+            FaultError::MapFailed(name) => write!(f, "failed to map page in region `{name}`"),
+        }
+    }
+}
+
+/// Reserves `size` bytes (rounded up to whole pages) at `start`,
+/// to be mapped with `flags` as they're accessed
+pub fn reserve(name: &'static str, start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<Region, ReserveError> {
+    if size == 0 || !start.is_aligned(PAGE_SIZE) {
+        return Err(ReserveError::InvalidRange);
+    }
+
+    let size = size.checked_add(PAGE_SIZE - 1).ok_or(ReserveError::InvalidRange)? / PAGE_SIZE * PAGE_SIZE;
+    start.as_u64().checked_add(size - 1)
+        .and_then(|last| VirtAddr::try_new(last).ok())
+        .ok_or(ReserveError::InvalidRange)?;
+
+    let region = Region { name, start, size, flags: flags | PageTableFlags::PRESENT };
+
+    interrupts::without_interrupts(|| {
+        let mut regions = REGIONS.lock();
+
+        if let Some(other) = regions.iter().flatten().find(|other| other.overlaps(&region)) {
+            return Err(ReserveError::Overlaps(other.name));
+        }
+
+        let slot = regions.iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(ReserveError::TooManyRegions)?;
+        *slot = Some(region);
+
+        Ok(region)
+    })
+}
+
+/// Removes the region reserved at `start`, and unmaps its pages. Their frames
+/// aren't freed, as the frame allocator can't take them back, and only this
+/// CPU's TLB is flushed.
+pub fn release(start: VirtAddr) -> Option<Region> {
+    // Removed first, so its pages aren't mapped again meanwhile
+    let region = interrupts::without_interrupts(|| {
+        REGIONS.lock().iter_mut()
+            .find(|slot| slot.map_or(false, |region| region.start == start))?
+            .take()
+    })?;
+
+    if let Some(memory) = memory::get() {
+        let first = Page::<Size4KiB>::containing_address(region.start);
+        let last = Page::containing_address(VirtAddr::new(region.last()));
+
+        for page in Page::range_inclusive(first, last) {
+            if let Ok((_, flush)) = interrupts::without_interrupts(|| memory.lock().mapper.unmap(page)) {
+                flush.flush();
+            }
+        }
+    }
+
+    Some(region)
+}
+
+/// The reserved region containing `addr`
+pub fn region(addr: VirtAddr) -> Option<Region> {
+    interrupts::without_interrupts(|| {
+        REGIONS.lock().iter().flatten().find(|region| region.contains(addr)).copied()
+    })
+}
+
+/// Backs the page at `addr` with a zeroed frame, if it is in a reserved region.
+///
+/// Called by the page fault handler, with interrupts disabled.
+pub(crate) fn handle_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> Result<(), FaultError> {
+    // Faults while a region is being reserved can't be resolved
+    let region = lock_from_fault(&REGIONS)
+        .ok_or(FaultError::MemoryUnavailable)?
+        .iter()
+        .flatten()
+        .find(|region| region.contains(addr))
+        .copied()
+        .ok_or(FaultError::NotInRegion)?;
+
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return Err(FaultError::ProtectionViolation(region.name));
+    }
+
+    let denied = (error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !region.flags.contains(PageTableFlags::WRITABLE))
+        || (error_code.contains(PageFaultErrorCode::USER_MODE) && !region.flags.contains(PageTableFlags::USER_ACCESSIBLE))
+        || (error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) && region.flags.contains(PageTableFlags::NO_EXECUTE));
+    if denied {
+        return Err(FaultError::AccessDenied(region.name));
+    }
+
+    let mut memory = memory::get()
+        .and_then(lock_from_fault)
+        .ok_or(FaultError::MemoryUnavailable)?;
+
+    // Another CPU may have faulted on the same page first, or it's mapped
+    // already but the fault happened in a process' page table
+    let page = Page::containing_address(addr);
+    if memory.mapper.translate_page(page).is_err() {
+        back_page(&mut memory, &region, page)?;
+    }
+
+    memory::sync_kernel_entry(addr);
+    Ok(())
+}
+
+/// Locks `mutex` from the page fault handler. If this CPU faulted while holding
+/// the lock, it never gets released, so this gives up after a while.
+fn lock_from_fault<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    // Other CPUs only hold these locks for a few page table updates
+    const ATTEMPTS: usize = 1_000_000;
+
+    (0..ATTEMPTS).find_map(|_| {
+        let guard = mutex.try_lock();
+        if guard.is_none() {
+            core::hint::spin_loop();
+        }
+        guard
+    })
+}
+
+/// Backs every page of `region` right away, for memory that can't take
+/// page faults (e.g. stacks: the fault would be pushed onto the missing page)
+pub fn populate(region: &Region) -> Result<(), FaultError> {
+    let memory = memory::get().ok_or(FaultError::MemoryUnavailable)?;
+
+    let first = Page::<Size4KiB>::containing_address(region.start);
+    let last = Page::containing_address(VirtAddr::new(region.last()));
+
+    for page in Page::range_inclusive(first, last) {
+        if memory::is_mapped(page.start_address()) {
+            continue;
+        }
+
+        interrupts::without_interrupts(|| back_page(&mut memory.lock(), region, page))?;
+    }
+
+    Ok(())
+}
+
+/// Maps `page` of `region` to a zeroed frame
+fn back_page(memory: &mut memory::Memory, region: &Region, page: Page<Size4KiB>) -> Result<(), FaultError> {
+    let physical_offset = *PHYSICAL_MEM_OFFSET.get().ok_or(FaultError::MemoryUnavailable)?;
+    let memory::Memory { mapper, frame_allocator } = memory;
+
+    let frame = frame_allocator.allocate_frame()
+        .ok_or(FaultError::OutOfMemory(region.name))?;
+
+    // Don't leak whatever the frame was used for before
+    unsafe {
+        let frame_ptr = (physical_offset + frame.start_address().as_u64()) as *mut u8;
+        frame_ptr.write_bytes(0, PAGE_SIZE as usize);
+    }
+
+    unsafe { mapper.map_to(page, frame, region.flags, frame_allocator) }
+        .map_err(|e| match e {
+            MapToError::FrameAllocationFailed => FaultError::OutOfMemory(region.name),
+            _ => FaultError::MapFailed(region.name),
+        })?
+        .flush();
+
+    Ok(())
+}
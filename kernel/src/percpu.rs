@@ -0,0 +1,213 @@
+//! Per-CPU data, found through `GS_BASE`
+
+use core::{
+    mem::{size_of, MaybeUninit},
+    ptr::addr_of,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use x86_64::{
+    instructions::interrupts,
+    registers::{model_specific::{GsBase, KernelGsBase}, segmentation::GS},
+    VirtAddr,
+};
+
+/// Most CPUs the kernel uses, any more are left offline
+pub const MAX_CPUS: usize = 64;
+
+static LOCALS: [CpuLocal; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: CpuLocal = CpuLocal {
+        cpu: 0,
+        interrupt_depth: AtomicUsize::new(0),
+        kernel_stack: AtomicU64::new(0),
+        user_stack: AtomicU64::new(0),
+    };
+
+    let mut locals = [EMPTY; MAX_CPUS];
+    let mut cpu = 0;
+    while cpu < MAX_CPUS {
+        locals[cpu].cpu = cpu;
+        cpu += 1;
+    }
+    locals
+};
+
+/// What `GS_BASE` points to
+#[repr(C)]
+struct CpuLocal {
+    /// Must stay first, see [`cpu`]
+    cpu: usize,
+    /// How many interrupt (or exception) handlers are running
+    interrupt_depth: AtomicUsize,
+    /// Top of the running thread's stack, 0 if it has none.
+    /// At [`KERNEL_STACK_OFFSET`], for the system call entry.
+    kernel_stack: AtomicU64,
+    /// Where the system call entry saves the user stack pointer,
+    /// at [`USER_STACK_OFFSET`]
+    user_stack: AtomicU64,
+}
+
+/// The offset of `field` in [`CpuLocal`], as `core::mem::offset_of!` isn't stable yet
+macro_rules! offset_of {
+    ($field:ident) => {{
+        // Atomics can't be borrowed in constants, so this only has their layout
+        let local = MaybeUninit::<[u64; size_of::<CpuLocal>() / 8]>::uninit();
+        let base = local.as_ptr().cast::<CpuLocal>();
+        unsafe { (addr_of!((*base).$field) as *const u8).offset_from(base as *const u8) as usize }
+    }};
+}
+
+/// Offsets of [`CpuLocal`] fields used from assembly
+pub(crate) const KERNEL_STACK_OFFSET: usize = offset_of!(kernel_stack);
+pub(crate) const USER_STACK_OFFSET: usize = offset_of!(user_stack);
+
+/// Points this CPU's `GS_BASE` at the data of CPU `cpu`.
+///
+/// # Safety
+///
+/// Must be called once per CPU, with a different `cpu` each time,
+/// before anything else on that CPU uses per-CPU data.
+pub(crate) unsafe fn init(cpu: usize) {
+    assert!(cpu < MAX_CPUS, "CPU {cpu} is over the maximum of {MAX_CPUS}");
+
+    GsBase::write(VirtAddr::from_ptr(&LOCALS[cpu]));
+    // User mode's, swapped in on the way there
+    KernelGsBase::write(VirtAddr::zero());
+}
+
+/// Swaps in the kernel's GS base if it isn't there, and back as the guard is
+/// dropped, right before returning to the interrupted code. Interrupt and
+/// exception handlers must call it before anything uses per-CPU data.
+///
+/// This goes by the GS base itself rather than the interrupted privilege level:
+/// an NMI or machine check can come in ring 0 with the user's GS base, on the
+/// few instructions between entering the kernel and `swapgs` (or the other
+/// way around on the way out).
+pub(crate) fn swap_gs() -> SwapGsGuard {
+    let locals = LOCALS.as_ptr_range();
+    let base = GsBase::read().as_u64();
+    let swapped = !(locals.start as u64..locals.end as u64).contains(&base);
+    if swapped {
+        unsafe { GS::swap() };
+    }
+
+    SwapGsGuard { swapped }
+}
+
+pub(crate) struct SwapGsGuard {
+    swapped: bool,
+}
+
+impl Drop for SwapGsGuard {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+/// Index of the CPU this runs on, 0 for the boot CPU (see [`smp::cpus`](crate::smp::cpus)).
+///
+/// Without interrupts disabled, the caller may be moved to another CPU right after.
+pub fn cpu() -> usize {
+    let cpu: usize;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, preserves_flags, readonly));
+    }
+    cpu
+}
+
+fn local() -> &'static CpuLocal {
+    &LOCALS[cpu()]
+}
+
+/// Sets the stack system calls on this CPU switch to. Called with interrupts
+/// disabled, as threads are switched.
+pub(crate) fn set_kernel_stack(top: Option<VirtAddr>) {
+    local().kernel_stack.store(top.map_or(0, VirtAddr::as_u64), Ordering::Relaxed);
+}
+
+/// How many interrupt handlers are running on this CPU, nested in each other
+pub fn interrupt_depth() -> usize {
+    interrupts::without_interrupts(|| local().interrupt_depth.load(Ordering::Relaxed))
+}
+
+pub fn in_interrupt() -> bool {
+    interrupt_depth() > 0
+}
+
+/// Counts an interrupt handler as running until the guard is dropped
+pub(crate) fn enter_interrupt() -> InterruptGuard {
+    local().interrupt_depth.fetch_add(1, Ordering::Relaxed);
+    InterruptGuard { _private: () }
+}
+
+/// For handlers that never return, e.g. because they killed the interrupted thread
+pub(crate) fn reset_interrupt_depth() {
+    local().interrupt_depth.store(0, Ordering::Relaxed);
+}
+
+pub(crate) struct InterruptGuard {
+    _private: (),
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        // Interrupt handlers never move to another CPU
+        local().interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A variable with a value for each CPU, declared with [`percpu!`](crate::percpu!)
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS],
+}
+
+// Each value is only accessed from its own CPU
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    #[doc(hidden)]
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        PerCpu { values }
+    }
+
+    /// Runs `f` with this CPU's value, with interrupts disabled
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        interrupts::without_interrupts(|| f(&self.values[cpu()]))
+    }
+
+    /// This CPU's value.
+    ///
+    /// # Safety
+    ///
+    /// Interrupts must stay disabled while the value is used, nothing else
+    /// may stop the caller from being moved to another CPU.
+    pub unsafe fn get(&self) -> &T {
+        &self.values[cpu()]
+    }
+}
+
+/// Declares statics with one value per CPU, each starting out as `init`:
+///
+/// ```ignore
+/// percpu! {
+///     static TICKS: Cell<u64> = Cell::new(0);
+/// }
+///
+/// TICKS.with(|ticks| ticks.set(ticks.get() + 1));
+/// ```
+#[macro_export]
+macro_rules! percpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> = {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const INIT: $ty = $init;
+                $crate::percpu::PerCpu::new([INIT; $crate::percpu::MAX_CPUS])
+            };
+        )*
+    };
+}
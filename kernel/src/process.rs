@@ -0,0 +1,369 @@
+//! User processes, running in ring 3
+
+use alloc::sync::Arc;
+use core::{
+    arch::asm,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::{exceptions::Exception, gdt, memory, println, thread::{self, JoinHandle, SpawnError}, PHYSICAL_MEM_OFFSET};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Where user space starts, and the program is loaded
+pub const USER_START: u64 = 0x2000_0000_0000;
+/// Where user space ends, and the stack starts (growing down)
+pub const USER_END: u64 = 0x4000_0000_0000;
+
+const MAX_IMAGE_SIZE: u64 = 16 * 1024 * 1024;
+const STACK_SIZE: u64 = 64 * 1024;
+
+/// `RFLAGS` in user mode, with interrupts enabled
+const USER_RFLAGS: u64 = 0x202;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ProcessId(u64);
+
+impl fmt::Display for ProcessId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How a process ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// With the `exit` system call
+    Exited(i32),
+    Killed(Exception),
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Exited(code) => write!(f, "exited with code {code}"),
+            ExitStatus::Killed(exception) => write!(f, "killed by {exception}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// The kernel has memory mapped in user space, so it can't be given to processes
+    UserSpaceInUse,
+    /// The program is empty, or over the maximum size
+    InvalidImage,
+    OutOfMemory,
+    Spawn(SpawnError),
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::UserSpaceInUse => write!(f, "the kernel has memory mapped in user space"),
+            ProcessError::InvalidImage => write!(f, "the program is empty or too large"),
+            ProcessError::OutOfMemory => write!(f, "out of memory"),
+            ProcessError::Spawn(e) => write!(f, "failed to spawn its thread: {e}"),
+        }
+    }
+}
+
+/// A page table for a process
+pub struct AddressSpace {
+    level_4_table: PhysFrame,
+}
+
+impl AddressSpace {
+    /// A page table with the kernel's memory mapped, and nothing in user space
+    pub fn new() -> Result<Self, ProcessError> {
+        let memory = memory::get().ok_or(ProcessError::OutOfMemory)?;
+        let kernel = memory::kernel_page_table().ok_or(ProcessError::OutOfMemory)?;
+
+        let level_4_table = interrupts::without_interrupts(|| memory.lock().frame_allocator.allocate_frame())
+            .ok_or(ProcessError::OutOfMemory)?;
+
+        let kernel = unsafe { &*table_ptr(kernel) };
+        let table = unsafe { &mut *table_ptr(level_4_table) };
+
+        let user_entries = p4_index(USER_START)..p4_index(USER_END);
+        for (index, entry) in table.iter_mut().enumerate() {
+            if user_entries.contains(&index) {
+                if !kernel[index].is_unused() {
+                    return Err(ProcessError::UserSpaceInUse);
+                }
+                entry.set_unused();
+            } else {
+                *entry = kernel[index].clone();
+            }
+        }
+
+        Ok(AddressSpace { level_4_table })
+    }
+
+    /// The level 4 table, for CR3
+    pub fn page_table(&self) -> PhysFrame {
+        self.level_4_table
+    }
+
+    /// Copies the kernel's level 4 entries added since the table was made,
+    /// so kernel memory mapped under them (e.g. new thread stacks) is there too
+    pub(crate) fn sync_kernel_entries(&self) {
+        let Some(kernel) = memory::kernel_page_table() else {
+            return;
+        };
+
+        let kernel = unsafe { &*table_ptr(kernel) };
+        let table = unsafe { &mut *table_ptr(self.level_4_table) };
+
+        let user_entries = p4_index(USER_START)..p4_index(USER_END);
+        for (index, entry) in table.iter_mut().enumerate() {
+            if !user_entries.contains(&index) && entry.is_unused() && !kernel[index].is_unused() {
+                *entry = kernel[index].clone();
+            }
+        }
+    }
+
+    /// Runs `f` with this page table active on this CPU. Interrupts are
+    /// disabled meanwhile, so no thread switch puts another one in.
+    pub fn with_active<R>(&self, f: impl FnOnce() -> R) -> R {
+        interrupts::without_interrupts(|| {
+            self.sync_kernel_entries();
+            let (previous, flags) = Cr3::read();
+            unsafe { Cr3::write(self.level_4_table, flags) };
+            let result = f();
+            unsafe { Cr3::write(previous, flags) };
+            result
+        })
+    }
+
+    /// Maps `size` bytes (rounded up to whole pages) at `start` (page aligned,
+    /// in user space) to new frames, starting with `contents` and zeroed after
+    pub fn map(&mut self, start: VirtAddr, size: u64, flags: PageTableFlags, contents: &[u8]) -> Result<(), ProcessError> {
+        let end = start.as_u64().checked_add(size).ok_or(ProcessError::InvalidImage)?;
+        let in_user_space = start.as_u64() >= USER_START && end <= USER_END;
+        if !in_user_space || !start.is_aligned(PAGE_SIZE) || (contents.len() as u64) > size {
+            return Err(ProcessError::InvalidImage);
+        }
+
+        let physical_offset = *PHYSICAL_MEM_OFFSET.get().ok_or(ProcessError::OutOfMemory)?;
+        let memory = memory::get().ok_or(ProcessError::OutOfMemory)?;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        let first = Page::<Size4KiB>::containing_address(start);
+        let last = Page::containing_address(VirtAddr::new(end - 1));
+
+        for page in Page::range_inclusive(first, last) {
+            interrupts::without_interrupts(|| {
+                let mut memory = memory.lock();
+                let frame = memory.frame_allocator.allocate_frame().ok_or(ProcessError::OutOfMemory)?;
+
+                let offset = (page.start_address() - start) as usize;
+                let chunk = contents.get(offset..).unwrap_or(&[]);
+                let chunk = &chunk[..chunk.len().min(PAGE_SIZE as usize)];
+
+                unsafe {
+                    let frame_ptr = (physical_offset + frame.start_address().as_u64()) as *mut u8;
+                    frame_ptr.write_bytes(0, PAGE_SIZE as usize);
+                    frame_ptr.copy_from_nonoverlapping(chunk.as_ptr(), chunk.len());
+                }
+
+                let mut mapper = unsafe { OffsetPageTable::new(&mut *table_ptr(self.level_4_table), VirtAddr::new(physical_offset)) };
+                unsafe { mapper.map_to(page, frame, flags, &mut memory.frame_allocator) }
+                    .map_err(|e| match e {
+                        MapToError::FrameAllocationFailed => ProcessError::OutOfMemory,
+                        _ => ProcessError::InvalidImage,
+                    })?
+                    // Not the active page table
+                    .ignore();
+
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    let physical_offset = *PHYSICAL_MEM_OFFSET.get().unwrap();
+    (physical_offset + frame.start_address().as_u64()) as *mut PageTable
+}
+
+fn p4_index(addr: u64) -> usize {
+    (addr >> 39) as usize & 0x1FF
+}
+
+/// A process, shared by its thread and [`ProcessHandle`]
+pub struct Process {
+    id: ProcessId,
+    name: &'static str,
+    address_space: AddressSpace,
+    status: Mutex<Option<ExitStatus>>,
+}
+
+impl Process {
+    pub fn id(&self) -> ProcessId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub(crate) fn page_table(&self) -> PhysFrame {
+        self.address_space.page_table()
+    }
+
+    pub(crate) fn sync_kernel_entries(&self) {
+        self.address_space.sync_kernel_entries();
+    }
+
+    fn set_status(&self, status: ExitStatus) {
+        interrupts::without_interrupts(|| *self.status.lock() = Some(status));
+    }
+}
+
+pub struct ProcessHandle {
+    process: Arc<Process>,
+    thread: JoinHandle<()>,
+}
+
+impl ProcessHandle {
+    pub fn id(&self) -> ProcessId {
+        self.process.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.process.name
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Waits for the process to end
+    pub fn wait(self) -> ExitStatus {
+        self.thread.join();
+
+        interrupts::without_interrupts(|| *self.process.status.lock())
+            .expect("the process ended without a status")
+    }
+}
+
+/// Loads `image` (a flat binary) into a new process, and starts running it
+/// in a new thread on this CPU
+///
+/// Processes don't get their own FPU/SSE state yet, so programs shouldn't use it.
+pub fn spawn(name: &'static str, image: &[u8]) -> Result<ProcessHandle, ProcessError> {
+    if image.is_empty() || image.len() as u64 > MAX_IMAGE_SIZE {
+        return Err(ProcessError::InvalidImage);
+    }
+
+    let mut address_space = AddressSpace::new()?;
+    address_space.map(VirtAddr::new(USER_START), image.len() as u64, PageTableFlags::empty(), image)?;
+    address_space.map(VirtAddr::new(USER_END - STACK_SIZE), STACK_SIZE, PageTableFlags::WRITABLE, &[])?;
+
+    let id = ProcessId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+    let process = Arc::new(Process { id, name, address_space, status: Mutex::new(None) });
+
+    let thread = thread::spawn_in(process.clone(), || unsafe { enter_user(USER_START, USER_END) })
+        .map_err(ProcessError::Spawn)?;
+
+    Ok(ProcessHandle { process, thread })
+}
+
+/// The current thread's process ID and name, if it has one
+pub fn current() -> Option<(ProcessId, &'static str)> {
+    thread::current_process().map(|process| (process.id, process.name))
+}
+
+/// Ends the current thread's process with `code`, or just the thread if it has none
+pub fn exit(code: i32) -> ! {
+    if let Some(process) = thread::current_process() {
+        process.set_status(ExitStatus::Exited(code));
+    }
+
+    thread::exit()
+}
+
+/// Ends the current thread's process as if by an `exception`, from a system
+/// call that can't return to it. Ends just the thread if it has no process.
+pub(crate) fn kill_from_syscall(exception: Exception) -> ! {
+    if let Some(process) = thread::current_process() {
+        println!("Killing process {} `{}`", process.id, process.name);
+        process.set_status(ExitStatus::Killed(exception));
+    }
+
+    thread::exit()
+}
+
+/// Ends the current thread's process from the handler of an `exception`
+/// in user mode. Returns if the thread has no process.
+pub(crate) fn kill_current(exception: Exception) {
+    let Some(process) = thread::current_process() else {
+        return;
+    };
+
+    println!("Killing process {} `{}`", process.id, process.name);
+    process.set_status(ExitStatus::Killed(exception));
+    drop(process);
+
+    thread::kill_current();
+}
+
+/// Drops to user mode at `rip`, with stack `rsp`, and every other register cleared.
+///
+/// # Safety
+///
+/// The current thread must belong to a process with `rip` and `rsp` mapped.
+unsafe fn enter_user(rip: u64, rsp: u64) -> ! {
+    let (_, selectors) = &*gdt::GDT;
+
+    // Interrupts stay disabled until `iretq`, which loads the user's flags,
+    // as handlers would take the user's GS base for the kernel's
+    asm!(
+        "cli",
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "swapgs",
+        "xor eax, eax",
+        "xor ebx, ebx",
+        "xor ecx, ecx",
+        "xor edx, edx",
+        "xor esi, esi",
+        "xor edi, edi",
+        "xor ebp, ebp",
+        "xor r8d, r8d",
+        "xor r9d, r9d",
+        "xor r10d, r10d",
+        "xor r11d, r11d",
+        "xor r12d, r12d",
+        "xor r13d, r13d",
+        "xor r14d, r14d",
+        "xor r15d, r15d",
+        "iretq",
+        ss = in(reg) u64::from(selectors.user_ds().0),
+        rsp = in(reg) rsp,
+        rflags = in(reg) USER_RFLAGS,
+        cs = in(reg) u64::from(selectors.user_cs().0),
+        rip = in(reg) rip,
+        options(noreturn),
+    )
+}
@@ -0,0 +1,348 @@
+//! The PS/2 keyboard, on the controller's first port
+
+use core::time::Duration;
+
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{
+    keymap::{Keymap, KeymapError},
+    scancode::{Decoder, KeyCode, KeyState, ScancodeSet},
+    Channel, InputEvent, Ps2Error, EVENTS,
+};
+use crate::{interrupts, ps2, Locked};
+
+const IRQ: u8 = 1;
+
+const COMMAND_SET_LEDS: u8 = 0xED;
+const COMMAND_RESET: u8 = 0xFF;
+const SELF_TEST_PASSED: u8 = 0xAA;
+
+/// How long the keyboard may take to test itself after a reset
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `None` until initialized
+static KEYBOARD: Locked<Option<Keyboard>> = Locked::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// Pressed again by the keyboard's auto-repeat
+    pub repeat: bool,
+    /// Including this key
+    pub modifiers: Modifiers,
+    /// Including this key
+    pub locks: Locks,
+    /// What the key types, only set for presses
+    pub char: Option<char>,
+}
+
+/// Which modifier keys are held
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift_left: bool,
+    pub shift_right: bool,
+    pub control_left: bool,
+    pub control_right: bool,
+    pub alt_left: bool,
+    /// AltGr
+    pub alt_right: bool,
+    pub meta_left: bool,
+    pub meta_right: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.shift_left || self.shift_right
+    }
+
+    pub fn control(&self) -> bool {
+        self.control_left || self.control_right
+    }
+
+    pub fn alt(&self) -> bool {
+        self.alt_left
+    }
+
+    pub fn alt_gr(&self) -> bool {
+        self.alt_right
+    }
+
+    pub fn meta(&self) -> bool {
+        self.meta_left || self.meta_right
+    }
+
+    fn update(&mut self, code: KeyCode, pressed: bool) {
+        let modifier = match code {
+            KeyCode::ShiftLeft => &mut self.shift_left,
+            KeyCode::ShiftRight => &mut self.shift_right,
+            KeyCode::ControlLeft => &mut self.control_left,
+            KeyCode::ControlRight => &mut self.control_right,
+            KeyCode::AltLeft => &mut self.alt_left,
+            KeyCode::AltRight => &mut self.alt_right,
+            KeyCode::MetaLeft => &mut self.meta_left,
+            KeyCode::MetaRight => &mut self.meta_right,
+            _ => return,
+        };
+
+        *modifier = pressed;
+    }
+}
+
+/// Which locks are on
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Locks {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Locks {
+    /// The byte for the set LEDs command
+    fn leds(&self) -> u8 {
+        self.scroll_lock as u8 | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+
+    /// Whether `code` was a lock key
+    fn toggle(&mut self, code: KeyCode) -> bool {
+        let lock = match code {
+            KeyCode::CapsLock => &mut self.caps_lock,
+            KeyCode::NumLock => &mut self.num_lock,
+            KeyCode::ScrollLock => &mut self.scroll_lock,
+            _ => return false,
+        };
+
+        *lock = !*lock;
+        true
+    }
+}
+
+/// Setting the LEDs takes two bytes, each acknowledged in the IRQ handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedUpdate {
+    Idle,
+    SentCommand,
+    SentValue(u8),
+}
+
+/// The keyboard's state, turning its bytes into events
+pub struct Keyboard {
+    decoder: Decoder,
+    keymap: &'static Keymap,
+    modifiers: Modifiers,
+    locks: Locks,
+    /// A bit for each held key, by `KeyCode`
+    held: u128,
+    leds: LedUpdate,
+    /// The locks changed while the LEDs were being set
+    leds_outdated: bool,
+}
+
+impl Keyboard {
+    pub fn new(set: ScancodeSet, keymap: &'static Keymap) -> Self {
+        Keyboard {
+            decoder: Decoder::new(set),
+            keymap,
+            modifiers: Modifiers::default(),
+            locks: Locks::default(),
+            held: 0,
+            leds: LedUpdate::Idle,
+            leds_outdated: false,
+        }
+    }
+
+    /// Takes a byte from the keyboard, returning the event it completes.
+    /// Bytes for the keyboard (to set its LEDs) are sent with `write`.
+    pub fn process(&mut self, byte: u8, mut write: impl FnMut(u8) -> Result<(), Ps2Error>) -> Option<KeyEvent> {
+        // Responses to setting the LEDs. Otherwise, they aren't keys in either set.
+        match (byte, self.leds) {
+            (ps2::ACK, LedUpdate::SentCommand) => {
+                let value = self.locks.leds();
+                self.send_led_byte(&mut write, value, LedUpdate::SentValue(value));
+                return None;
+            },
+            (ps2::ACK, LedUpdate::SentValue(_)) => {
+                self.leds = LedUpdate::Idle;
+                if self.leds_outdated {
+                    self.update_leds(&mut write);
+                }
+                return None;
+            },
+            (ps2::RESEND, LedUpdate::SentCommand) => {
+                self.send_led_byte(&mut write, COMMAND_SET_LEDS, LedUpdate::SentCommand);
+                return None;
+            },
+            (ps2::RESEND, LedUpdate::SentValue(value)) => {
+                self.send_led_byte(&mut write, value, LedUpdate::SentValue(value));
+                return None;
+            },
+            _ => { },
+        }
+
+        let (code, state) = self.decoder.advance(byte)?;
+        let pressed = state == KeyState::Pressed;
+
+        let bit = 1 << code as u8;
+        let repeat = pressed && self.held & bit != 0;
+        if pressed {
+            self.held |= bit;
+        } else {
+            self.held &= !bit;
+        }
+
+        self.modifiers.update(code, pressed);
+        if pressed && !repeat && self.locks.toggle(code) {
+            self.update_leds(&mut write);
+        }
+
+        Some(KeyEvent {
+            code,
+            state,
+            repeat,
+            modifiers: self.modifiers,
+            locks: self.locks,
+            char: if pressed { self.translate(code) } else { None },
+        })
+    }
+
+    /// What `code` types with the current modifiers and locks
+    fn translate(&self, code: KeyCode) -> Option<char> {
+        use KeyCode::*;
+
+        // The same on every layout
+        let fixed = match code {
+            Enter | NumpadEnter => Some('\n'),
+            Tab => Some('\t'),
+            Backspace => Some('\x08'),
+            Escape => Some('\x1b'),
+            Space => Some(' '),
+            Delete => Some('\x7f'),
+            NumpadDivide => Some('/'),
+            NumpadMultiply => Some('*'),
+            NumpadSubtract => Some('-'),
+            NumpadAdd => Some('+'),
+            // Arrows and such, without num lock
+            Numpad0 | Numpad1 | Numpad2 | Numpad3 | Numpad4 | Numpad5 | Numpad6 | Numpad7 | Numpad8 | Numpad9
+                | NumpadDecimal if !self.locks.num_lock => return None,
+            Numpad0 => Some('0'),
+            Numpad1 => Some('1'),
+            Numpad2 => Some('2'),
+            Numpad3 => Some('3'),
+            Numpad4 => Some('4'),
+            Numpad5 => Some('5'),
+            Numpad6 => Some('6'),
+            Numpad7 => Some('7'),
+            Numpad8 => Some('8'),
+            Numpad9 => Some('9'),
+            NumpadDecimal => Some('.'),
+            _ => None,
+        };
+        if fixed.is_some() {
+            return fixed;
+        }
+
+        // Caps lock only shifts keys whose shifted character is their uppercase
+        let caps = self.locks.caps_lock && self.keymap.get(code, false, false)
+            .zip(self.keymap.get(code, true, false))
+            .map_or(false, |(lower, upper)| lower.to_uppercase().eq([upper]));
+
+        let c = self.keymap.get(code, self.modifiers.shift() != caps, self.modifiers.alt_gr())?;
+
+        // Control characters, as terminals have them
+        if self.modifiers.control() && c.is_ascii_alphabetic() {
+            return Some(char::from(c.to_ascii_uppercase() as u8 - b'@'));
+        }
+
+        Some(c)
+    }
+
+    fn update_leds(&mut self, write: &mut impl FnMut(u8) -> Result<(), Ps2Error>) {
+        if self.leds != LedUpdate::Idle {
+            self.leds_outdated = true;
+            return;
+        }
+
+        self.leds_outdated = false;
+        self.send_led_byte(write, COMMAND_SET_LEDS, LedUpdate::SentCommand);
+    }
+
+    fn send_led_byte(&mut self, write: &mut impl FnMut(u8) -> Result<(), Ps2Error>, byte: u8, next: LedUpdate) {
+        // The LEDs are only cosmetic, so they're given up on
+        self.leds = match write(byte) {
+            Ok(()) => next,
+            Err(_) => LedUpdate::Idle,
+        };
+    }
+}
+
+/// Resets the keyboard, then starts handling its IRQ.
+/// Returns the scancode set it's decoded with.
+pub(crate) fn init() -> Result<ScancodeSet, Ps2Error> {
+    if !ps2::is_initialized() {
+        return Err(Ps2Error::NoController);
+    }
+
+    ps2::with_controller(|| {
+        ps2::send(Channel::First, COMMAND_RESET)?;
+        match ps2::wait_data(Channel::First, RESET_TIMEOUT)? {
+            SELF_TEST_PASSED => Ok(()),
+            response => Err(Ps2Error::UnexpectedResponse(response)),
+        }
+    })?;
+
+    // The keyboard is back to set 2 (and its LEDs off), which the controller may translate
+    let set = if ps2::is_translated() { ScancodeSet::Set1 } else { ScancodeSet::Set2 };
+    ps2::with_controller(|| {
+        ps2::send(Channel::First, COMMAND_SET_LEDS)?;
+        ps2::send(Channel::First, Locks::default().leds())
+    })?;
+
+    *KEYBOARD.lock() = Some(Keyboard::new(set, Keymap::us()));
+
+    interrupts::register_irq(IRQ, handle_irq).map_err(Ps2Error::Irq)?;
+    ps2::with_controller(|| ps2::set_irq(Channel::First, true))?;
+
+    Ok(set)
+}
+
+fn handle_irq(_: &InterruptStackFrame) {
+    // With the LEDs written in the same go
+    let event = ps2::with_controller(|| {
+        // The IRQ may still be raised for a byte that was already polled
+        if !ps2::has_data(Channel::First) {
+            return None;
+        }
+
+        let byte = ps2::read_data();
+        KEYBOARD.lock().as_mut()
+            .and_then(|keyboard| keyboard.process(byte, |byte| ps2::write_data(Channel::First, byte)))
+    });
+
+    if let Some(event) = event {
+        EVENTS.push(InputEvent::Key(event));
+    }
+}
+
+/// Switches to a keymap by name (see [`Keymap::load`])
+pub fn set_keymap(name: &str) -> Result<(), KeymapError> {
+    let keymap = Keymap::load(name)?;
+
+    if let Some(keyboard) = KEYBOARD.lock().as_mut() {
+        keyboard.keymap = keymap;
+    }
+
+    Ok(())
+}
+
+/// The keymap in use
+pub fn keymap() -> &'static Keymap {
+    KEYBOARD.lock().as_ref().map_or(Keymap::us(), |keyboard| keyboard.keymap)
+}
+
+pub fn modifiers() -> Modifiers {
+    KEYBOARD.lock().as_ref().map(|keyboard| keyboard.modifiers).unwrap_or_default()
+}
+
+pub fn locks() -> Locks {
+    KEYBOARD.lock().as_ref().map(|keyboard| keyboard.locks).unwrap_or_default()
+}
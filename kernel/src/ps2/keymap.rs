@@ -0,0 +1,151 @@
+//! Keymaps, which turn keys into characters (see `keymaps/us.map` for the format)
+
+use alloc::{boxed::Box, collections::BTreeMap, format, string::{String, ToString}, vec::Vec};
+use core::fmt;
+
+use spin::{Lazy, Mutex};
+use x86_64::instructions::interrupts;
+
+use super::scancode::KeyCode;
+use crate::initrd;
+
+const BUILTIN_SOURCES: [(&str, &str); 4] = [
+    ("us", include_str!("keymaps/us.map")),
+    ("uk", include_str!("keymaps/uk.map")),
+    ("de", include_str!("keymaps/de.map")),
+    ("dvorak", include_str!("keymaps/dvorak.map")),
+];
+
+static BUILTIN: Lazy<Vec<Keymap>> = Lazy::new(|| {
+    BUILTIN_SOURCES.iter()
+        .map(|(name, source)| Keymap::parse(name, source).expect("built-in keymap is invalid"))
+        .collect()
+});
+
+/// Keymaps loaded from the initrd, kept forever
+static LOADED: Mutex<Vec<&'static Keymap>> = Mutex::new(Vec::new());
+
+/// Characters with no modifiers, shift, AltGr, and shift and AltGr
+type Levels = [Option<char>; 4];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keymap {
+    name: String,
+    keys: BTreeMap<KeyCode, Levels>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeymapError {
+    /// Neither built in nor in the initrd
+    NotFound,
+    /// The file isn't UTF-8
+    InvalidText,
+    /// The line's key code doesn't exist
+    UnknownKey(usize),
+    /// The line has something that isn't a character, or more than four of them
+    InvalidLevels(usize),
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeymapError::NotFound => write!(f, "keymap not found"),
+            KeymapError::InvalidText => write!(f, "keymap isn't UTF-8"),
+            KeymapError::UnknownKey(line) => write!(f, "unknown key on line {line}"),
+            KeymapError::InvalidLevels(line) => write!(f, "invalid characters on line {line}"),
+        }
+    }
+}
+
+impl Keymap {
+    pub fn parse(name: &str, source: &str) -> Result<Keymap, KeymapError> {
+        let mut keys = BTreeMap::new();
+
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut tokens = line.split_whitespace();
+            let code = tokens.next()
+                .and_then(KeyCode::from_name)
+                .ok_or(KeymapError::UnknownKey(line_number))?;
+
+            let mut levels = [None; 4];
+            for (i, token) in tokens.enumerate() {
+                let level = levels.get_mut(i).ok_or(KeymapError::InvalidLevels(line_number))?;
+                *level = parse_char(token).ok_or(KeymapError::InvalidLevels(line_number))?;
+            }
+
+            keys.insert(code, levels);
+        }
+
+        Ok(Keymap { name: name.to_string(), keys })
+    }
+
+    /// A built-in keymap, or one loaded from the initrd
+    pub fn load(name: &str) -> Result<&'static Keymap, KeymapError> {
+        if let Some(keymap) = BUILTIN.iter().find(|keymap| keymap.name == name) {
+            return Ok(keymap);
+        }
+
+        interrupts::without_interrupts(|| {
+            let mut loaded = LOADED.lock();
+            if let Some(keymap) = loaded.iter().find(|keymap| keymap.name == name) {
+                return Ok(*keymap);
+            }
+
+            let source = initrd::archive()
+                .and_then(|initrd| initrd.read(&format!("etc/keymaps/{name}.map")))
+                .ok_or(KeymapError::NotFound)?;
+            let source = core::str::from_utf8(source).map_err(|_| KeymapError::InvalidText)?;
+
+            let keymap: &'static Keymap = Box::leak(Box::new(Keymap::parse(name, source)?));
+            loaded.push(keymap);
+
+            Ok(keymap)
+        })
+    }
+
+    /// The US keymap, which the keyboard starts with
+    pub fn us() -> &'static Keymap {
+        &BUILTIN[0]
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// What `code` types, falling back to the levels without AltGr, then without shift
+    pub fn get(&self, code: KeyCode, shift: bool, alt_gr: bool) -> Option<char> {
+        let levels = self.keys.get(&code)?;
+
+        let mut candidates = match (shift, alt_gr) {
+            (false, false) => [0, 0, 0],
+            (true, false) => [1, 0, 0],
+            (false, true) => [2, 0, 0],
+            (true, true) => [3, 1, 0],
+        }.into_iter();
+
+        candidates.find_map(|level| levels[level])
+    }
+}
+
+/// A single character, `U+<hex>` or `none`. `None` if it's neither.
+fn parse_char(token: &str) -> Option<Option<char>> {
+    if token == "none" {
+        return Some(None);
+    }
+
+    if let Some(hex) = token.strip_prefix("U+") {
+        return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32).map(Some);
+    }
+
+    let mut chars = token.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Some(Some(c)),
+        _ => None,
+    }
+}
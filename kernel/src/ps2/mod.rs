@@ -0,0 +1,380 @@
+//! The 8042 PS/2 controller
+
+use alloc::vec::Vec;
+use core::{
+    fmt,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use x86_64::instructions::port::Port;
+
+use self::{keyboard::KeyEvent, mouse::MouseEvent};
+use crate::{clock, queue::ArrayQueue, task::Stream, Locked};
+
+pub mod keyboard;
+pub mod keymap;
+pub mod mouse;
+pub mod scancode;
+
+const DATA_PORT: u16 = 0x60;
+/// Status when read, commands when written
+const COMMAND_PORT: u16 = 0x64;
+
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// The byte waiting in the data port is from the second port
+const STATUS_SECOND_PORT: u8 = 1 << 5;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND_PORT: u8 = 0xA7;
+const COMMAND_ENABLE_SECOND_PORT: u8 = 0xA8;
+const COMMAND_TEST_SECOND_PORT: u8 = 0xA9;
+const COMMAND_TEST_CONTROLLER: u8 = 0xAA;
+const COMMAND_TEST_FIRST_PORT: u8 = 0xAB;
+const COMMAND_DISABLE_FIRST_PORT: u8 = 0xAD;
+const COMMAND_ENABLE_FIRST_PORT: u8 = 0xAE;
+/// Sends the next data byte to the second port's device
+const COMMAND_WRITE_SECOND_PORT: u8 = 0xD4;
+
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+const CONTROLLER_OK: u8 = 0x55;
+const PORT_OK: u8 = 0x00;
+
+/// Device responses
+pub(crate) const ACK: u8 = 0xFA;
+pub(crate) const RESEND: u8 = 0xFE;
+
+/// How often a device is asked again to take a command
+const RETRIES: usize = 3;
+
+/// How long the controller or a device may take to respond
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+const EVENT_QUEUE_SIZE: usize = 256;
+
+/// Held through each command and its responses, see [`with_controller`]
+static CONTROLLER: Locked<()> = Locked::new(());
+
+static EVENTS: EventQueue<InputEvent, EVENT_QUEUE_SIZE> = EventQueue::new();
+
+/// Stream returned by [`events`]
+pub type InputEvents = Events<InputEvent, EVENT_QUEUE_SIZE>;
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static TRANSLATED: AtomicBool = AtomicBool::new(false);
+static HAS_SECOND_PORT: AtomicBool = AtomicBool::new(false);
+
+/// One of the controller's ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// The keyboard's
+    First,
+    /// The mouse's, also called the auxiliary port
+    Second,
+}
+
+/// An event from either device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputEvent {
+    Key(KeyEvent),
+    Mouse(MouseEvent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// Nothing answers at the controller's ports
+    NoController,
+    /// The controller's self test answered with something else than `0x55`
+    ControllerTest(u8),
+    /// The port test answered with the failure it found
+    PortTest(u8),
+    /// The controller only has one port
+    NoSecondPort,
+    /// The device kept asking for the command to be sent again
+    Resend,
+    /// The device answered a command with something else than an ACK
+    UnexpectedResponse(u8),
+    Timeout,
+    /// The device's IRQ couldn't be set up
+    Irq(crate::interrupts::RegisterError),
+}
+
+impl fmt::Display for Ps2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ps2Error::NoController => write!(f, "no PS/2 controller"),
+            Ps2Error::ControllerTest(response) => write!(f, "controller self test failed ({response:#04x})"),
+            Ps2Error::PortTest(response) => write!(f, "port test failed ({response:#04x})"),
+            Ps2Error::NoSecondPort => write!(f, "no second PS/2 port"),
+            Ps2Error::Resend => write!(f, "device keeps asking for a resend"),
+            Ps2Error::UnexpectedResponse(response) => write!(f, "unexpected response {response:#04x}"),
+            Ps2Error::Timeout => write!(f, "timed out"),
+            Ps2Error::Irq(e) => write!(f, "failed to register the IRQ: {e:?}"),
+        }
+    }
+}
+
+/// Disables the ports, tests the controller, then enables the ports that
+/// passed their test (with their interrupts still disabled until their
+/// drivers are ready).
+///
+/// Must run after the clock is calibrated, for the timeouts.
+pub(super) fn init() -> Result<(), Ps2Error> {
+    with_controller(init_controller)
+}
+
+fn init_controller() -> Result<(), Ps2Error> {
+    // The FADT's flag for it isn't reliable (see `Fadt::has_8042`),
+    // but without a controller, the bus is floating
+    if status() == 0xFF {
+        return Err(Ps2Error::NoController);
+    }
+
+    command(COMMAND_DISABLE_FIRST_PORT)?;
+    command(COMMAND_DISABLE_SECOND_PORT)?;
+    flush();
+
+    let config = read_config()? & !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+    write_config(config)?;
+    TRANSLATED.store(config & CONFIG_TRANSLATION != 0, Ordering::Relaxed);
+
+    match command_with_response(COMMAND_TEST_CONTROLLER)? {
+        CONTROLLER_OK => { },
+        response => return Err(Ps2Error::ControllerTest(response)),
+    }
+    // Some controllers are reset by the self test
+    write_config(config)?;
+
+    // Only a controller with a second port enables its clock
+    command(COMMAND_ENABLE_SECOND_PORT)?;
+    let has_second_port = read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+    command(COMMAND_DISABLE_SECOND_PORT)?;
+    flush();
+
+    match command_with_response(COMMAND_TEST_FIRST_PORT)? {
+        PORT_OK => { },
+        response => return Err(Ps2Error::PortTest(response)),
+    }
+
+    // The mouse is optional, so a failing second port doesn't fail the controller
+    let has_second_port = has_second_port && command_with_response(COMMAND_TEST_SECOND_PORT)? == PORT_OK;
+
+    command(COMMAND_ENABLE_FIRST_PORT)?;
+    if has_second_port {
+        command(COMMAND_ENABLE_SECOND_PORT)?;
+    }
+
+    HAS_SECOND_PORT.store(has_second_port, Ordering::Relaxed);
+    INITIALIZED.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+pub fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Relaxed)
+}
+
+/// Whether the controller has a working second port, for a mouse
+pub fn has_second_port() -> bool {
+    HAS_SECOND_PORT.load(Ordering::Relaxed)
+}
+
+/// Whether the controller translates the keyboard's scancodes to set 1
+pub fn is_translated() -> bool {
+    TRANSLATED.load(Ordering::Relaxed)
+}
+
+/// Runs `f` with the controller to itself, with interrupts disabled. Every
+/// command and its responses must be sent in one go, and IRQ handlers read
+/// their bytes in one, so neither takes the other's bytes.
+pub(crate) fn with_controller<R>(f: impl FnOnce() -> R) -> R {
+    let _controller = CONTROLLER.lock();
+    f()
+}
+
+/// Takes the oldest event
+pub fn next_event() -> Option<InputEvent> {
+    EVENTS.pop()
+}
+
+/// The events, as they arrive
+pub fn events() -> InputEvents {
+    EVENTS.stream()
+}
+
+fn status() -> u8 {
+    unsafe { Port::<u8>::new(COMMAND_PORT).read() }
+}
+
+/// Whether a byte from `channel`'s device is waiting in the data port
+pub(crate) fn has_data(channel: Channel) -> bool {
+    let status = status();
+    let second = status & STATUS_SECOND_PORT != 0;
+
+    status & STATUS_OUTPUT_FULL != 0 && second == (channel == Channel::Second)
+}
+
+/// Reads the data port, without checking there's something in it
+pub(crate) fn read_data() -> u8 {
+    unsafe { Port::<u8>::new(DATA_PORT).read() }
+}
+
+/// Waits for a byte from `channel`'s device
+pub(crate) fn wait_data(channel: Channel, timeout: Duration) -> Result<u8, Ps2Error> {
+    wait_for(timeout, || has_data(channel))?;
+    Ok(read_data())
+}
+
+/// Writes `byte` to `channel`'s device, without waiting for its response
+pub(crate) fn write_data(channel: Channel, byte: u8) -> Result<(), Ps2Error> {
+    if channel == Channel::Second {
+        command(COMMAND_WRITE_SECOND_PORT)?;
+    }
+
+    write_data_port(byte)
+}
+
+/// Sends `byte` to `channel`'s device and waits for its ACK.
+/// Only while its interrupt is disabled, or the IRQ handler would take the response.
+pub(crate) fn send(channel: Channel, byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..RETRIES {
+        write_data(channel, byte)?;
+
+        match wait_data(channel, TIMEOUT)? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            response => return Err(Ps2Error::UnexpectedResponse(response)),
+        }
+    }
+
+    Err(Ps2Error::Resend)
+}
+
+/// Enables or disables `channel`'s interrupt
+pub(crate) fn set_irq(channel: Channel, enabled: bool) -> Result<(), Ps2Error> {
+    let irq = match channel {
+        Channel::First => CONFIG_FIRST_IRQ,
+        Channel::Second => CONFIG_SECOND_IRQ,
+    };
+
+    let config = read_config()?;
+    write_config(if enabled { config | irq } else { config & !irq })
+}
+
+fn write_data_port(byte: u8) -> Result<(), Ps2Error> {
+    wait_for(TIMEOUT, || status() & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(DATA_PORT).write(byte) };
+    Ok(())
+}
+
+fn command(command: u8) -> Result<(), Ps2Error> {
+    wait_for(TIMEOUT, || status() & STATUS_INPUT_FULL == 0)?;
+    unsafe { Port::<u8>::new(COMMAND_PORT).write(command) };
+    Ok(())
+}
+
+fn command_with_response(command_byte: u8) -> Result<u8, Ps2Error> {
+    command(command_byte)?;
+    wait_for(TIMEOUT, || status() & STATUS_OUTPUT_FULL != 0)?;
+    Ok(read_data())
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    command_with_response(COMMAND_READ_CONFIG)
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    command(COMMAND_WRITE_CONFIG)?;
+    write_data_port(config)
+}
+
+/// Drops whatever the devices sent before they were disabled
+fn flush() {
+    // The buffer is a single byte, but some controllers keep a few more
+    for _ in 0..16 {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        read_data();
+    }
+}
+
+fn wait_for(timeout: Duration, mut ready: impl FnMut() -> bool) -> Result<(), Ps2Error> {
+    let deadline = clock::now() + timeout;
+
+    while !ready() {
+        if clock::now() >= deadline {
+            return Err(Ps2Error::Timeout);
+        }
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+/// Events from a device's IRQ handler, for the rest of the kernel.
+/// Each event goes to one consumer, and they're dropped while the queue is full.
+pub(crate) struct EventQueue<T, const N: usize> {
+    events: ArrayQueue<T, N>,
+    /// Tasks waiting in `Events`
+    waiters: Locked<Vec<Waker>>,
+}
+
+impl<T, const N: usize> EventQueue<T, N> {
+    pub(crate) const fn new() -> Self {
+        EventQueue { events: ArrayQueue::new(), waiters: Locked::new(Vec::new()) }
+    }
+
+    pub(crate) fn push(&self, event: T) {
+        let _ = self.events.push(event);
+
+        let waiters = core::mem::take(&mut *self.waiters.lock());
+        for waker in waiters {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn pop(&self) -> Option<T> {
+        self.events.pop()
+    }
+
+    pub(crate) fn stream(&'static self) -> Events<T, N> {
+        Events { queue: self }
+    }
+}
+
+/// A device's events as they arrive, which never ends
+pub struct Events<T: 'static, const N: usize> {
+    queue: &'static EventQueue<T, N>,
+}
+
+impl<T, const N: usize> Stream for Events<T, N> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if let Some(event) = self.queue.pop() {
+            return Poll::Ready(Some(event));
+        }
+
+        {
+            let mut waiters = self.queue.waiters.lock();
+            if !waiters.iter().any(|waiter| waiter.will_wake(cx.waker())) {
+                waiters.push(cx.waker().clone());
+            }
+        }
+
+        // In case an event came in before the waker was there
+        match self.queue.pop() {
+            Some(event) => Poll::Ready(Some(event)),
+            None => Poll::Pending,
+        }
+    }
+}
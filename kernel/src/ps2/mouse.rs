@@ -0,0 +1,220 @@
+//! The PS/2 mouse, on the controller's second port
+
+use core::{fmt, time::Duration};
+
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{Channel, InputEvent, Ps2Error, EVENTS};
+use crate::{interrupts, ps2, Locked};
+
+const IRQ: u8 = 12;
+
+const COMMAND_SET_SAMPLE_RATE: u8 = 0xF3;
+const COMMAND_ENABLE_REPORTING: u8 = 0xF4;
+const COMMAND_GET_ID: u8 = 0xF2;
+const COMMAND_RESET: u8 = 0xFF;
+const SELF_TEST_PASSED: u8 = 0xAA;
+
+/// Sample rates that switch an IntelliMouse to 4 byte packets
+const WHEEL_SEQUENCE: [u8; 3] = [200, 100, 80];
+/// Samples per second, once set up
+const SAMPLE_RATE: u8 = 100;
+
+const ID_STANDARD: u8 = 0x00;
+const ID_WHEEL: u8 = 0x03;
+
+/// How long the mouse may take to test itself after a reset
+const RESET_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Bits of a packet's first byte
+const PACKET_LEFT: u8 = 1 << 0;
+const PACKET_RIGHT: u8 = 1 << 1;
+const PACKET_MIDDLE: u8 = 1 << 2;
+/// Always set, to find the start of packets
+const PACKET_ALWAYS_ONE: u8 = 1 << 3;
+const PACKET_X_SIGN: u8 = 1 << 4;
+const PACKET_Y_SIGN: u8 = 1 << 5;
+const PACKET_X_OVERFLOW: u8 = 1 << 6;
+const PACKET_Y_OVERFLOW: u8 = 1 << 7;
+
+/// `None` until initialized
+static MOUSE: Locked<Option<Mouse>> = Locked::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKind {
+    /// Three buttons, no wheel
+    Standard,
+    IntelliMouse,
+}
+
+impl fmt::Display for MouseKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MouseKind::Standard => write!(f, "3 buttons"),
+            MouseKind::IntelliMouse => write!(f, "3 buttons and a wheel"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+impl MouseButton {
+    const ALL: [MouseButton; 3] = [MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+
+    fn bit(self) -> u8 {
+        match self {
+            MouseButton::Left => PACKET_LEFT,
+            MouseButton::Right => PACKET_RIGHT,
+            MouseButton::Middle => PACKET_MIDDLE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseEvent {
+    /// Relative motion, with `dy` growing downwards like screen coordinates
+    Motion { dx: i16, dy: i16 },
+    ButtonPressed(MouseButton),
+    ButtonReleased(MouseButton),
+    /// Positive when scrolled down, towards the user
+    Wheel(i8),
+}
+
+/// Decodes packets into events
+#[derive(Debug, Clone)]
+pub struct PacketDecoder {
+    packet: [u8; 4],
+    received: usize,
+    length: usize,
+    /// Buttons held as of the last packet, as in its first byte
+    buttons: u8,
+}
+
+impl PacketDecoder {
+    pub const fn new(kind: MouseKind) -> Self {
+        let length = match kind {
+            MouseKind::Standard => 3,
+            MouseKind::IntelliMouse => 4,
+        };
+
+        PacketDecoder { packet: [0; 4], received: 0, length, buttons: 0 }
+    }
+
+    /// Takes the next byte, calling `emit` with the events of the packet it completes
+    pub fn advance(&mut self, byte: u8, mut emit: impl FnMut(MouseEvent)) {
+        // Out of sync, e.g. after a byte got lost
+        if self.received == 0 && byte & PACKET_ALWAYS_ONE == 0 {
+            return;
+        }
+
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.length {
+            return;
+        }
+        self.received = 0;
+
+        let [flags, x, y, z] = self.packet;
+
+        // An overflow's motion is meaningless
+        if flags & (PACKET_X_OVERFLOW | PACKET_Y_OVERFLOW) == 0 {
+            // 9 bit two's complement, with the sign in the flags
+            let dx = x as i16 - (((flags & PACKET_X_SIGN) as i16) << 4);
+            let dy = y as i16 - (((flags & PACKET_Y_SIGN) as i16) << 3);
+
+            if dx != 0 || dy != 0 {
+                emit(MouseEvent::Motion { dx, dy: -dy });
+            }
+        }
+
+        for button in MouseButton::ALL {
+            match (self.buttons & button.bit() != 0, flags & button.bit() != 0) {
+                (false, true) => emit(MouseEvent::ButtonPressed(button)),
+                (true, false) => emit(MouseEvent::ButtonReleased(button)),
+                _ => { },
+            }
+        }
+        self.buttons = flags & (PACKET_LEFT | PACKET_RIGHT | PACKET_MIDDLE);
+
+        if self.length == 4 && z != 0 {
+            emit(MouseEvent::Wheel(z as i8));
+        }
+    }
+}
+
+struct Mouse {
+    decoder: PacketDecoder,
+}
+
+/// Resets the mouse, turns on its wheel if it has one, then starts
+/// handling its IRQ. Must run after the keyboard is set up, as the
+/// keyboard's bytes are left to its IRQ handler meanwhile.
+pub(crate) fn init() -> Result<MouseKind, Ps2Error> {
+    if !ps2::has_second_port() {
+        return Err(Ps2Error::NoSecondPort);
+    }
+
+    // The keyboard's IRQ handler is running, so each command and its
+    // responses are sent with the controller held
+    ps2::with_controller(|| {
+        ps2::send(Channel::Second, COMMAND_RESET)?;
+        match ps2::wait_data(Channel::Second, RESET_TIMEOUT)? {
+            SELF_TEST_PASSED => { },
+            response => return Err(Ps2Error::UnexpectedResponse(response)),
+        }
+        match ps2::wait_data(Channel::Second, RESET_TIMEOUT)? {
+            ID_STANDARD => Ok(()),
+            response => Err(Ps2Error::UnexpectedResponse(response)),
+        }
+    })?;
+
+    for rate in WHEEL_SEQUENCE {
+        set_sample_rate(rate)?;
+    }
+    let kind = ps2::with_controller(|| {
+        ps2::send(Channel::Second, COMMAND_GET_ID)?;
+        Ok(match ps2::wait_data(Channel::Second, RESET_TIMEOUT)? {
+            ID_WHEEL => MouseKind::IntelliMouse,
+            _ => MouseKind::Standard,
+        })
+    })?;
+
+    set_sample_rate(SAMPLE_RATE)?;
+
+    *MOUSE.lock() = Some(Mouse { decoder: PacketDecoder::new(kind) });
+
+    // Before the IRQ is enabled, which would take the ACK. Packets
+    // sent meanwhile wait in the controller.
+    ps2::with_controller(|| ps2::send(Channel::Second, COMMAND_ENABLE_REPORTING))?;
+
+    interrupts::register_irq(IRQ, handle_irq).map_err(Ps2Error::Irq)?;
+    ps2::with_controller(|| ps2::set_irq(Channel::Second, true))?;
+
+    Ok(kind)
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), Ps2Error> {
+    ps2::with_controller(|| {
+        ps2::send(Channel::Second, COMMAND_SET_SAMPLE_RATE)?;
+        ps2::send(Channel::Second, rate)
+    })
+}
+
+fn handle_irq(_: &InterruptStackFrame) {
+    ps2::with_controller(|| {
+        // The IRQ may still be raised for a byte that was already polled
+        if !ps2::has_data(Channel::Second) {
+            return;
+        }
+
+        let byte = ps2::read_data();
+        if let Some(mouse) = MOUSE.lock().as_mut() {
+            mouse.decoder.advance(byte, |event| EVENTS.push(InputEvent::Mouse(event)));
+        }
+    });
+}
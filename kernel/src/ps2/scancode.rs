@@ -0,0 +1,258 @@
+//! Scancode sets 1 and 2
+
+use core::fmt;
+
+macro_rules! key_codes {
+    ($($name:ident)*) => {
+        /// A physical key
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub enum KeyCode {
+            $($name,)*
+        }
+
+        impl KeyCode {
+            pub const ALL: &'static [KeyCode] = &[$(KeyCode::$name,)*];
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(KeyCode::$name => stringify!($name),)*
+                }
+            }
+        }
+    };
+}
+
+key_codes! {
+    Escape F1 F2 F3 F4 F5 F6 F7 F8 F9 F10 F11 F12 PrintScreen ScrollLock Pause
+    Backquote Digit1 Digit2 Digit3 Digit4 Digit5 Digit6 Digit7 Digit8 Digit9 Digit0 Minus Equal Backspace
+    Tab KeyQ KeyW KeyE KeyR KeyT KeyY KeyU KeyI KeyO KeyP BracketLeft BracketRight Backslash
+    CapsLock KeyA KeyS KeyD KeyF KeyG KeyH KeyJ KeyK KeyL Semicolon Quote Enter
+    ShiftLeft IntlBackslash KeyZ KeyX KeyC KeyV KeyB KeyN KeyM Comma Period Slash ShiftRight
+    ControlLeft MetaLeft AltLeft Space AltRight MetaRight ContextMenu ControlRight
+    Insert Delete Home End PageUp PageDown ArrowUp ArrowLeft ArrowDown ArrowRight
+    NumLock NumpadDivide NumpadMultiply NumpadSubtract NumpadAdd NumpadEnter NumpadDecimal
+    Numpad0 Numpad1 Numpad2 Numpad3 Numpad4 Numpad5 Numpad6 Numpad7 Numpad8 Numpad9
+}
+
+impl KeyCode {
+    /// By [`KeyCode::name`]
+    pub fn from_name(name: &str) -> Option<KeyCode> {
+        KeyCode::ALL.iter().copied().find(|code| code.name() == name)
+    }
+}
+
+impl fmt::Display for KeyCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+impl fmt::Display for ScancodeSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScancodeSet::Set1 => write!(f, "1"),
+            ScancodeSet::Set2 => write!(f, "2"),
+        }
+    }
+}
+
+const EXTENDED: u8 = 0xE0;
+/// Only starts the pause key's sequence
+const EXTENDED_PAUSE: u8 = 0xE1;
+/// Set 2's prefix for releases (set 1 sets the top bit instead)
+const RELEASE: u8 = 0xF0;
+const SET1_RELEASE_BIT: u8 = 0x80;
+
+/// Bytes in the pause key's sequence after `0xE1`. It has no release.
+const SET1_PAUSE_LENGTH: u8 = 5;
+const SET2_PAUSE_LENGTH: u8 = 7;
+
+/// Turns a keyboard's bytes into key presses and releases
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    set: ScancodeSet,
+    state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    Extended,
+    /// Set 2 only
+    Release,
+    /// Set 2 only
+    ExtendedRelease,
+    /// Skipping the rest of the pause key's sequence
+    Pause(u8),
+}
+
+impl Decoder {
+    pub const fn new(set: ScancodeSet) -> Self {
+        Decoder { set, state: State::Start }
+    }
+
+    pub fn set(&self) -> ScancodeSet {
+        self.set
+    }
+
+    /// Takes the next byte, returning a key once its sequence is complete.
+    ///
+    /// The fake shifts some keyboards send around extended keys
+    /// (e.g. print screen is `E0 2A E0 37` in set 1) are ignored.
+    pub fn advance(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        match (self.state, byte) {
+            (State::Pause(1), _) => {
+                self.state = State::Start;
+                return Some((KeyCode::Pause, KeyState::Pressed));
+            },
+            (State::Pause(remaining), _) => {
+                self.state = State::Pause(remaining - 1);
+                return None;
+            },
+            (State::Start, EXTENDED_PAUSE) => {
+                self.state = State::Pause(match self.set {
+                    ScancodeSet::Set1 => SET1_PAUSE_LENGTH,
+                    ScancodeSet::Set2 => SET2_PAUSE_LENGTH,
+                });
+                return None;
+            },
+            (State::Start, EXTENDED) => {
+                self.state = State::Extended;
+                return None;
+            },
+            _ => { },
+        }
+
+        match self.set {
+            ScancodeSet::Set1 => self.advance_set1(byte),
+            ScancodeSet::Set2 => self.advance_set2(byte),
+        }
+    }
+
+    fn advance_set1(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        let extended = self.state == State::Extended;
+        self.state = State::Start;
+
+        let state = if byte & SET1_RELEASE_BIT != 0 { KeyState::Released } else { KeyState::Pressed };
+        let code = byte & !SET1_RELEASE_BIT;
+
+        let key = if extended { set1_extended(code) } else { set1(code) };
+        key.map(|key| (key, state))
+    }
+
+    fn advance_set2(&mut self, byte: u8) -> Option<(KeyCode, KeyState)> {
+        match (self.state, byte) {
+            (State::Start, RELEASE) => {
+                self.state = State::Release;
+                None
+            },
+            (State::Extended, RELEASE) => {
+                self.state = State::ExtendedRelease;
+                None
+            },
+            (state, code) => {
+                self.state = State::Start;
+
+                let (key, key_state) = match state {
+                    State::Extended => (set2_extended(code), KeyState::Pressed),
+                    State::Release => (set2(code), KeyState::Released),
+                    State::ExtendedRelease => (set2_extended(code), KeyState::Released),
+                    _ => (set2(code), KeyState::Pressed),
+                };
+                key.map(|key| (key, key_state))
+            },
+        }
+    }
+}
+
+fn set1(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    Some(match code {
+        0x01 => Escape,
+        0x02 => Digit1, 0x03 => Digit2, 0x04 => Digit3, 0x05 => Digit4, 0x06 => Digit5,
+        0x07 => Digit6, 0x08 => Digit7, 0x09 => Digit8, 0x0A => Digit9, 0x0B => Digit0,
+        0x0C => Minus, 0x0D => Equal, 0x0E => Backspace, 0x0F => Tab,
+        0x10 => KeyQ, 0x11 => KeyW, 0x12 => KeyE, 0x13 => KeyR, 0x14 => KeyT,
+        0x15 => KeyY, 0x16 => KeyU, 0x17 => KeyI, 0x18 => KeyO, 0x19 => KeyP,
+        0x1A => BracketLeft, 0x1B => BracketRight, 0x1C => Enter, 0x1D => ControlLeft,
+        0x1E => KeyA, 0x1F => KeyS, 0x20 => KeyD, 0x21 => KeyF, 0x22 => KeyG,
+        0x23 => KeyH, 0x24 => KeyJ, 0x25 => KeyK, 0x26 => KeyL,
+        0x27 => Semicolon, 0x28 => Quote, 0x29 => Backquote, 0x2A => ShiftLeft, 0x2B => Backslash,
+        0x2C => KeyZ, 0x2D => KeyX, 0x2E => KeyC, 0x2F => KeyV, 0x30 => KeyB, 0x31 => KeyN, 0x32 => KeyM,
+        0x33 => Comma, 0x34 => Period, 0x35 => Slash, 0x36 => ShiftRight,
+        0x37 => NumpadMultiply, 0x38 => AltLeft, 0x39 => Space, 0x3A => CapsLock,
+        0x3B => F1, 0x3C => F2, 0x3D => F3, 0x3E => F4, 0x3F => F5,
+        0x40 => F6, 0x41 => F7, 0x42 => F8, 0x43 => F9, 0x44 => F10,
+        0x45 => NumLock, 0x46 => ScrollLock,
+        0x47 => Numpad7, 0x48 => Numpad8, 0x49 => Numpad9, 0x4A => NumpadSubtract,
+        0x4B => Numpad4, 0x4C => Numpad5, 0x4D => Numpad6, 0x4E => NumpadAdd,
+        0x4F => Numpad1, 0x50 => Numpad2, 0x51 => Numpad3, 0x52 => Numpad0, 0x53 => NumpadDecimal,
+        0x56 => IntlBackslash, 0x57 => F11, 0x58 => F12,
+        _ => return None,
+    })
+}
+
+fn set1_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    Some(match code {
+        0x1C => NumpadEnter, 0x1D => ControlRight, 0x35 => NumpadDivide, 0x37 => PrintScreen,
+        0x38 => AltRight, 0x47 => Home, 0x48 => ArrowUp, 0x49 => PageUp,
+        0x4B => ArrowLeft, 0x4D => ArrowRight, 0x4F => End, 0x50 => ArrowDown,
+        0x51 => PageDown, 0x52 => Insert, 0x53 => Delete,
+        0x5B => MetaLeft, 0x5C => MetaRight, 0x5D => ContextMenu,
+        _ => return None,
+    })
+}
+
+fn set2(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    Some(match code {
+        0x01 => F9, 0x03 => F5, 0x04 => F3, 0x05 => F1, 0x06 => F2, 0x07 => F12,
+        0x09 => F10, 0x0A => F8, 0x0B => F6, 0x0C => F4, 0x0D => Tab, 0x0E => Backquote,
+        0x11 => AltLeft, 0x12 => ShiftLeft, 0x14 => ControlLeft, 0x15 => KeyQ, 0x16 => Digit1,
+        0x1A => KeyZ, 0x1B => KeyS, 0x1C => KeyA, 0x1D => KeyW, 0x1E => Digit2,
+        0x21 => KeyC, 0x22 => KeyX, 0x23 => KeyD, 0x24 => KeyE, 0x25 => Digit4, 0x26 => Digit3,
+        0x29 => Space, 0x2A => KeyV, 0x2B => KeyF, 0x2C => KeyT, 0x2D => KeyR, 0x2E => Digit5,
+        0x31 => KeyN, 0x32 => KeyB, 0x33 => KeyH, 0x34 => KeyG, 0x35 => KeyY, 0x36 => Digit6,
+        0x3A => KeyM, 0x3B => KeyJ, 0x3C => KeyU, 0x3D => Digit7, 0x3E => Digit8,
+        0x41 => Comma, 0x42 => KeyK, 0x43 => KeyI, 0x44 => KeyO, 0x45 => Digit0, 0x46 => Digit9,
+        0x49 => Period, 0x4A => Slash, 0x4B => KeyL, 0x4C => Semicolon, 0x4D => KeyP, 0x4E => Minus,
+        0x52 => Quote, 0x54 => BracketLeft, 0x55 => Equal,
+        0x58 => CapsLock, 0x59 => ShiftRight, 0x5A => Enter, 0x5B => BracketRight, 0x5D => Backslash,
+        0x61 => IntlBackslash, 0x66 => Backspace,
+        0x69 => Numpad1, 0x6B => Numpad4, 0x6C => Numpad7,
+        0x70 => Numpad0, 0x71 => NumpadDecimal, 0x72 => Numpad2, 0x73 => Numpad5,
+        0x74 => Numpad6, 0x75 => Numpad8, 0x76 => Escape, 0x77 => NumLock,
+        0x78 => F11, 0x79 => NumpadAdd, 0x7A => Numpad3, 0x7B => NumpadSubtract,
+        0x7C => NumpadMultiply, 0x7D => Numpad9, 0x7E => ScrollLock, 0x83 => F7,
+        _ => return None,
+    })
+}
+
+fn set2_extended(code: u8) -> Option<KeyCode> {
+    use KeyCode::*;
+
+    Some(match code {
+        0x11 => AltRight, 0x14 => ControlRight, 0x1F => MetaLeft, 0x27 => MetaRight,
+        0x2F => ContextMenu, 0x4A => NumpadDivide, 0x5A => NumpadEnter,
+        0x69 => End, 0x6B => ArrowLeft, 0x6C => Home, 0x70 => Insert, 0x71 => Delete,
+        0x72 => ArrowDown, 0x74 => ArrowRight, 0x75 => ArrowUp, 0x7A => PageDown,
+        0x7C => PrintScreen, 0x7D => PageUp,
+        _ => return None,
+    })
+}
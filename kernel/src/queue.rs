@@ -0,0 +1,125 @@
+//! A bounded lock-free queue, for passing values out of interrupt handlers
+
+use core::{
+    cell::UnsafeCell,
+    cmp,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub struct ArrayQueue<T, const N: usize> {
+    slots: [Slot<T>; N],
+    /// Position of the next value to pop
+    head: AtomicUsize,
+    /// Position of the next value to push
+    tail: AtomicUsize,
+}
+
+struct Slot<T> {
+    /// `position` while empty, `position + 1` once written, for the slot's position in the current lap
+    sequence: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// A value is only ever accessed by the one thread that claimed its slot
+unsafe impl<T: Send, const N: usize> Sync for ArrayQueue<T, N> {}
+
+impl<T, const N: usize> ArrayQueue<T, N> {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Slot<T> = Slot { sequence: AtomicUsize::new(0), value: UnsafeCell::new(MaybeUninit::uninit()) };
+
+    pub const fn new() -> Self {
+        assert!(N > 0, "queues need room for at least one value");
+
+        let mut slots = [Self::EMPTY; N];
+        let mut i = 0;
+        while i < N {
+            slots[i].sequence = AtomicUsize::new(i);
+            i += 1;
+        }
+
+        ArrayQueue { slots, head: AtomicUsize::new(0), tail: AtomicUsize::new(0) }
+    }
+
+    /// Adds `value` at the back, or gives it back if the queue is full
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let mut tail = self.tail.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[tail % N];
+            let lap = slot.sequence.load(Ordering::Acquire).wrapping_sub(tail) as isize;
+
+            match lap.cmp(&0) {
+                // Empty, ours if nobody else claims it first
+                cmp::Ordering::Equal => {
+                    match self.tail.compare_exchange_weak(tail, tail.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                        Ok(_) => {
+                            unsafe { (*slot.value.get()).write(value) };
+                            slot.sequence.store(tail.wrapping_add(1), Ordering::Release);
+                            return Ok(());
+                        },
+                        Err(current) => tail = current,
+                    }
+                },
+                // Still holds the value from the last lap
+                cmp::Ordering::Less => return Err(value),
+                // Another producer got it
+                cmp::Ordering::Greater => tail = self.tail.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// Takes the value at the front
+    pub fn pop(&self) -> Option<T> {
+        let mut head = self.head.load(Ordering::Relaxed);
+
+        loop {
+            let slot = &self.slots[head % N];
+            let lap = slot.sequence.load(Ordering::Acquire).wrapping_sub(head.wrapping_add(1)) as isize;
+
+            match lap.cmp(&0) {
+                // Written, ours if nobody else claims it first
+                cmp::Ordering::Equal => {
+                    match self.head.compare_exchange_weak(head, head.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                        Ok(_) => {
+                            let value = unsafe { (*slot.value.get()).assume_init_read() };
+                            slot.sequence.store(head.wrapping_add(N), Ordering::Release);
+                            return Some(value);
+                        },
+                        Err(current) => head = current,
+                    }
+                },
+                // Not written yet (or still being written)
+                cmp::Ordering::Less => return None,
+                // Another consumer got it
+                cmp::Ordering::Greater => head = self.head.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    /// How many values are queued, which may have changed by the time it's returned
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        self.tail.load(Ordering::Relaxed).wrapping_sub(head).min(N)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for ArrayQueue<T, N> {
+    fn default() -> Self {
+        ArrayQueue::new()
+    }
+}
+
+impl<T, const N: usize> Drop for ArrayQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() { }
+    }
+}
@@ -19,6 +19,21 @@ pub fn _print(args: ::core::fmt::Arguments) {
     })
 }
 
+/// Writes to serial without waiting for the lock, for code that may have
+/// interrupted its holder (panics, NMIs, machine checks). If it's held,
+/// the output may interleave with the holder's.
+pub struct EmergencyWriter;
+
+impl ::core::fmt::Write for EmergencyWriter {
+    fn write_str(&mut self, s: &str) -> ::core::fmt::Result {
+        match SERIAL1.try_lock() {
+            Some(mut serial) => serial.write_str(s),
+            // Already initialized by the holder
+            None => unsafe { SerialPort::new(0x3F8) }.write_str(s),
+        }
+    }
+}
+
 #[macro_export]
 macro_rules! serial_print {
     ($($arg:tt)*) => ($crate::serial::_print(format_args!($($arg)*)));
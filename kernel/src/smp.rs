@@ -0,0 +1,486 @@
+//! Starting the application processors
+
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use core::{arch::global_asm, fmt, sync::atomic::{AtomicBool, Ordering}};
+
+use spin::{Mutex, Once};
+use x86_64::{
+    instructions::interrupts,
+    registers::{control::{Cr3, Cr4}, model_specific::{Efer, EferFlags}},
+    structures::paging::{mapper::MapToError, Mapper, PageTable, PageTableFlags, PhysFrame},
+    VirtAddr,
+};
+
+use crate::{
+    acpi::{self, fadt::PmTimer},
+    apic, clock,
+    gdt, memory,
+    paging::{self, FaultError, ReserveError},
+    percpu, syscall, thread,
+    PHYSICAL_MEM_OFFSET,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Each AP's kernel stack
+const STACK_SIZE: u64 = 64 * 1024;
+/// Each AP's interrupt stacks (see [`gdt::IST_STACKS`])
+const IST_STACK_SIZE: u64 = gdt::IST_STACK_SIZE as u64;
+const IST_STACK_NAMES: [&str; gdt::IST_STACKS] = ["ap double fault stack", "ap nmi stack", "ap machine check stack"];
+
+/// Where the APs' stacks are reserved
+const STACKS_START: u64 = 0x5000_0000_0000;
+/// Each AP's stacks: a guard page and the kernel stack, then a guard page
+/// and each interrupt stack
+const STACKS_STRIDE: u64 = PAGE_SIZE + STACK_SIZE + gdt::IST_STACKS as u64 * (PAGE_SIZE + IST_STACK_SIZE);
+
+/// How long to wait for an AP to come online after starting it
+const STARTUP_TIMEOUT_US: u64 = 100_000;
+
+/// Temporary GDT for the trampoline: null, 64 bit code (0x08), data (0x10)
+const TRAMPOLINE_GDT: [u64; 3] = [0, 0x00AF_9A00_0000_FFFF, 0x00CF_9200_0000_FFFF];
+
+static CPUS: Once<Vec<Cpu>> = Once::new();
+
+/// Wakes a halted CPU up, e.g. to run the work it was given
+static WAKE_VECTOR: Once<u8> = Once::new();
+
+type Work = Box<dyn FnOnce() + Send>;
+
+/// A CPU found in the MADT
+pub struct Cpu {
+    pub apic_id: u32,
+    online: AtomicBool,
+    work: Mutex<VecDeque<Work>>,
+}
+
+impl Cpu {
+    fn new(apic_id: u32) -> Self {
+        Cpu { apic_id, online: AtomicBool::new(false), work: Mutex::new(VecDeque::new()) }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmpError {
+    NoSuchCpu(usize),
+    Offline(usize),
+    /// The boot CPU doesn't take work from other CPUs
+    BootCpuBusy,
+    /// There's no free memory below 1 MiB for the trampoline
+    NoLowMemory,
+    /// The trampoline couldn't be identity mapped
+    TrampolineMapping,
+    NoWakeVector,
+    StackReserve(ReserveError),
+    StackMap(FaultError),
+    /// The AP with this local APIC ID didn't come online
+    Timeout(u32),
+}
+
+impl fmt::Display for SmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmpError::NoSuchCpu(cpu) => write!(f, "no CPU {cpu}"),
+            SmpError::Offline(cpu) => write!(f, "CPU {cpu} is offline"),
+            SmpError::BootCpuBusy => write!(f, "the boot CPU doesn't take work"),
+            SmpError::NoLowMemory => write!(f, "no memory below 1 MiB for the trampoline"),
+            SmpError::TrampolineMapping => write!(f, "failed to map the trampoline"),
+            SmpError::NoWakeVector => write!(f, "no free interrupt vector"),
+            SmpError::StackReserve(e) => write!(f, "failed to reserve a stack: {e:?}"),
+            SmpError::StackMap(e) => write!(f, "failed to map a stack: {e}"),
+            SmpError::Timeout(apic_id) => write!(f, "CPU with APIC ID {apic_id} didn't start"),
+        }
+    }
+}
+
+/// Starts every AP in the MADT, returning how many CPUs are online.
+///
+/// Must run after `acpi::init`, with the local APIC enabled.
+pub(super) fn init() -> Result<usize, SmpError> {
+    let bsp_id = apic::id();
+
+    let mut cpus = vec![ Cpu::new(bsp_id) ];
+    if let Some(madt) = acpi::get().and_then(|acpi| acpi.madt.as_ref()) {
+        cpus.extend(madt.local_apics.iter()
+            .filter(|lapic| lapic.enabled && lapic.apic_id != bsp_id)
+            .map(|lapic| Cpu::new(lapic.apic_id))
+            .take(percpu::MAX_CPUS - 1));
+    }
+    cpus[0].online.store(true, Ordering::SeqCst);
+
+    let cpus = CPUS.call_once(|| cpus);
+    if cpus.len() == 1 {
+        return Ok(1);
+    }
+
+    let vector = crate::interrupts::register(|_: &_| { }).map_err(|_| SmpError::NoWakeVector)?;
+    WAKE_VECTOR.call_once(|| vector);
+
+    let trampoline = install_trampoline()?;
+
+    // An AP that doesn't start doesn't stop the others
+    let mut result = Ok(());
+    for (index, cpu) in cpus.iter().enumerate().skip(1) {
+        if let Err(e) = start(trampoline, index, cpu) {
+            result = Err(e);
+        }
+    }
+
+    result.map(|_| online_count())
+}
+
+/// Every CPU in the MADT, starting with the boot CPU
+pub fn cpus() -> &'static [Cpu] {
+    CPUS.get().map_or(&[], Vec::as_slice)
+}
+
+pub fn online_count() -> usize {
+    cpus().iter().filter(|cpu| cpu.is_online()).count().max(1)
+}
+
+/// Index (into [`cpus`]) of the CPU this runs on
+pub fn current() -> usize {
+    percpu::cpu()
+}
+
+/// Runs `work` on CPU `cpu` (an index into [`cpus`]).
+///
+/// APs run their work in order, whenever they're idle. The boot CPU is busy
+/// running the kernel, so it only runs work given to it by itself (right away).
+pub fn run_on(cpu: usize, work: impl FnOnce() + Send + 'static) -> Result<(), SmpError> {
+    let target = cpus().get(cpu).ok_or(SmpError::NoSuchCpu(cpu))?;
+    if !target.is_online() {
+        return Err(SmpError::Offline(cpu));
+    }
+
+    if cpu == current() {
+        work();
+        return Ok(());
+    }
+
+    if cpu == 0 {
+        return Err(SmpError::BootCpuBusy);
+    }
+
+    let work: Work = Box::new(work);
+    interrupts::without_interrupts(|| target.work.lock().push_back(work));
+
+    // In case it's halted
+    wake(cpu);
+
+    Ok(())
+}
+
+/// Interrupts CPU `cpu` (an index into [`cpus`]) out of `hlt`, if it's another online CPU
+pub(crate) fn wake(cpu: usize) {
+    let (Some(target), Some(&vector)) = (cpus().get(cpu), WAKE_VECTOR.get()) else {
+        return;
+    };
+
+    if cpu != current() && target.is_online() {
+        apic::with_lapic(|lapic| unsafe { lapic.send_ipi(vector, target.apic_id) });
+    }
+}
+
+/// Loads the trampoline into low memory, returning its frame
+fn install_trampoline() -> Result<PhysFrame, SmpError> {
+    let physical_offset = *PHYSICAL_MEM_OFFSET.get().unwrap();
+    let memory = memory::get().ok_or(SmpError::TrampolineMapping)?;
+
+    interrupts::without_interrupts(|| {
+        let mut memory = memory.lock();
+        let memory::Memory { mapper, frame_allocator } = &mut *memory;
+
+        let code = frame_allocator.allocate_low_frame().ok_or(SmpError::NoLowMemory)?;
+        let page_table = frame_allocator.allocate_low_frame().ok_or(SmpError::NoLowMemory)?;
+
+        // The trampoline keeps running at its physical address once paging is enabled
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.identity_map(code, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(MapToError::PageAlreadyMapped(frame)) if frame == code => { },
+            Err(_) => return Err(SmpError::TrampolineMapping),
+        }
+
+        unsafe {
+            let start = &AP_TRAMPOLINE_START as *const u8;
+            let len = &AP_TRAMPOLINE_END as *const u8 as usize - start as usize;
+            assert!(len as u64 <= PAGE_SIZE, "AP trampoline doesn't fit in a page");
+
+            let dest = (physical_offset + code.start_address().as_u64()) as *mut u8;
+            core::ptr::copy_nonoverlapping(start, dest, len);
+
+            // The AP's first page table, which has the identity mapping made above
+            let (kernel_table, _) = Cr3::read();
+            core::ptr::copy_nonoverlapping(
+                (physical_offset + kernel_table.start_address().as_u64()) as *const PageTable,
+                (physical_offset + page_table.start_address().as_u64()) as *mut PageTable,
+                1,
+            );
+
+            write_params(code, |params| params.temp_cr3 = page_table.start_address().as_u64() as u32);
+        }
+
+        Ok(code)
+    })
+}
+
+/// Starts AP `index` and waits for it to come online
+fn start(trampoline: PhysFrame, index: usize, cpu: &Cpu) -> Result<(), SmpError> {
+    let (stack, ist_stacks) = stacks(index);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+
+    let stack = paging::reserve("ap stack", stack, STACK_SIZE, flags).map_err(SmpError::StackReserve)?;
+    // Page faults need a stack to be handled on
+    paging::populate(&stack).map_err(SmpError::StackMap)?;
+
+    for (name, start) in IST_STACK_NAMES.into_iter().zip(ist_stacks) {
+        let ist_stack = paging::reserve(name, start, IST_STACK_SIZE, flags).map_err(SmpError::StackReserve)?;
+        paging::populate(&ist_stack).map_err(SmpError::StackMap)?;
+    }
+    gdt::prepare_ap(index, ist_stacks.map(|start| start + IST_STACK_SIZE));
+
+    let trampoline_addr = trampoline.start_address().as_u64();
+    let (kernel_table, kernel_table_flags) = Cr3::read();
+
+    unsafe {
+        write_params(trampoline, |params| {
+            params.gdt = TRAMPOLINE_GDT;
+            params.gdtr_limit = (core::mem::size_of_val(&TRAMPOLINE_GDT) - 1) as u16;
+            params.gdtr_base = (trampoline_addr + params_offset()) as u32;
+            params.far_jump_offset = (trampoline_addr + long_mode_offset()) as u32;
+            params.far_jump_selector = 0x08;
+            // LMA is set by the CPU once paging is enabled
+            params.efer = (Efer::read_raw() & !EferFlags::LONG_MODE_ACTIVE.bits()) as u32;
+            params.cr4 = Cr4::read_raw();
+            params.kernel_cr3 = kernel_table.start_address().as_u64() | kernel_table_flags.bits();
+            params.stack_top = (stack.start + stack.size).as_u64();
+            params.entry = ap_main as usize as u64;
+            params.cpu = index as u64;
+        });
+    }
+
+    // The AP starts at the trampoline, which has to be page aligned below 1 MiB
+    let sipi_vector = (trampoline_addr >> 12) as u8;
+
+    apic::with_lapic(|lapic| unsafe { lapic.send_init_ipi(cpu.apic_id) });
+    delay_us(10_000);
+
+    for _ in 0..2 {
+        apic::with_lapic(|lapic| unsafe { lapic.send_sipi(sipi_vector, cpu.apic_id) });
+        delay_us(200);
+
+        if cpu.is_online() {
+            break;
+        }
+    }
+
+    for _ in 0..STARTUP_TIMEOUT_US / 100 {
+        if cpu.is_online() {
+            return Ok(());
+        }
+        delay_us(100);
+    }
+
+    Err(SmpError::Timeout(cpu.apic_id))
+}
+
+/// Where the kernel stack and interrupt stacks of AP `index` start
+fn stacks(index: usize) -> (VirtAddr, [VirtAddr; gdt::IST_STACKS]) {
+    let base = STACKS_START + index as u64 * STACKS_STRIDE;
+    let stack = VirtAddr::new(base + PAGE_SIZE);
+    let ist_stacks = core::array::from_fn(|i| stack + STACK_SIZE + PAGE_SIZE + i as u64 * (PAGE_SIZE + IST_STACK_SIZE));
+
+    (stack, ist_stacks)
+}
+
+/// Where the APs start running Rust, on their own stack
+extern "C" fn ap_main(index: u64) -> ! {
+    let index = index as usize;
+
+    unsafe {
+        gdt::init_ap(index);
+        percpu::init(index);
+    }
+    crate::interrupts::init_ap();
+    apic::with_lapic(|lapic| unsafe { lapic.enable() });
+    clock::init_ap();
+    syscall::init();
+    // This CPU only runs its threads when this one is preempted, or waits
+    thread::init_cpu("smp worker").expect("failed to start the scheduler");
+
+    let cpu = &cpus()[index];
+    cpu.online.store(true, Ordering::SeqCst);
+
+    loop {
+        // Work may arrive between checking for it and halting,
+        // but `sti; hlt` doesn't let the wake up interrupt in until halted
+        interrupts::disable();
+        let work = cpu.work.lock().pop_front();
+
+        match work {
+            Some(work) => {
+                interrupts::enable();
+                work();
+            },
+            None => interrupts::enable_and_hlt(),
+        }
+    }
+}
+
+/// Busy-waits for at least `us` microseconds
+fn delay_us(us: u64) {
+    let pm_timer = acpi::get()
+        .and_then(|acpi| acpi.fadt.as_ref())
+        .and_then(|fadt| fadt.pm_timer)
+        .filter(|timer| timer.read().is_some());
+
+    let Some(timer) = pm_timer else {
+        // Way too long on anything but the slowest emulators, but that's fine
+        for _ in 0..us * 1000 {
+            core::hint::spin_loop();
+        }
+        return;
+    };
+
+    let ticks = us * PmTimer::FREQUENCY / 1_000_000 + 1;
+    let start = timer.read().unwrap() as u64;
+
+    while (timer.read().unwrap() as u64 + timer.modulus() - start) % timer.modulus() < ticks {
+        core::hint::spin_loop();
+    }
+}
+
+/// The variables at the end of the trampoline, must match the assembly below
+#[repr(C, packed)]
+struct TrampolineParams {
+    gdt: [u64; 3],
+    gdtr_limit: u16,
+    gdtr_base: u32,
+    far_jump_offset: u32,
+    far_jump_selector: u16,
+    /// The first page table, which has to be below 4 GiB
+    temp_cr3: u32,
+    efer: u32,
+    _padding: u32,
+    cr4: u64,
+    kernel_cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu: u64,
+}
+
+/// Updates the variables of the trampoline copied to `trampoline`
+///
+/// # Safety
+///
+/// The trampoline must have been copied there, and no AP may be starting
+unsafe fn write_params(trampoline: PhysFrame, f: impl FnOnce(&mut TrampolineParams)) {
+    let physical_offset = *PHYSICAL_MEM_OFFSET.get().unwrap();
+    let params = (physical_offset + trampoline.start_address().as_u64() + params_offset()) as *mut TrampolineParams;
+
+    let mut value = params.read_unaligned();
+    f(&mut value);
+    params.write_unaligned(value);
+}
+
+fn params_offset() -> u64 {
+    unsafe { &AP_TRAMPOLINE_PARAMS as *const u8 as u64 - &AP_TRAMPOLINE_START as *const u8 as u64 }
+}
+
+fn long_mode_offset() -> u64 {
+    unsafe { &AP_TRAMPOLINE_LONG_MODE as *const u8 as u64 - &AP_TRAMPOLINE_START as *const u8 as u64 }
+}
+
+extern "C" {
+    static AP_TRAMPOLINE_START: u8;
+    static AP_TRAMPOLINE_LONG_MODE: u8;
+    static AP_TRAMPOLINE_PARAMS: u8;
+    static AP_TRAMPOLINE_END: u8;
+}
+
+// Copied to a page below 1 MiB, where an AP starts with CS = page >> 4 and IP = 0.
+// Real mode addresses are relative to the start of the trampoline.
+global_asm!(r#"
+.pushsection .text.ap_trampoline, "ax"
+.global AP_TRAMPOLINE_START
+.global AP_TRAMPOLINE_LONG_MODE
+.global AP_TRAMPOLINE_PARAMS
+.global AP_TRAMPOLINE_END
+
+.code16
+AP_TRAMPOLINE_START:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+
+    lgdtl (ap_gdtr - AP_TRAMPOLINE_START)
+
+    // PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+
+    mov (ap_temp_cr3 - AP_TRAMPOLINE_START), %eax
+    mov %eax, %cr3
+
+    // Long mode (and whatever else the boot CPU has enabled, like NX)
+    mov $0xC0000080, %ecx
+    mov (ap_efer - AP_TRAMPOLINE_START), %eax
+    xor %edx, %edx
+    wrmsr
+
+    // Paging, write protection and protected mode at once, straight into long mode,
+    // plus ET and NE for native FPU errors. Set rather than ORed in, as INIT
+    // leaves CD and NW set, with caching disabled.
+    mov $0x80010031, %eax
+    mov %eax, %cr0
+
+    ljmpl *(ap_far_jump - AP_TRAMPOLINE_START)
+
+.code64
+AP_TRAMPOLINE_LONG_MODE:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    xor %ax, %ax
+    mov %ax, %fs
+    mov %ax, %gs
+
+    mov ap_cr4(%rip), %rax
+    mov %rax, %cr4
+    mov ap_kernel_cr3(%rip), %rax
+    mov %rax, %cr3
+
+    mov ap_stack_top(%rip), %rsp
+    mov ap_cpu(%rip), %rdi
+    // Nothing called the trampoline, so `ap_main` has no caller frame
+    xor %ebp, %ebp
+    call *ap_entry(%rip)
+    ud2
+
+.balign 8
+AP_TRAMPOLINE_PARAMS:
+ap_gdt:         .quad 0, 0, 0
+ap_gdtr:        .word 0
+                .long 0
+ap_far_jump:    .long 0
+                .word 0
+ap_temp_cr3:    .long 0
+ap_efer:        .long 0
+                .long 0
+ap_cr4:         .quad 0
+ap_kernel_cr3:  .quad 0
+ap_stack_top:   .quad 0
+ap_entry:       .quad 0
+ap_cpu:         .quad 0
+AP_TRAMPOLINE_END:
+
+.popsection
+"#, options(att_syntax));
@@ -0,0 +1,307 @@
+//! System calls, made with `syscall` (see [`kleos_abi`] for the ABI)
+
+use core::{arch::global_asm, time::Duration};
+
+use x86_64::{
+    registers::{
+        model_specific::{Efer, EferFlags, LStar, SFMask, Star},
+        rflags::RFlags,
+    },
+    structures::paging::{Page, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+pub use kleos_abi::{Error, Syscall};
+
+use crate::{clock, exceptions::Exception, gdt, memory, percpu, print, process, thread};
+
+/// The user registers, as saved on the kernel stack by the entry stub.
+/// The result goes in `rax`, the rest is restored as is.
+#[derive(Debug, Clone)]
+#[repr(C)]
+pub struct SyscallFrame {
+    /// The system call number on entry
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub r10: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub rbx: u64,
+    pub rbp: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    /// From `r11`
+    pub rflags: u64,
+    /// From `rcx`
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+impl SyscallFrame {
+    pub fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+/// Enables `syscall` on this CPU. Every CPU's GDT has the same layout,
+/// so any of them gives the right selectors.
+pub(crate) fn init() {
+    let (_, selectors) = &*gdt::GDT;
+
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+    Star::write(selectors.user_cs(), selectors.user_ds(), selectors.kernel_cs(), selectors.kernel_ds())
+        .expect("the GDT doesn't have the layout `syscall` needs");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // Cleared on entry, interrupts are enabled again once on the kernel stack
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG | RFlags::ALIGNMENT_CHECK);
+}
+
+/// Runs system call `number` with the raw `args`, as if it was made by the current thread
+pub fn dispatch(number: u64, args: [u64; 6]) -> Result<u64, Error> {
+    let handler = usize::try_from(number).ok()
+        .and_then(|number| TABLE.get(number))
+        .ok_or(Error::NoSuchSyscall)?;
+
+    handler(&mut Args { values: args, next: 0 })
+}
+
+/// Called by `syscall_entry`, with the result going back in `rax`
+extern "C" fn handle(frame: &mut SyscallFrame) {
+    frame.rax = kleos_abi::encode(dispatch(frame.rax, frame.args()));
+
+    // `sysretq` to a non-canonical address faults in ring 0, already on the
+    // user's stack, so the process gets the fault instead
+    if VirtAddr::try_new(frame.rip).is_err() {
+        process::kill_from_syscall(Exception::GeneralProtection);
+    }
+}
+
+type Handler = fn(&mut Args) -> Result<u64, Error>;
+
+/// Wraps `handler` in a [`Handler`], decoding its arguments (in order) with [`FromArgs`]
+macro_rules! handler {
+    ($handler:ident($($ty:ty),*)) => {{
+        #[allow(unused_variables)]
+        fn decode(args: &mut Args) -> Result<u64, Error> {
+            $handler($(<$ty as FromArgs>::from_args(args)?),*)
+        }
+        decode as Handler
+    }};
+}
+
+/// The handlers, indexed by system call number
+static TABLE: [Handler; Syscall::COUNT] = {
+    let mut table = [no_such_syscall as Handler; Syscall::COUNT];
+    table[Syscall::Exit as usize] = handler!(sys_exit(i32));
+    table[Syscall::Write as usize] = handler!(sys_write(u64, &[u8]));
+    table[Syscall::Yield as usize] = handler!(sys_yield());
+    table[Syscall::Sleep as usize] = handler!(sys_sleep(Duration));
+    table[Syscall::Uptime as usize] = handler!(sys_uptime());
+    table
+};
+
+fn no_such_syscall(_: &mut Args) -> Result<u64, Error> {
+    Err(Error::NoSuchSyscall)
+}
+
+fn sys_exit(code: i32) -> Result<u64, Error> {
+    process::exit(code)
+}
+
+fn sys_write(fd: u64, text: &[u8]) -> Result<u64, Error> {
+    if fd != kleos_abi::STDOUT && fd != kleos_abi::STDERR {
+        return Err(Error::BadDescriptor);
+    }
+
+    let text = core::str::from_utf8(text).map_err(|_| Error::InvalidArgument)?;
+    print!("{text}");
+
+    Ok(text.len() as u64)
+}
+
+fn sys_yield() -> Result<u64, Error> {
+    thread::yield_now();
+    Ok(0)
+}
+
+fn sys_sleep(duration: Duration) -> Result<u64, Error> {
+    thread::sleep(duration);
+    Ok(0)
+}
+
+fn sys_uptime() -> Result<u64, Error> {
+    Ok(clock::uptime().as_nanos() as u64)
+}
+
+/// The raw arguments of a system call, decoded in order
+pub struct Args {
+    values: [u64; 6],
+    next: usize,
+}
+
+impl Args {
+    /// Missing arguments are 0
+    fn next(&mut self) -> u64 {
+        let value = self.values.get(self.next).copied().unwrap_or(0);
+        self.next += 1;
+        value
+    }
+}
+
+/// A type handlers take as an argument, decoded from one or more registers
+pub trait FromArgs: Sized {
+    fn from_args(args: &mut Args) -> Result<Self, Error>;
+}
+
+impl FromArgs for u64 {
+    fn from_args(args: &mut Args) -> Result<Self, Error> {
+        Ok(args.next())
+    }
+}
+
+impl FromArgs for usize {
+    fn from_args(args: &mut Args) -> Result<Self, Error> {
+        args.next().try_into().map_err(|_| Error::InvalidArgument)
+    }
+}
+
+/// Sign extended to 64 bits by the caller
+impl FromArgs for i32 {
+    fn from_args(args: &mut Args) -> Result<Self, Error> {
+        (args.next() as i64).try_into().map_err(|_| Error::InvalidArgument)
+    }
+}
+
+/// In nanoseconds
+impl FromArgs for Duration {
+    fn from_args(args: &mut Args) -> Result<Self, Error> {
+        Ok(Duration::from_nanos(args.next()))
+    }
+}
+
+/// A pointer and a length, to memory the user can read
+impl<'a> FromArgs for &'a [u8] {
+    fn from_args(args: &mut Args) -> Result<Self, Error> {
+        let ptr = args.next();
+        let len = usize::from_args(args)?;
+        check_user_range(ptr, len, false)?;
+
+        if len == 0 {
+            return Ok(&[]);
+        }
+
+        Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len) })
+    }
+}
+
+/// A pointer and a length, to memory the user can write
+impl<'a> FromArgs for &'a mut [u8] {
+    fn from_args(args: &mut Args) -> Result<Self, Error> {
+        let ptr = args.next();
+        let len = usize::from_args(args)?;
+        check_user_range(ptr, len, true)?;
+
+        if len == 0 {
+            return Ok(&mut []);
+        }
+
+        Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len) })
+    }
+}
+
+/// Whether the `len` bytes at `start` are in user space (not just memory the
+/// user can access, which the kernel may have outside it), mapped and
+/// `writable` if asked
+pub fn check_user_range(start: u64, len: usize, writable: bool) -> Result<(), Error> {
+    if len == 0 {
+        return Ok(());
+    }
+
+    let last = start.checked_add(len as u64 - 1)
+        .filter(|&last| start >= process::USER_START && last < process::USER_END)
+        .ok_or(Error::BadPointer)?;
+
+    let mut required = PageTableFlags::USER_ACCESSIBLE;
+    if writable {
+        required |= PageTableFlags::WRITABLE;
+    }
+
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(start));
+    let last = Page::containing_address(VirtAddr::new(last));
+
+    for page in Page::range_inclusive(first, last) {
+        let flags = memory::page_flags(page.start_address());
+        if !flags.map_or(false, |flags| flags.contains(required)) {
+            return Err(Error::BadPointer);
+        }
+    }
+
+    Ok(())
+}
+
+extern "C" {
+    fn syscall_entry();
+}
+
+global_asm!(r#"
+.global syscall_entry
+syscall_entry:
+    // The user's GS base is swapped out for the kernel's, then the user's
+    // stack for the current thread's kernel stack
+    swapgs
+    mov gs:[{user_stack}], rsp
+    mov rsp, gs:[{kernel_stack}]
+
+    // The `SyscallFrame`, backwards
+    push qword ptr gs:[{user_stack}]
+    push rcx
+    push r11
+    push r15
+    push r14
+    push r13
+    push r12
+    push rbp
+    push rbx
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    push rax
+
+    mov rdi, rsp
+    // rbp is the user's (saved in the frame above), which backtraces mustn't follow
+    xor ebp, ebp
+    sti
+    call {handle}
+    cli
+
+    pop rax
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop rbx
+    pop rbp
+    pop r12
+    pop r13
+    pop r14
+    pop r15
+    pop r11
+    pop rcx
+    pop rsp
+
+    swapgs
+    sysretq
+"#,
+    user_stack = const percpu::USER_STACK_OFFSET,
+    kernel_stack = const percpu::KERNEL_STACK_OFFSET,
+    handle = sym handle,
+);
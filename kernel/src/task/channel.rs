@@ -0,0 +1,233 @@
+//! Bounded channels, from any number of [`Sender`]s to one [`Receiver`]
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    future::poll_fn,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use super::Stream;
+use crate::Locked;
+
+/// A channel holding up to `capacity` values that haven't been received yet
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channels need room for at least one value");
+
+    let state = State {
+        values: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receiver_alive: true,
+        receiver: None,
+        waiting_senders: Vec::new(),
+    };
+    let channel = Arc::new(Locked::new(state));
+
+    (Sender { channel: channel.clone() }, Receiver { channel })
+}
+
+struct State<T> {
+    values: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_alive: bool,
+    /// The receiver, waiting for a value
+    receiver: Option<Waker>,
+    /// Senders waiting for room
+    waiting_senders: Vec<Waker>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    /// The receiver was dropped
+    Closed(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel is full"),
+            TrySendError::Closed(_) => write!(f, "channel is closed"),
+        }
+    }
+}
+
+/// The receiver was dropped, with the value that couldn't be sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel is closed")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    /// Every sender was dropped, and every value received
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => write!(f, "channel is empty"),
+            TryRecvError::Closed => write!(f, "channel is closed"),
+        }
+    }
+}
+
+pub struct Sender<T> {
+    channel: Arc<Locked<State<T>>>,
+}
+
+impl<T> Sender<T> {
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.send_or_wait(value, None)
+    }
+
+    /// Waits for room in the channel, then sends `value`
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+
+        poll_fn(|cx| {
+            let pending = value.take().expect("polled after completion");
+
+            match self.send_or_wait(pending, Some(cx.waker())) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(TrySendError::Closed(pending)) => Poll::Ready(Err(SendError(pending))),
+                Err(TrySendError::Full(pending)) => {
+                    value = Some(pending);
+                    Poll::Pending
+                },
+            }
+        }).await
+    }
+
+    /// Sends `value`, or if the channel is full, has `waker` woken once there's room
+    fn send_or_wait(&self, value: T, waker: Option<&Waker>) -> Result<(), TrySendError<T>> {
+        let receiver = {
+            let mut state = self.channel.lock();
+
+            if !state.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            if state.values.len() >= state.capacity {
+                state.waiting_senders.extend(waker.cloned());
+                return Err(TrySendError::Full(value));
+            }
+
+            state.values.push_back(value);
+            state.receiver.take()
+        };
+
+        // Without the lock, as waking takes others
+        if let Some(receiver) = receiver {
+            receiver.wake();
+        }
+
+        Ok(())
+    }
+
+    /// Whether the receiver was dropped
+    pub fn is_closed(&self) -> bool {
+        !self.channel.lock().receiver_alive
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.lock().senders += 1;
+        Sender { channel: self.channel.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let receiver = {
+            let mut state = self.channel.lock();
+            state.senders -= 1;
+            if state.senders == 0 { state.receiver.take() } else { None }
+        };
+
+        // To see that the channel is closed
+        if let Some(receiver) = receiver {
+            receiver.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Locked<State<T>>>,
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.recv_or_wait(None)
+    }
+
+    /// Waits for the next value, `None` once every sender was dropped
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        match self.recv_or_wait(Some(cx.waker())) {
+            Ok(value) => Poll::Ready(Some(value)),
+            Err(TryRecvError::Closed) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    /// Takes the next value, or if the channel is empty, has `waker` woken once there's one
+    fn recv_or_wait(&mut self, waker: Option<&Waker>) -> Result<T, TryRecvError> {
+        let (value, senders) = {
+            let mut state = self.channel.lock();
+
+            match state.values.pop_front() {
+                Some(value) => (value, core::mem::take(&mut state.waiting_senders)),
+                None if state.senders == 0 => return Err(TryRecvError::Closed),
+                None => {
+                    if let Some(waker) = waker {
+                        state.receiver = Some(waker.clone());
+                    }
+                    return Err(TryRecvError::Empty);
+                },
+            }
+        };
+
+        // There's room for them now
+        for sender in senders {
+            sender.wake();
+        }
+
+        Ok(value)
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let senders = {
+            let mut state = self.channel.lock();
+            state.receiver_alive = false;
+            core::mem::take(&mut state.waiting_senders)
+        };
+
+        // To see that the channel is closed
+        for sender in senders {
+            sender.wake();
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc, task::Wake, vec::Vec};
+use core::{
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Waker},
+};
+
+use x86_64::instructions::interrupts;
+
+use super::{Task, TaskId};
+use crate::{smp, Locked};
+
+/// Runs tasks until they've all finished
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    wakers: BTreeMap<TaskId, Waker>,
+    shared: Arc<Shared>,
+}
+
+/// What wakers and spawners hand to the executor
+struct Shared {
+    queue: Locked<Queue>,
+    /// The CPU running the executor, to wake it from `hlt`
+    cpu: AtomicUsize,
+}
+
+struct Queue {
+    /// Tasks to poll, possibly more than once
+    woken: VecDeque<TaskId>,
+    /// From a [`Spawner`], not yet taken by the executor
+    spawned: Vec<Task>,
+}
+
+impl Queue {
+    fn is_empty(&self) -> bool {
+        self.woken.is_empty() && self.spawned.is_empty()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        let queue = Queue { woken: VecDeque::new(), spawned: Vec::new() };
+
+        Executor {
+            tasks: BTreeMap::new(),
+            wakers: BTreeMap::new(),
+            shared: Arc::new(Shared { queue: Locked::new(queue), cpu: AtomicUsize::new(usize::MAX) }),
+        }
+    }
+
+    pub fn spawn(&mut self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+        let task = Task::new(future);
+        let id = task.id;
+
+        self.tasks.insert(id, task);
+        self.shared.queue.lock().woken.push_back(id);
+
+        id
+    }
+
+    /// Spawns tasks on this executor from anywhere, including its own tasks
+    pub fn spawner(&self) -> Spawner {
+        Spawner { shared: self.shared.clone() }
+    }
+
+    /// Polls tasks as they're woken, until every task has finished. Tasks
+    /// spawned after that wait for the next call.
+    ///
+    /// Halts the CPU while there's nothing to poll, so interrupts must be enabled.
+    pub fn run(&mut self) {
+        // Threads stay on their CPU
+        self.shared.cpu.store(smp::current(), Ordering::Relaxed);
+
+        loop {
+            let (woken, spawned) = {
+                let mut queue = self.shared.queue.lock();
+                (core::mem::take(&mut queue.woken), core::mem::take(&mut queue.spawned))
+            };
+
+            for task in spawned {
+                let id = task.id;
+                self.tasks.insert(id, task);
+                self.poll(id);
+            }
+
+            // Only the tasks woken so far, so a task that keeps waking itself
+            // can't hold up the others
+            for id in woken {
+                self.poll(id);
+            }
+
+            if self.tasks.is_empty() {
+                break;
+            }
+
+            self.sleep_if_idle();
+        }
+
+        self.shared.cpu.store(usize::MAX, Ordering::Relaxed);
+    }
+
+    fn poll(&mut self, id: TaskId) {
+        // Already finished
+        let Some(task) = self.tasks.get_mut(&id) else {
+            return;
+        };
+
+        let waker = self.wakers.entry(id).or_insert_with(|| {
+            Waker::from(Arc::new(TaskWaker { id, shared: self.shared.clone() }))
+        });
+
+        if task.poll(&mut Context::from_waker(waker)).is_ready() {
+            self.tasks.remove(&id);
+            self.wakers.remove(&id);
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        // Checked with interrupts disabled, or a wakeup right after
+        // would be missed until the next interrupt
+        interrupts::disable();
+
+        if self.shared.queue.lock().is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Executor::new()
+    }
+}
+
+/// Spawns tasks on an [`Executor`], see [`Executor::spawner`]
+#[derive(Clone)]
+pub struct Spawner {
+    shared: Arc<Shared>,
+}
+
+impl Spawner {
+    pub fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) -> TaskId {
+        let task = Task::new(future);
+        let id = task.id;
+
+        self.shared.queue.lock().spawned.push(task);
+        smp::wake(self.shared.cpu.load(Ordering::Relaxed));
+
+        id
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    shared: Arc<Shared>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.shared.queue.lock().woken.push_back(self.id);
+        smp::wake(self.shared.cpu.load(Ordering::Relaxed));
+    }
+}
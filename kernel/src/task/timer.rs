@@ -0,0 +1,138 @@
+//! Futures driven by the APIC timer
+
+use alloc::collections::BTreeMap;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use super::Stream;
+use crate::{clock::{self, Instant}, Locked};
+
+/// Wakers of pending [`Sleep`]s, by deadline (and an ID to tell them apart)
+static TIMERS: Locked<BTreeMap<(Instant, u64), Waker>> = Locked::new(BTreeMap::new());
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Waits until `duration` has passed
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(clock::now().checked_add(duration).unwrap_or_else(clock::now))
+}
+
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep { deadline, id: NEXT_ID.fetch_add(1, Ordering::Relaxed), registered: false }
+}
+
+/// Yields the time every `period`, starting right away. Ticks that are
+/// missed (because the stream wasn't polled) are skipped.
+pub fn interval(period: Duration) -> Interval {
+    Interval { period, sleep: sleep_until(clock::now()) }
+}
+
+/// Future returned by [`sleep`] and [`sleep_until`]
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    id: u64,
+    /// Whether a waker is in `TIMERS`
+    registered: bool,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    fn key(&self) -> (Instant, u64) {
+        (self.deadline, self.id)
+    }
+
+    fn reset(&mut self, deadline: Instant) {
+        self.unregister();
+        self.deadline = deadline;
+    }
+
+    fn unregister(&mut self) {
+        if self.registered {
+            TIMERS.lock().remove(&self.key());
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Locked first, so a tick can't pass the deadline before the waker is in
+        let mut timers = TIMERS.lock();
+
+        if clock::now() >= self.deadline {
+            timers.remove(&self.key());
+            self.registered = false;
+            return Poll::Ready(());
+        }
+
+        timers.insert(self.key(), cx.waker().clone());
+        self.registered = true;
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+/// Stream returned by [`interval`]
+#[derive(Debug)]
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    /// Never ends
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Instant>> {
+        if Pin::new(&mut self.sleep).poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let now = clock::now();
+        let mut next = self.sleep.deadline() + self.period;
+        if next <= now {
+            next = now + self.period;
+        }
+        self.sleep.reset(next);
+
+        Poll::Ready(Some(now))
+    }
+}
+
+/// Wakes the timers whose deadline has passed, every tick
+pub(crate) fn tick() {
+    let now = clock::now();
+
+    loop {
+        // Each waker is woken without the lock held, as it takes others
+        let waker = {
+            let mut timers = TIMERS.lock();
+            match timers.first_key_value() {
+                Some((&(deadline, _), _)) if deadline <= now => timers.pop_first().map(|(_, waker)| waker),
+                _ => None,
+            }
+        };
+
+        match waker {
+            Some(waker) => waker.wake(),
+            None => break,
+        }
+    }
+}
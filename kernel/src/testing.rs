@@ -0,0 +1,79 @@
+use core::panic::PanicInfo;
+
+use x86_64::instructions::port::Port;
+
+use crate::{serial_print, serial_println, hlt_loop, cmdline, crash};
+
+/// Exit codes understood by the runner.
+///
+/// QEMU's `isa-debug-exit` device exits with `(code << 1) | 1`,
+/// so neither of these can collide with QEMU's own exit codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Exits QEMU through the `isa-debug-exit` device (iobase `0xF4`).
+///
+/// Halts forever if the device is not present.
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    unsafe {
+        let mut port = Port::new(0xF4);
+        port.write(exit_code as u32);
+    }
+
+    hlt_loop()
+}
+
+/// Printed over serial once the screenshot kernel has finished drawing,
+/// telling the runner to capture the framebuffer.
+pub const SCREENSHOT_READY: &str = "[kleos: screenshot ready]";
+
+pub trait Testable {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        serial_print!("{}... ", self.name());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+/// Runs every test whose name contains the command line's `test` filter
+pub fn test_runner(tests: &[&dyn Testable]) -> ! {
+    let filter = cmdline::get().test_filter().unwrap_or_default();
+    let selected = || tests.iter().filter(|test| test.name().contains(filter));
+
+    let filtered_out = tests.len() - selected().count();
+    if filtered_out > 0 {
+        serial_println!("Running {} tests ({filtered_out} filtered out by `test={filter}`)", selected().count());
+    } else {
+        serial_println!("Running {} tests", tests.len());
+    }
+
+    for test in selected() {
+        test.run();
+    }
+
+    serial_println!("All tests passed!");
+    exit_qemu(QemuExitCode::Success)
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("{info}");
+
+    // Lets the runner show where the test failed
+    crash::report(format_args!("{info}"));
+
+    exit_qemu(QemuExitCode::Failed)
+}
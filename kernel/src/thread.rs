@@ -0,0 +1,610 @@
+//! Preemptive kernel threads
+
+use alloc::{boxed::Box, collections::LinkedList, sync::Arc, vec::Vec};
+use core::{
+    arch::global_asm,
+    cell::{Cell, RefCell},
+    fmt,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    registers::control::Cr3,
+    structures::paging::PageTableFlags,
+    VirtAddr,
+};
+
+use crate::{
+    clock::{self, Instant},
+    gdt, memory,
+    paging::{self, Region},
+    percpu::{self, MAX_CPUS},
+    process::Process,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Each thread's stack
+const STACK_SIZE: u64 = 64 * 1024;
+/// Where thread stacks are mapped, each above an unmapped guard page
+const STACKS_START: u64 = 0x6000_0000_0000;
+const STACKS_STRIDE: u64 = PAGE_SIZE + STACK_SIZE;
+/// Most threads that can exist at once
+const MAX_THREADS: usize = 1024;
+
+/// `RFLAGS` of a new thread, with interrupts disabled until it's started
+const INITIAL_RFLAGS: u64 = 0x2;
+
+static SCHEDULERS: [Mutex<Scheduler>; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Mutex<Scheduler> = Mutex::new(Scheduler::new());
+    [EMPTY; MAX_CPUS]
+};
+
+/// Whether each CPU's scheduler has started
+static RUNNING: [AtomicBool; MAX_CPUS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const STOPPED: AtomicBool = AtomicBool::new(false);
+    [STOPPED; MAX_CPUS]
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Stack slots of threads that exited, their pages are still mapped
+static FREE_STACKS: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+static NEXT_STACK: AtomicUsize = AtomicUsize::new(0);
+
+crate::percpu! {
+    /// The thread running on each CPU, `None` until its scheduler starts
+    static CURRENT: RefCell<Option<Box<Thread>>> = RefCell::new(None);
+    /// Each CPU's idle thread, while it isn't running
+    static IDLE: RefCell<Option<Box<Thread>>> = RefCell::new(None);
+    /// A thread that exited, dropped (with its stack) once switched away from
+    static DEAD: RefCell<Option<Box<Thread>>> = RefCell::new(None);
+    /// Set every tick, to switch threads when the interrupt returns
+    static NEED_SWITCH: Cell<bool> = Cell::new(false);
+}
+
+/// The threads of a CPU that aren't running. Other CPUs only add to it.
+struct Scheduler {
+    ready: LinkedList<Box<Thread>>,
+    sleeping: Vec<(Instant, Box<Thread>)>,
+}
+
+impl Scheduler {
+    const fn new() -> Self {
+        Scheduler { ready: LinkedList::new(), sleeping: Vec::new() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    cpu: usize,
+    /// Stack pointer while switched out, the registers are on the stack
+    rsp: u64,
+    /// Kept until the thread is dropped, system calls and interrupts from user
+    /// mode run on it too. `None` for threads adopted from the code running
+    /// when the scheduler started.
+    stack: Option<Stack>,
+    /// Finished when the thread exits
+    packet: Option<Arc<dyn Finish + Send + Sync>>,
+    /// Whose page table the thread runs with, the kernel's if `None`
+    process: Option<Arc<Process>>,
+    idle: bool,
+}
+
+impl Thread {
+    fn new(name: &'static str, cpu: usize, stack: Option<Stack>) -> Box<Self> {
+        let id = ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed));
+
+        Box::new(Thread { id, name, cpu, rsp: 0, stack, packet: None, process: None, idle: false })
+    }
+}
+
+/// A thread's stack, freed (for reuse) when dropped
+struct Stack {
+    slot: usize,
+}
+
+impl Stack {
+    fn new() -> Result<Self, SpawnError> {
+        let slot = interrupts::without_interrupts(|| {
+            FREE_STACKS.lock().pop().or_else(|| {
+                NEXT_STACK.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| (next < MAX_THREADS).then_some(next + 1))
+                    .ok()
+            })
+        }).ok_or(SpawnError::TooManyThreads)?;
+
+        let stack = Stack { slot };
+
+        // Mapped up front, stacks can't take page faults. Reused stacks are mapped already.
+        let region = Region {
+            name: "thread stack",
+            start: stack.bottom(),
+            size: STACK_SIZE,
+            flags: PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+        };
+        paging::populate(&region).map_err(|_| SpawnError::OutOfMemory)?;
+
+        Ok(stack)
+    }
+
+    fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(STACKS_START + self.slot as u64 * STACKS_STRIDE + PAGE_SIZE)
+    }
+
+    fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| FREE_STACKS.lock().push(self.slot));
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    NoSuchCpu(usize),
+    /// The CPU isn't running threads (yet)
+    NotRunning(usize),
+    TooManyThreads,
+    /// The stack couldn't be mapped
+    OutOfMemory,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::NoSuchCpu(cpu) => write!(f, "no CPU {cpu}"),
+            SpawnError::NotRunning(cpu) => write!(f, "CPU {cpu} isn't running threads"),
+            SpawnError::TooManyThreads => write!(f, "too many threads"),
+            SpawnError::OutOfMemory => write!(f, "out of memory for the stack"),
+        }
+    }
+}
+
+/// Starts running threads on this CPU, the calling code becomes thread `name`.
+///
+/// Must run after `clock::init` (on the boot CPU) or `clock::init_ap`.
+pub(crate) fn init_cpu(name: &'static str) -> Result<(), SpawnError> {
+    let cpu = percpu::cpu();
+
+    let mut idle = new_thread("idle", cpu, Box::new(idle))?;
+    idle.idle = true;
+
+    let current = Thread::new(name, cpu, None);
+
+    interrupts::without_interrupts(|| {
+        IDLE.with(|slot| *slot.borrow_mut() = Some(idle));
+        CURRENT.with(|slot| *slot.borrow_mut() = Some(current));
+    });
+    RUNNING[cpu].store(true, Ordering::SeqCst);
+
+    Ok(())
+}
+
+fn is_running(cpu: usize) -> bool {
+    RUNNING.get(cpu).map_or(false, |running| running.load(Ordering::SeqCst))
+}
+
+/// Runs `f` in a new thread on this CPU
+pub fn spawn<F, T>(name: &'static str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_on(percpu::cpu(), name, f).expect("failed to spawn a thread")
+}
+
+/// Runs `f` in a new thread on CPU `cpu` (an index into [`smp::cpus`](crate::smp::cpus))
+pub fn spawn_on<F, T>(cpu: usize, name: &'static str, f: F) -> Result<JoinHandle<T>, SpawnError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with(cpu, name, None, f)
+}
+
+/// Runs `f` in a new thread of `process` on this CPU, with its page table
+pub(crate) fn spawn_in<F, T>(process: Arc<Process>, f: F) -> Result<JoinHandle<T>, SpawnError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with(percpu::cpu(), process.name(), Some(process), f)
+}
+
+fn spawn_with<F, T>(cpu: usize, name: &'static str, process: Option<Arc<Process>>, f: F) -> Result<JoinHandle<T>, SpawnError>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    if cpu >= MAX_CPUS {
+        return Err(SpawnError::NoSuchCpu(cpu));
+    }
+    if !is_running(cpu) {
+        return Err(SpawnError::NotRunning(cpu));
+    }
+
+    let packet = Arc::new(Packet { result: Mutex::new(None), finished: AtomicBool::new(false), waiters: WaitQueue::new() });
+
+    let thread_packet = packet.clone();
+    let entry = Box::new(move || {
+        let result = f();
+        interrupts::without_interrupts(|| *thread_packet.result.lock() = Some(result));
+    });
+
+    let mut thread = new_thread(name, cpu, entry)?;
+    thread.packet = Some(packet.clone());
+    thread.process = process;
+    let id = thread.id;
+    interrupts::without_interrupts(|| SCHEDULERS[cpu].lock().ready.push_back(thread));
+
+    Ok(JoinHandle { id, packet })
+}
+
+type Entry = Box<dyn FnOnce() + Send>;
+
+/// A thread that starts by calling `entry`
+fn new_thread(name: &'static str, cpu: usize, entry: Entry) -> Result<Box<Thread>, SpawnError> {
+    let stack = Stack::new()?;
+
+    // The frame `thread_switch` pops: flags, r15, r14, r13, r12 (the entry), rbx, rbp,
+    // then the return address
+    let entry = Box::into_raw(Box::new(entry)) as u64;
+    let frame = [INITIAL_RFLAGS, 0, 0, 0, entry, 0, 0, thread_trampoline as usize as u64];
+
+    let rsp = stack.top().as_u64() - core::mem::size_of_val(&frame) as u64;
+    unsafe { (rsp as *mut [u64; 8]).write(frame) };
+
+    let mut thread = Thread::new(name, cpu, Some(stack));
+    thread.rsp = rsp;
+
+    Ok(thread)
+}
+
+/// The results of a thread, shared with its [`JoinHandle`]
+struct Packet<T> {
+    result: Mutex<Option<T>>,
+    finished: AtomicBool,
+    waiters: WaitQueue,
+}
+
+trait Finish {
+    fn finish(&self);
+}
+
+impl<T> Finish for Packet<T> {
+    fn finish(&self) {
+        self.finished.store(true, Ordering::SeqCst);
+        self.waiters.wake_all();
+    }
+}
+
+pub struct JoinHandle<T> {
+    id: ThreadId,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.finished.load(Ordering::SeqCst)
+    }
+
+    /// Waits for the thread to finish, returning what it returned.
+    /// `None` if it called [`exit`] or was killed instead.
+    pub fn join(self) -> Option<T> {
+        let packet = &self.packet;
+        packet.waiters.wait_while(|| !packet.finished.load(Ordering::SeqCst));
+
+        interrupts::without_interrupts(|| packet.result.lock().take())
+    }
+}
+
+/// Threads waiting for something, which [`wake_all`](WaitQueue::wake_all) makes ready again
+pub struct WaitQueue {
+    // Threads are boxed everywhere, `switch` saves their stack pointer after they're parked
+    #[allow(clippy::vec_box)]
+    waiters: Mutex<Vec<Box<Thread>>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiters: Mutex::new(Vec::new()) }
+    }
+
+    /// Blocks the current thread as long as `condition` holds. Wakers have
+    /// to change what it checks before calling [`wake_all`](WaitQueue::wake_all).
+    ///
+    /// Spins instead on CPUs not running threads, or in interrupt handlers.
+    pub fn wait_while(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            let waited = interrupts::without_interrupts(|| {
+                let mut waiters = self.waiters.lock();
+                if !condition() {
+                    return None;
+                }
+
+                if !can_block() {
+                    return Some(false);
+                }
+
+                // The waiter is queued before the lock is released, so a wake up can't be missed
+                switch(false, |thread| {
+                    waiters.push(thread);
+                    drop(waiters);
+                });
+
+                Some(true)
+            });
+
+            match waited {
+                None => return,
+                Some(true) => { },
+                Some(false) => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    pub fn wake_all(&self) {
+        let waiters = interrupts::without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+
+        for thread in waiters {
+            make_ready(thread);
+        }
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Puts a thread back on its CPU's run queue
+fn make_ready(thread: Box<Thread>) {
+    interrupts::without_interrupts(|| SCHEDULERS[thread.cpu].lock().ready.push_back(thread));
+}
+
+/// Whether the current thread can switch away (and isn't the idle thread)
+fn can_block() -> bool {
+    !percpu::in_interrupt()
+        && CURRENT.with(|current| current.borrow().as_ref().map_or(false, |thread| !thread.idle))
+}
+
+/// Lets the other threads ready on this CPU run first
+pub fn yield_now() {
+    if !can_block() {
+        return;
+    }
+
+    interrupts::without_interrupts(|| switch(true, park_ready));
+}
+
+/// Blocks the current thread for at least `duration`.
+/// Busy-waits on CPUs not running threads, or in interrupt handlers.
+pub fn sleep(duration: Duration) {
+    if !can_block() {
+        clock::sleep(duration);
+        return;
+    }
+
+    let wake_at = clock::now().checked_add(duration).unwrap_or_else(clock::now);
+
+    // Woken up by `tick`
+    interrupts::without_interrupts(|| switch(false, |thread| {
+        SCHEDULERS[thread.cpu].lock().sleeping.push((wake_at, thread));
+    }));
+}
+
+/// Ends the current thread. Unless it returned, its [`JoinHandle::join`] returns `None`.
+pub fn exit() -> ! {
+    assert!(can_block(), "only threads can exit, outside of interrupt handlers");
+
+    interrupts::disable();
+
+    let packet = CURRENT.with(|current| current.borrow().as_ref().and_then(|thread| thread.packet.clone()));
+    if let Some(packet) = packet {
+        packet.finish();
+    }
+
+    switch(false, |thread| DEAD.with(|dead| *dead.borrow_mut() = Some(thread)));
+    unreachable!("exited thread was resumed");
+}
+
+/// Ends the current thread from an exception handler, so it has to be
+/// called on the thread's stack (not an IST stack). Returns if it can't be
+/// killed: it's the idle thread, or the exception interrupted an interrupt handler.
+pub(crate) fn kill_current() {
+    let killable = percpu::interrupt_depth() == 1
+        && CURRENT.with(|current| current.borrow().as_ref().map_or(false, |thread| !thread.idle));
+    if !killable {
+        return;
+    }
+
+    // The exception handler never returns
+    percpu::reset_interrupt_depth();
+    exit()
+}
+
+/// The current thread's ID and name, once the scheduler is running on this CPU
+pub fn current() -> Option<(ThreadId, &'static str)> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|thread| (thread.id, thread.name)))
+}
+
+/// The process the current thread belongs to, if any
+pub(crate) fn current_process() -> Option<Arc<Process>> {
+    CURRENT.with(|current| current.borrow().as_ref().and_then(|thread| thread.process.clone()))
+}
+
+/// Called on every timer tick: wakes sleeping threads, and asks for a switch
+pub(crate) fn tick() {
+    let cpu = percpu::cpu();
+    if !is_running(cpu) {
+        return;
+    }
+
+    let now = clock::now();
+    let woken: Vec<_> = {
+        let mut scheduler = SCHEDULERS[cpu].lock();
+        let (woken, sleeping) = core::mem::take(&mut scheduler.sleeping)
+            .into_iter()
+            .partition(|(wake_at, _)| *wake_at <= now);
+        scheduler.sleeping = sleeping;
+        woken
+    };
+
+    for (_, thread) in woken {
+        make_ready(thread);
+    }
+
+    NEED_SWITCH.with(|need_switch| need_switch.set(true));
+}
+
+/// Switches to the next ready thread, if a tick asked for it. Called as an
+/// interrupt returns, after it was acknowledged, with interrupts disabled.
+pub(crate) fn preempt() {
+    if percpu::in_interrupt() || !is_running(percpu::cpu()) {
+        return;
+    }
+
+    if NEED_SWITCH.with(|need_switch| need_switch.replace(false)) {
+        switch(true, park_ready);
+    }
+}
+
+fn park_ready(thread: Box<Thread>) {
+    SCHEDULERS[thread.cpu].lock().ready.push_back(thread);
+}
+
+/// Switches from the current thread to the next ready one, handing the
+/// current thread to `park`. With `only_if_ready`, keeps running the
+/// current thread if nothing else is ready, instead of idling.
+///
+/// Interrupts must be disabled.
+fn switch(only_if_ready: bool, park: impl FnOnce(Box<Thread>)) {
+    let cpu = percpu::cpu();
+    let (current, idle) = unsafe { (CURRENT.get(), IDLE.get()) };
+
+    let next = SCHEDULERS[cpu].lock().ready.pop_front();
+    let next = match next {
+        Some(next) => next,
+        None if only_if_ready => return,
+        None => idle.borrow_mut().take().expect("the idle thread can't block"),
+    };
+
+    let mut previous = current.borrow_mut().take().expect("no thread is running");
+    let previous_rsp: *mut u64 = &mut previous.rsp;
+    let next_rsp = next.rsp;
+    let kernel_stack = next.stack.as_ref().map(Stack::top);
+    percpu::set_kernel_stack(kernel_stack);
+    gdt::set_privilege_stack(kernel_stack);
+
+    // The kernel may have mapped memory under new level 4 entries since,
+    // which the process' page table needs before the thread runs on it
+    let page_table = next.process.as_ref()
+        .map(|process| {
+            process.sync_kernel_entries();
+            process.page_table()
+        })
+        .or_else(memory::kernel_page_table);
+    let (active, flags) = Cr3::read();
+    if let Some(page_table) = page_table && page_table != active {
+        unsafe { Cr3::write(page_table, flags) };
+    }
+
+    *current.borrow_mut() = Some(next);
+    if previous.idle {
+        *idle.borrow_mut() = Some(previous);
+    } else {
+        park(previous);
+    }
+
+    // The thread isn't dropped until it's switched away from (see `finish_switch`),
+    // and isn't resumed (by its CPU) until then either
+    unsafe { thread_switch(previous_rsp, next_rsp) };
+
+    finish_switch();
+}
+
+/// Runs on the new thread after every switch
+fn finish_switch() {
+    let dead = unsafe { DEAD.get() }.borrow_mut().take();
+    drop(dead);
+}
+
+/// Where new threads start, from `thread_trampoline`
+extern "C" fn thread_start(entry: *mut Entry) -> ! {
+    finish_switch();
+    interrupts::enable();
+
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+
+    exit()
+}
+
+fn idle() {
+    loop {
+        interrupts::enable_and_hlt();
+    }
+}
+
+extern "C" {
+    /// Saves the current thread's registers on its stack, and the stack pointer
+    /// to `previous_rsp`, then switches to `next_rsp` and restores its registers
+    fn thread_switch(previous_rsp: *mut u64, next_rsp: u64);
+    fn thread_trampoline();
+}
+
+global_asm!(r#"
+.global thread_switch
+thread_switch:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    pushfq
+    mov [rdi], rsp
+
+    mov rsp, rsi
+    popfq
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+
+// The first return of a new thread, with the entry in r12
+.global thread_trampoline
+thread_trampoline:
+    mov rdi, r12
+    and rsp, -16
+    // `thread_start` is the outermost frame of every thread
+    xor ebp, ebp
+    call {start}
+    ud2
+"#, start = sym thread_start);
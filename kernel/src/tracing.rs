@@ -1,12 +1,21 @@
 use alloc::{collections::BTreeMap, string::{String, ToString}};
-use core::fmt::Write;
+use core::{cell::Cell, fmt::Write};
 
-use tracing::{Subscriber, Metadata, span, field::Visit, Level};
+use tracing::{Subscriber, Metadata, span, field::Visit, Level, level_filters::LevelFilter};
 
-use crate::{serial_println, Locked, println, color::{ColorExt, ColorName}};
+use crate::{serial_println, Locked, println, clock, cmdline, color::{ColorExt, ColorName}, percpu};
 
+percpu! {
+    /// The span entered on each CPU
+    static CURRENT_SPAN: Cell<Option<u64>> = Cell::new(None);
+}
+
+/// Installs the kernel's subscriber, logging events up to the
+/// command line's `loglevel` (everything by default)
 pub fn init_tracing() {
-    tracing::subscriber::set_global_default(Locked::new(KernelTracingSubscriber::new()))
+    let max_level = cmdline::get().log_level().unwrap_or(LevelFilter::TRACE);
+
+    tracing::subscriber::set_global_default(Locked::new(KernelTracingSubscriber::new(max_level)))
         .unwrap();
 }
 
@@ -80,16 +89,16 @@ impl core::fmt::Display for KernelSpan {
 }
 
 struct KernelTracingSubscriber {
+    max_level: LevelFilter,
     spans: BTreeMap<u64, KernelSpan>,
-    current_span: Option<u64>,
     next_id: u64,
 }
 
 impl KernelTracingSubscriber {
-    const fn new() -> Self {
+    const fn new(max_level: LevelFilter) -> Self {
         KernelTracingSubscriber {
+            max_level,
             spans: BTreeMap::new(),
-            current_span: None,
             next_id: 1,
         }
     }
@@ -108,18 +117,15 @@ impl KernelTracingSubscriber {
         id
     }
 
-    fn enter(&mut self, span: &span::Id) {
-        self.current_span.replace(span.into_u64());
-    }
-    
-    fn exit(&mut self, _: &span::Id) {
-        self.current_span.take();
-    }
 }
 
 impl Subscriber for Locked<KernelTracingSubscriber> {
-    fn enabled(&self, _: &Metadata<'_>) -> bool {
-        true // Interested in every trace emitted
+    fn enabled(&self, meta: &Metadata<'_>) -> bool {
+        *meta.level() <= self.lock().max_level
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(self.lock().max_level)
     }
 
     fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
@@ -142,10 +148,14 @@ impl Subscriber for Locked<KernelTracingSubscriber> {
         let mut visitor = EventVisitor { record: &mut event_info };
         event.record(&mut visitor);
         
+        // Seconds since boot
+        let uptime = clock::uptime();
+        let timestamp = alloc::format!("[{:>5}.{:03}]", uptime.as_secs(), uptime.subsec_millis());
+
         let sub = self.lock();
 
-        if let Some(span_id) = sub.current_span && let Some(span) = sub.spans.get(&span_id) {
-            println!("{span}{event_info}");
+        if let Some(span_id) = CURRENT_SPAN.with(Cell::get) && let Some(span) = sub.spans.get(&span_id) {
+            println!("{timestamp} {span}{event_info}");
         } else {
             // TODO: abstract
             let meta = event.metadata();
@@ -164,17 +174,15 @@ impl Subscriber for Locked<KernelTracingSubscriber> {
             let line = meta.line().unwrap_or(0).to_string();
             let line = line.fg(level_color);
 
-            println!("[{level} {file}:{line}] {event_info}");
+            println!("{timestamp} [{level} {file}:{line}] {event_info}");
         }
     }
 
     fn enter(&self, span: &span::Id) {
-        let mut sub = self.lock();
-        sub.enter(span)
+        CURRENT_SPAN.with(|current| current.set(Some(span.into_u64())));
     }
 
-    fn exit(&self, span: &span::Id) {
-        let mut sub = self.lock();
-        sub.exit(span)
+    fn exit(&self, _: &span::Id) {
+        CURRENT_SPAN.with(|current| current.set(None));
     }
 }
@@ -0,0 +1,193 @@
+use std::{env, fmt, path::PathBuf, process, str::FromStr, time::Duration};
+
+use crate::{debugger::{self, DebuggerKind}, testing};
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootType {
+    UEFI,
+    BIOS
+}
+
+impl fmt::Display for BootType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootType::UEFI => write!(f, "uefi"),
+            BootType::BIOS => write!(f, "bios"),
+        }
+    }
+}
+
+/// Which firmware path(s) the runner should boot the kernel with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootSelection {
+    Single(BootType),
+    /// Boot with BIOS, then UEFI, one after the other
+    Both,
+}
+
+impl BootSelection {
+    pub fn boot_types(self) -> Vec<BootType> {
+        match self {
+            BootSelection::Single(boot_type) => vec![ boot_type ],
+            BootSelection::Both => vec![ BootType::BIOS, BootType::UEFI ],
+        }
+    }
+}
+
+impl FromStr for BootSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "uefi" => Ok(BootSelection::Single(BootType::UEFI)),
+            "bios" => Ok(BootSelection::Single(BootType::BIOS)),
+            "both" => Ok(BootSelection::Both),
+            other  => Err(format!("unknown boot type `{other}` (expected uefi, bios or both)")),
+        }
+    }
+}
+
+/// Which kernel the runner boots, and what it does with it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Boot the kernel
+    Run,
+    /// Boot the test kernel and exit with its result
+    Test,
+    /// Boot the screenshot kernel and compare its framebuffer against the golden images
+    Screenshot,
+}
+
+impl Mode {
+    /// Whether the run is a headless, automated check rather than an interactive run
+    pub fn is_automated(self) -> bool {
+        self != Mode::Run
+    }
+}
+
+pub struct Args {
+    pub boot: BootSelection,
+    pub headless: bool,
+    pub mode: Mode,
+    /// Overwrite the golden images with the screenshots taken
+    pub bless: bool,
+    pub timeout: Duration,
+    /// Start QEMU halted and attach a debugger to it
+    pub debugger: Option<DebuggerKind>,
+    /// Virtual address the bootloader loads the kernel ELF at
+    pub kernel_offset: u64,
+    /// Name of the VM profile in `kleos.toml`
+    pub profile: Option<String>,
+    /// Where QEMU listens for QMP connections
+    pub qmp_socket: PathBuf,
+}
+
+impl Args {
+    /// Parses the runner's command line, falling back to environment variables
+    /// (`KLEOS_BOOT`, `NO_DISPLAY`, `KLEOS_TEST_TIMEOUT`, `KLEOS_KERNEL_OFFSET`,
+    /// `KLEOS_PROFILE`, `KLEOS_QMP`)
+    /// for anything not passed explicitly.
+    pub fn parse() -> Result<Self, String> {
+        let boot = env::var("KLEOS_BOOT")
+            .ok()
+            .map(|s| s.parse())
+            .transpose()?
+            .unwrap_or(BootSelection::Single(BootType::BIOS));
+
+        let headless = env::var("NO_DISPLAY").map(|s| s == "true").unwrap_or(false);
+
+        let timeout = env::var("KLEOS_TEST_TIMEOUT")
+            .ok()
+            .map(|s| parse_timeout(&s))
+            .transpose()?
+            .unwrap_or(testing::DEFAULT_TIMEOUT);
+
+        let kernel_offset = env::var("KLEOS_KERNEL_OFFSET")
+            .ok()
+            .map(|s| parse_address(&s))
+            .transpose()?
+            .unwrap_or(debugger::DEFAULT_KERNEL_OFFSET);
+
+        let mut args = Args {
+            boot,
+            headless,
+            mode: Mode::Run,
+            bless: false,
+            timeout,
+            debugger: None,
+            kernel_offset,
+            profile: env::var("KLEOS_PROFILE").ok(),
+            qmp_socket: env::var_os("KLEOS_QMP")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| env::temp_dir().join(format!("kleos-qmp-{}.sock", process::id()))),
+        };
+
+        let mut argv = env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            // Accept both `--flag value` and `--flag=value`
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+
+            let mut value = |name: &str| {
+                inline_value.clone()
+                    .or_else(|| argv.next())
+                    .ok_or_else(|| format!("missing value for `{name}`"))
+            };
+
+            match flag.as_str() {
+                "--boot" => args.boot = value("--boot")?.parse()?,
+                "--headless" => args.headless = true,
+                "--profile" => args.profile = Some(value("--profile")?),
+                "--qmp" => args.qmp_socket = PathBuf::from(value("--qmp")?),
+                "--test" => args.mode = Mode::Test,
+                "--screenshot" => args.mode = Mode::Screenshot,
+                "--bless" => args.bless = true,
+                "--timeout" => args.timeout = parse_timeout(&value("--timeout")?)?,
+                "--gdb" => args.debugger = Some(DebuggerKind::Gdb),
+                "--lldb" => args.debugger = Some(DebuggerKind::Lldb),
+                "--kernel-offset" => args.kernel_offset = parse_address(&value("--kernel-offset")?)?,
+                "-h" | "--help" => {
+                    print_usage();
+                    process::exit(0);
+                },
+                other => return Err(format!("unknown argument `{other}`")),
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    s.parse()
+        .map(Duration::from_secs)
+        .map_err(|_| format!("invalid timeout `{s}` (expected a number of seconds)"))
+}
+
+fn parse_address(s: &str) -> Result<u64, String> {
+    let hex = s.trim_start_matches("0x").replace('_', "");
+
+    u64::from_str_radix(&hex, 16)
+        .map_err(|_| format!("invalid address `{s}` (expected a hexadecimal number)"))
+}
+
+fn print_usage() {
+    println!("Usage: cargo run -- [OPTIONS]");
+    println!();
+    println!("Options:");
+    println!("  --boot <uefi|bios|both>  Firmware to boot with [env: KLEOS_BOOT] [default: bios]");
+    println!("  --profile <name>         VM profile from kleos.toml [env: KLEOS_PROFILE] [default: default]");
+    println!("  --headless               Run QEMU without a display [env: NO_DISPLAY=true]");
+    println!("  --qmp <path>             Socket QEMU listens on for QMP [env: KLEOS_QMP] [default: $TMPDIR/kleos-qmp-<pid>.sock]");
+    println!("  --test                   Boot the test kernel headless and exit with its result");
+    println!("  --screenshot             Boot the screenshot kernel headless and compare it against tests/golden/");
+    println!("  --bless                  With --screenshot: save the screenshots as the new golden images");
+    println!("  --timeout <secs>         Kill a test run after this long [env: KLEOS_TEST_TIMEOUT] [default: 60]");
+    println!("  --gdb                    Start QEMU halted and debug the kernel with gdb");
+    println!("  --lldb                   Start QEMU halted and debug the kernel with lldb");
+    println!("  --kernel-offset <addr>   Address the kernel is loaded at, for symbols [env: KLEOS_KERNEL_OFFSET] [default: 0x8000000000]");
+    println!("  -h, --help               Print this message");
+}
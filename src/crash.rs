@@ -0,0 +1,357 @@
+//! Symbolized reports for the crash records written by `kernel::crash`
+
+use std::{borrow::Cow, fs, ops::Range, path::{Path, PathBuf}, sync::Arc};
+
+use addr2line::{
+    gimli::{self, EndianArcSlice, RunTimeEndian},
+    object::{Object, ObjectSection, ObjectSymbol, SectionKind, SymbolKind},
+    Context,
+};
+
+/// Must match `kernel::crash::CRASH_BEGIN`
+const CRASH_BEGIN: &str = "[kleos: crash begin]";
+/// Must match `kernel::crash::CRASH_END`
+const CRASH_END: &str = "[kleos: crash end]";
+
+/// Watches serial output for crash records and prints a symbolized report for each
+pub struct CrashScanner {
+    kernel: PathBuf,
+    offset: u64,
+    /// Loaded on the first crash
+    symbolizer: Option<Result<Symbolizer, String>>,
+    /// The current, incomplete line of output
+    line: Vec<u8>,
+    /// The lines of the record being read, if inside one
+    record: Option<Vec<String>>,
+}
+
+impl CrashScanner {
+    /// `kernel` is the ELF that was booted, loaded at `offset`
+    pub fn new(kernel: &Path, offset: u64) -> Self {
+        CrashScanner {
+            kernel: kernel.to_path_buf(),
+            offset,
+            symbolizer: None,
+            line: Vec::new(),
+            record: None,
+        }
+    }
+
+    /// Feeds a chunk of serial output, which doesn't have to end on a line break
+    pub fn feed(&mut self, output: &[u8]) {
+        for &byte in output {
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue;
+            }
+
+            let line = String::from_utf8_lossy(&self.line).trim_end_matches('\r').to_string();
+            self.line.clear();
+
+            self.feed_line(line);
+        }
+    }
+
+    fn feed_line(&mut self, line: String) {
+        // Records start on a line of their own, but the line
+        // before might not have been finished by the kernel
+        if line.ends_with(CRASH_BEGIN) {
+            self.record = Some(Vec::new());
+            return;
+        }
+
+        let Some(record) = &mut self.record else { return };
+
+        if line == CRASH_END {
+            let record = CrashRecord::parse(&std::mem::take(record));
+            self.record = None;
+
+            self.report(&record);
+        } else {
+            record.push(line);
+        }
+    }
+
+    fn report(&mut self, record: &CrashRecord) {
+        let symbolizer = self.symbolizer
+            .get_or_insert_with(|| Symbolizer::load(&self.kernel, self.offset));
+
+        let symbolizer = match symbolizer {
+            Ok(symbolizer) => Some(&*symbolizer),
+            Err(e) => {
+                println!("Failed to load kernel symbols from {}: {e}", self.kernel.display());
+                None
+            },
+        };
+
+        println!();
+        println!("{:=^80}", " KERNEL CRASH ");
+        println!("{}", record.reason);
+
+        if let Some(error) = record.error {
+            println!("error code: {error:#x}");
+        }
+
+        println!();
+        for (name, value) in &record.registers {
+            println!("{name:>6}: {value:#018x}");
+        }
+
+        let Some(symbolizer) = symbolizer else {
+            println!("{:=^80}", "");
+            return;
+        };
+
+        if let Some(rip) = record.register("rip") {
+            println!();
+            println!("at {rip:#018x}:");
+
+            for frame in symbolizer.frames(rip) {
+                println!("    {frame}");
+            }
+        }
+
+        if !record.frames.is_empty() {
+            println!();
+            println!("backtrace:");
+
+            for (i, return_address) in record.frames.iter().enumerate() {
+                println!("  {i:>2}: {return_address:#018x}");
+
+                // The return address is the instruction after the call.
+                // A corrupt frame can hold anything, zero included.
+                let Some(call) = return_address.checked_sub(1) else { continue };
+                for frame in symbolizer.frames(call) {
+                    println!("        {frame}");
+                }
+            }
+
+            println!("{:=^80}", "");
+            return;
+        }
+
+        // Without a frame pointer chain there's no reliable way to unwind,
+        // but any word on the stack pointing into the kernel's code
+        // is most likely a return address.
+        let return_addresses: Vec<_> = record.stack.iter()
+            .filter(|(_, value)| symbolizer.is_code(*value))
+            .collect();
+
+        if !return_addresses.is_empty() {
+            println!();
+            println!("possible return addresses on the stack:");
+
+            for (addr, value) in return_addresses {
+                println!("    [{addr:#018x}] {value:#018x}");
+
+                // The return address is the instruction after the call
+                for frame in symbolizer.frames(value.saturating_sub(1)) {
+                    println!("        {frame}");
+                }
+            }
+        }
+
+        println!("{:=^80}", "");
+    }
+}
+
+/// A crash record as written by `kernel::crash`
+#[derive(Debug, Default)]
+struct CrashRecord {
+    reason: String,
+    error: Option<u64>,
+    registers: Vec<(String, u64)>,
+    /// `(address, value)` of the words at the top of the stack
+    stack: Vec<(u64, u64)>,
+    /// Return addresses found by following the frame pointers, innermost first
+    frames: Vec<u64>,
+}
+
+impl CrashRecord {
+    /// Parses the lines between the begin and end markers, skipping anything unexpected
+    fn parse(lines: &[String]) -> Self {
+        let mut record = CrashRecord::default();
+
+        for line in lines {
+            let Some((key, value)) = line.split_once(": ") else { continue };
+
+            match key {
+                "reason" => record.reason = value.replace("\\n", "\n"),
+                "error" => record.error = parse_hex(value),
+                "register" => {
+                    if let Some((name, value)) = value.split_once(' ') && let Some(value) = parse_hex(value) {
+                        record.registers.push((name.to_string(), value));
+                    }
+                },
+                "stack" => {
+                    if let Some((addr, value)) = value.split_once(' ')
+                        && let (Some(addr), Some(value)) = (parse_hex(addr), parse_hex(value))
+                    {
+                        record.stack.push((addr, value));
+                    }
+                },
+                "frame" => record.frames.extend(parse_hex(value)),
+                _ => { },
+            }
+        }
+
+        record
+    }
+
+    fn register(&self, name: &str) -> Option<u64> {
+        self.registers.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim().trim_start_matches("0x"), 16).ok()
+}
+
+/// Looks up kernel addresses in the kernel ELF
+struct Symbolizer {
+    /// Uses `Arc`s rather than `Rc`s, as serial output is scanned on its own thread
+    context: Context<EndianArcSlice<RunTimeEndian>>,
+    /// Function symbols, sorted by address, for code without debug info
+    symbols: Vec<(Range<u64>, String)>,
+    /// The kernel's executable sections, as linked
+    code: Vec<Range<u64>>,
+    offset: u64,
+}
+
+impl Symbolizer {
+    fn load(kernel: &Path, offset: u64) -> Result<Self, String> {
+        let data = fs::read(kernel).map_err(|e| e.to_string())?;
+        let elf = addr2line::object::File::parse(&*data).map_err(|e| e.to_string())?;
+
+        let endian = if elf.is_little_endian() { RunTimeEndian::Little } else { RunTimeEndian::Big };
+        let load_section = |id: gimli::SectionId| -> Result<_, gimli::Error> {
+            let data = elf.section_by_name(id.name())
+                .and_then(|section| section.uncompressed_data().ok())
+                .unwrap_or_default();
+
+            Ok(EndianArcSlice::new(Arc::from(&*data), endian))
+        };
+
+        let dwarf = gimli::Dwarf::load(load_section).map_err(|e| e.to_string())?;
+        let context = Context::from_dwarf(dwarf).map_err(|e| e.to_string())?;
+
+        let mut symbols: Vec<_> = elf.symbols()
+            .filter(|symbol| symbol.kind() == SymbolKind::Text && symbol.size() > 0)
+            .filter_map(|symbol| {
+                let name = symbol.name().ok()?;
+                let name = addr2line::demangle_auto(Cow::Borrowed(name), None).into_owned();
+
+                Some((symbol.address()..symbol.address() + symbol.size(), name))
+            })
+            .collect();
+        symbols.sort_by_key(|(range, _)| range.start);
+
+        let code = elf.sections()
+            .filter(|section| section.kind() == SectionKind::Text)
+            .map(|section| section.address()..section.address() + section.size())
+            .collect();
+
+        Ok(Symbolizer { context, symbols, code, offset })
+    }
+
+    /// Whether `addr` (as loaded) is inside the kernel's code
+    fn is_code(&self, addr: u64) -> bool {
+        let Some(addr) = addr.checked_sub(self.offset) else { return false };
+
+        self.code.iter().any(|range| range.contains(&addr))
+    }
+
+    /// The functions at `addr` (as loaded), innermost (inlined) first
+    fn frames(&self, addr: u64) -> Vec<Frame> {
+        let Some(probe) = addr.checked_sub(self.offset) else { return Vec::new() };
+
+        let mut frames = Vec::new();
+
+        if let Ok(mut iter) = self.context.find_frames(probe) {
+            while let Ok(Some(frame)) = iter.next() {
+                let function = frame.function
+                    .and_then(|function| function.demangle().ok().map(Cow::into_owned));
+
+                let location = frame.location.map(|location| {
+                    let file = location.file.unwrap_or("??");
+                    let file = Path::new(file)
+                        .strip_prefix(env!("CARGO_MANIFEST_DIR"))
+                        .unwrap_or(Path::new(file));
+
+                    match (location.line, location.column) {
+                        (Some(line), Some(column)) => format!("{}:{line}:{column}", file.display()),
+                        (Some(line), None) => format!("{}:{line}", file.display()),
+                        _ => file.display().to_string(),
+                    }
+                });
+
+                frames.push(Frame { function, location });
+            }
+        }
+
+        // No debug info, fall back to the symbol table
+        if frames.iter().all(|frame| frame.function.is_none()) {
+            let index = self.symbols.partition_point(|(range, _)| range.start <= probe);
+
+            if let Some((range, name)) = index.checked_sub(1).map(|i| &self.symbols[i]) && range.contains(&probe) {
+                let location = frames.pop().and_then(|frame| frame.location);
+                frames = vec![ Frame { function: Some(format!("{name}+{:#x}", probe - range.start)), location } ];
+            }
+        }
+
+        frames
+    }
+}
+
+struct Frame {
+    function: Option<String>,
+    location: Option<String>,
+}
+
+impl std::fmt::Display for Frame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.function.as_deref().unwrap_or("??"))?;
+
+        if let Some(location) = &self.location {
+            write!(f, " at {location}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_record() {
+        let record = "\
+reason: page fault\\nwhile accessing 0xdead
+error: 0x2
+register: rip 0xffff800000012345
+register: rsp 0xffff800000107f58
+register: cr2 garbage
+stack: 0xffff800000107f58 0xffff800000013000
+stack: 0xffff800000107f60
+frame: 0xffff800000013000
+frame: 0x0000000000000000
+unexpected line";
+
+        let lines: Vec<_> = record.lines().map(str::to_string).collect();
+        let record = CrashRecord::parse(&lines);
+
+        assert_eq!(record.reason, "page fault\nwhile accessing 0xdead");
+        assert_eq!(record.error, Some(2));
+        assert_eq!(record.registers, [
+            ("rip".to_string(), 0xffff_8000_0001_2345),
+            ("rsp".to_string(), 0xffff_8000_0010_7f58),
+        ]);
+        assert_eq!(record.register("rip"), Some(0xffff_8000_0001_2345));
+        assert_eq!(record.register("cr2"), None);
+        assert_eq!(record.stack, [(0xffff_8000_0010_7f58, 0xffff_8000_0001_3000)]);
+        assert_eq!(record.frames, [0xffff_8000_0001_3000, 0]);
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use crate::{crash::CrashScanner, log::RunLog};
+
+/// Where bootloader v0.11 maps the (position independent) kernel when ASLR is
+/// disabled: the first level 4 entry not used by the bootloader's identity mapping.
+///
+/// Can be overridden with `--kernel-offset` (or `KLEOS_KERNEL_OFFSET`) should that ever change.
+pub const DEFAULT_KERNEL_OFFSET: u64 = 0x0000_0080_0000_0000;
+
+/// Port of QEMU's gdbstub when started with `-s`
+const GDB_PORT: u16 = 1234;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebuggerKind {
+    Gdb,
+    Lldb,
+}
+
+impl fmt::Display for DebuggerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DebuggerKind::Gdb => write!(f, "gdb"),
+            DebuggerKind::Lldb => write!(f, "lldb"),
+        }
+    }
+}
+
+impl FromStr for DebuggerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "gdb"  => Ok(DebuggerKind::Gdb),
+            "lldb" => Ok(DebuggerKind::Lldb),
+            other  => Err(format!("unknown debugger `{other}` (expected gdb or lldb)")),
+        }
+    }
+}
+
+/// A debugging session attached to QEMU's gdbstub
+struct Session {
+    kind: DebuggerKind,
+    kernel: PathBuf,
+    offset: u64,
+}
+
+impl Session {
+    /// Writes the debugger's init script and returns its path.
+    ///
+    /// The script connects to QEMU, loads the kernel's symbols at the offset
+    /// the bootloader maps it to, and breaks on `kernel_main` and the panic handler.
+    /// Hardware breakpoints are used since the kernel isn't in memory yet when
+    /// the breakpoints are set.
+    fn write_script(&self) -> io::Result<PathBuf> {
+        let Session { kind, kernel, offset } = self;
+        let kernel = kernel.display();
+
+        let path = Path::new(env!("OUT_DIR")).join(format!("kleos.{kind}"));
+        let mut file = File::create(&path)?;
+
+        match kind {
+            DebuggerKind::Gdb => {
+                writeln!(file, "set pagination off")?;
+                writeln!(file, "set confirm off")?;
+                writeln!(file, "target remote localhost:{GDB_PORT}")?;
+                writeln!(file, "add-symbol-file {kernel} -o {offset:#x}")?;
+                writeln!(file, "hbreak kernel::kernel_main")?;
+                writeln!(file, "hbreak rust_begin_unwind")?;
+                writeln!(file, "continue")?;
+            },
+            DebuggerKind::Lldb => {
+                writeln!(file, "target create {kernel}")?;
+                writeln!(file, "target modules load --file {kernel} --slide {offset:#x}")?;
+                writeln!(file, "gdb-remote localhost:{GDB_PORT}")?;
+                writeln!(file, "breakpoint set --hardware --name kernel_main")?;
+                writeln!(file, "breakpoint set --hardware --name rust_begin_unwind")?;
+                writeln!(file, "continue")?;
+            },
+        }
+
+        Ok(path)
+    }
+
+    fn command(&self, script: &Path) -> Command {
+        // The `rust-` wrappers load the pretty printers for std/alloc types
+        match self.kind {
+            DebuggerKind::Gdb => {
+                let mut cmd = Command::new("rust-gdb");
+                cmd.arg("-q").arg("-x").arg(script);
+                cmd
+            },
+            DebuggerKind::Lldb => {
+                let mut cmd = Command::new("rust-lldb");
+                cmd.arg("-s").arg(script);
+                cmd
+            },
+        }
+    }
+}
+
+pub struct Debugger {
+    cmd: Command,
+    session: Option<Session>,
+    /// Not needed with a debugger attached, which stops at the panic handler
+    crashes: Option<CrashScanner>,
+}
+
+impl Debugger {
+    pub fn wrap(cmd: Command, crashes: CrashScanner) -> Self {
+        Debugger { cmd, session: None, crashes: Some(crashes) }
+    }
+
+    /// Starts QEMU halted, waiting for `kind` to attach to its gdbstub
+    /// with the symbols from `kernel`.
+    pub fn attach(mut cmd: Command, kind: DebuggerKind, kernel: &Path, offset: u64) -> Self {
+        // `-s`: gdbstub on tcp::1234, `-S`: don't start the CPU until the debugger says so
+        cmd.args([ "-s", "-S" ]);
+
+        let session = Session { kind, kernel: kernel.to_path_buf(), offset };
+
+        Debugger { cmd, session: Some(session), crashes: None }
+    }
+
+    /// Runs QEMU, streaming its serial output to the terminal and the run's log
+    fn run(&mut self) -> io::Result<()> {
+        let log = RunLog::create(&self.cmd)?;
+
+        let mut qemu = self.cmd
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let tee = match self.crashes.take() {
+            Some(mut crashes) => log.tee_with(qemu.stdout.take().unwrap(), move |output| crashes.feed(output))?,
+            None => log.tee(qemu.stdout.take().unwrap())?,
+        };
+        let status = qemu.wait();
+
+        // The output only ends once QEMU does
+        if status.is_err() {
+            let _ = qemu.kill();
+        }
+
+        log.finish_after(tee, status).map(|_| ())
+    }
+
+    fn debug(&mut self) -> io::Result<()> {
+        let Some(session) = &self.session else { return Ok(()) };
+
+        let script = session.write_script()?;
+        println!("{} init script written to: {}", session.kind, script.display());
+
+        // The debugger owns the terminal, so serial output only goes to the log
+        let log = RunLog::create(&self.cmd)?;
+
+        let mut qemu = self.cmd
+            .stdin(Stdio::null())
+            .stdout(log.file()?)
+            .spawn()?;
+
+        let status = session.command(&script).status();
+
+        qemu.kill()?;
+        let qemu_status = qemu.wait()?;
+        log.finish(qemu_status)?;
+
+        status.map(|_| ())
+    }
+}
+
+impl Drop for Debugger {
+    fn drop(&mut self) {
+        let result = if self.session.is_some() {
+            self.debug()
+        } else {
+            self.run()
+        };
+
+        if let Err(e) = result {
+            eprintln!("Failed to run QEMU: {e}");
+        }
+    }
+}
@@ -0,0 +1,4 @@
+//! The parts of the runner that integration tests (`tests/`) can use as well
+#![feature(restricted_std)]
+
+pub mod qmp;
@@ -0,0 +1,156 @@
+use std::{
+    fmt::Display,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::PathBuf,
+    process::Command,
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use time::{format_description, OffsetDateTime};
+
+/// Log of the current (or most recent) run
+const LOG_PATH: &str = "kleos.log";
+
+/// Number of previous runs kept as `kleos.log.1` (newest) to `kleos.log.N` (oldest)
+const MAX_OLD_LOGS: usize = 9;
+
+/// The log of a single QEMU run.
+///
+/// Writes go straight to the file (`File` is unbuffered), so the log stays
+/// readable even if the kernel hangs and the runner is killed.
+pub struct RunLog {
+    file: File,
+    start: Instant,
+}
+
+impl RunLog {
+    /// Rotates the previous logs and starts a new one for a run of `cmd`
+    pub fn create(cmd: &Command) -> io::Result<Self> {
+        rotate()?;
+
+        let mut file = File::create(LOG_PATH)?;
+
+        // `now_local` fails if the local offset can't be determined soundly
+        // (e.g. other threads are running), so fall back to UTC.
+        let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        let format = format_description::parse(
+            "[year]-[month]-[day] [hour]:[minute]:[second] UTC[offset_hour sign:mandatory]:[offset_minute]"
+        ).unwrap();
+        let now = now.format(&format).unwrap();
+
+        writeln!(file, "{:-^80}", format!(" {now} "))?;
+        writeln!(file, "command: {}", command_line(cmd))?;
+        writeln!(file, "{:-^80}", "")?;
+
+        Ok(RunLog { file, start: Instant::now() })
+    }
+
+    /// A handle to the log file, e.g. to hand to a child process as its stdout
+    pub fn file(&self) -> io::Result<File> {
+        self.file.try_clone()
+    }
+
+    /// Copies everything read from `output` to both stdout and the log as soon
+    /// as it arrives, until `output` is closed.
+    pub fn tee<R>(&self, output: R) -> io::Result<JoinHandle<io::Result<()>>>
+    where
+        R: Read + Send + 'static
+    {
+        self.tee_with(output, |_| { })
+    }
+
+    /// Like [`RunLog::tee`], but also hands every chunk of output to `inspect`
+    pub fn tee_with<R, F>(&self, mut output: R, mut inspect: F) -> io::Result<JoinHandle<io::Result<()>>>
+    where
+        R: Read + Send + 'static,
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        let mut file = self.file()?;
+
+        Ok(thread::spawn(move || {
+            // Not line buffered, so partial lines (`INIT: Heap.... `) show up immediately
+            let mut buf = [0; 4096];
+
+            loop {
+                let n = match output.read(&mut buf) {
+                    Ok(0) => return Ok(()),
+                    Ok(n) => n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(&buf[..n])?;
+                stdout.flush()?;
+
+                file.write_all(&buf[..n])?;
+
+                inspect(&buf[..n]);
+            }
+        }))
+    }
+
+    /// Waits for `tee` to copy the last of the output, then records how the
+    /// run ended, as `outcome` or the error getting it. The log is finished
+    /// even if the run or the tee failed, the first error is returned after.
+    ///
+    /// The output must be closed (QEMU exited), or this waits for it.
+    pub fn finish_after<T: Display>(self, tee: JoinHandle<io::Result<()>>, outcome: io::Result<T>) -> io::Result<T> {
+        let teed = tee.join().unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "the output thread panicked")));
+
+        let finished = match &outcome {
+            Ok(status) => self.finish(status),
+            Err(e) => self.finish(format!("runner error: {e}")),
+        };
+
+        let outcome = outcome?;
+        teed?;
+        finished?;
+
+        Ok(outcome)
+    }
+
+    /// Records how the run ended
+    pub fn finish(mut self, status: impl Display) -> io::Result<()> {
+        writeln!(self.file)?;
+        writeln!(self.file, "{:-^80}", "")?;
+        writeln!(self.file, "exit:    {status}")?;
+        writeln!(self.file, "elapsed: {:.2?}", self.start.elapsed())?;
+
+        println!("Log written to: {LOG_PATH}");
+
+        Ok(())
+    }
+}
+
+/// Shifts `kleos.log.N-1` to `kleos.log.N`, ..., `kleos.log` to `kleos.log.1`,
+/// dropping the oldest log.
+fn rotate() -> io::Result<()> {
+    let path = |n: usize| match n {
+        0 => PathBuf::from(LOG_PATH),
+        n => PathBuf::from(format!("{LOG_PATH}.{n}")),
+    };
+
+    for n in (0..MAX_OLD_LOGS).rev() {
+        let from = path(n);
+
+        if from.exists() {
+            fs::rename(from, path(n + 1))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn command_line(cmd: &Command) -> String {
+    let mut line = cmd.get_program().to_string_lossy().into_owned();
+
+    for arg in cmd.get_args() {
+        line.push(' ');
+        line.push_str(&arg.to_string_lossy());
+    }
+
+    line
+}
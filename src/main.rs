@@ -1,97 +1,144 @@
-#![feature(restricted_std)]
+#![feature(let_chains, restricted_std)]
 
-use std::{process::{Command, Output}, fs::File};
+use std::{fs, path::Path, process::Command};
 
-// use time;
+mod args;
+mod crash;
+mod debugger;
+mod log;
+mod profile;
+mod screenshot;
+mod testing;
 
-#[allow(clippy::upper_case_acronyms, unused)]
-enum BootType {
-    UEFI,
-    BIOS
-}
+use args::{Args, BootType, Mode};
+use crash::CrashScanner;
+use debugger::Debugger;
+use profile::Profile;
 
-const BOOT_TYPE: BootType = BootType::BIOS;
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse()?;
+    let profile = Profile::load(args.profile.as_deref())?;
+
+    // Ctrl-C is meant for QEMU (or the debugger), the runner
+    // keeps going so it can record how the run ended.
+    ctrlc::set_handler(|| {})?;
+
+    if args.mode.is_automated() && args.debugger.is_none() {
+        let mut exit_code = 0;
+
+        for boot_type in args.boot.boot_types() {
+            let cmd = qemu_command(boot_type, &args, &profile);
+            let crashes = CrashScanner::new(kernel_path(args.mode), args.kernel_offset);
+
+            let outcome = match args.mode {
+                Mode::Test => testing::run(cmd, args.timeout, crashes)?,
+                Mode::Screenshot => screenshot::run(cmd, boot_type, &args, crashes)?,
+                Mode::Run => unreachable!(),
+            };
+            println!("Kernel {} ({boot_type}): {outcome}", match args.mode {
+                Mode::Screenshot => "screenshot",
+                _ => "tests",
+            });
+
+            if exit_code == 0 {
+                exit_code = outcome.exit_code();
+            }
+        }
+
+        std::process::exit(exit_code);
+    }
 
-struct Debugger {
-    cmd: Command
-}
+    for boot_type in args.boot.boot_types() {
+        let cmd = qemu_command(boot_type, &args, &profile);
 
-impl Debugger {
-    fn wrap(cmd: Command) -> Self {
-        Debugger { cmd }
-    }
+        let kernel = kernel_path(args.mode);
 
-    fn run(&mut self) -> Output {
-        self.cmd.output().unwrap()
+        if let Some(kind) = args.debugger {
+            let _ = Debugger::attach(cmd, kind, kernel, args.kernel_offset);
+        } else {
+            let _ = Debugger::wrap(cmd, CrashScanner::new(kernel, args.kernel_offset));
+        }
     }
-}
-
-impl Drop for Debugger {
-    fn drop(&mut self) {
-        use std::io::Write;
 
-        let output = self.run();
+    Ok(())
+}
 
-        let mut file = File::options()
-            .append(true)
-            .create(true)
-            .write(true)
-            .open("kleos.log")
-            .unwrap();
+/// The kernel ELF booted in `mode`, for its symbols
+fn kernel_path(mode: Mode) -> &'static Path {
+    let kernel = match mode {
+        Mode::Run => env!("KERNEL_PATH"),
+        Mode::Test => env!("TEST_KERNEL_PATH"),
+        Mode::Screenshot => env!("SCREENSHOT_KERNEL_PATH"),
+    };
 
-        // let now = time::OffsetDateTime::now_local().unwrap();
-        // let format = time::format_description::parse("[month]/[day]/[year] [hour]:[minute]").unwrap();
+    Path::new(kernel)
+}
 
-        writeln!(file, "{:-^48}", "").unwrap(); // format!(" {} ", now.format(&format).unwrap())).unwrap();
+fn qemu_command(boot_type: BootType, args: &Args, profile: &Profile) -> Command {
+    let (uefi_path, bios_path) = match args.mode {
+        Mode::Run => (env!("UEFI_PATH"), env!("BIOS_PATH")),
+        Mode::Test => (env!("UEFI_TEST_PATH"), env!("BIOS_TEST_PATH")),
+        Mode::Screenshot => (env!("UEFI_SCREENSHOT_PATH"), env!("BIOS_SCREENSHOT_PATH")),
+    };
 
-        let out = String::from_utf8(output.stdout).unwrap();
-        writeln!(file, "{}\n", &out).unwrap();
+    let mut cmd = Command::new("qemu-system-x86_64");
 
-        println!("{out}");
+    if args.mode.is_automated() {
+        // Shut down instead of rebooting, so a triple fault ends the run
+        cmd.args([ "-action", "reboot=shutdown" ]);
+        // Let the test kernels exit QEMU with a status code
+        cmd.args([ "-device", "isa-debug-exit,iobase=0xf4,iosize=0x04" ]);
+    } else {
+        // Freeze QEMU instead of rebooting
+        cmd.args([ "-action", "reboot=shutdown,shutdown=pause" ]);
     }
-}
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let out_dir   = env!("OUT_DIR");
-    let uefi_path = env!("UEFI_PATH");
-    let bios_path = env!("BIOS_PATH");
+    let display = if args.headless || args.mode.is_automated() { "none" } else { &profile.display };
 
-    let headless = std::env::var("NO_DISPLAY").unwrap_or("false".to_string());
-
-    let mut cmd = Command::new("qemu-system-x86_64");
     cmd
-        // Freeze QEMU instead of rebooting
-        .args([ "-action", "reboot=shutdown,shutdown=pause" ])
         // Send serial output to stdout
         .args([ "-serial", "stdio" ])
-        // Display options
-        .args([ "-display", "gtk,gl=on,full-screen=on" ])
-        // Increase memory available to QEMU
-        .args([ "-m", "4G" ]);
+        // Machine options from the selected profile (`kleos.toml`)
+        .args([ "-display", display ])
+        .args([ "-m", &profile.memory ])
+        .args([ "-smp", &profile.cpus.to_string() ]);
+
+    for device in &profile.devices {
+        cmd.args([ "-device", device ]);
+    }
+
+    for drive in &profile.drives {
+        cmd.args([ "-drive", drive ]);
+    }
 
-    if headless == "true" {
-        cmd.args([ "-display", "none" ]);
+    if let Some(network) = &profile.network {
+        cmd.args([ "-nic", network ]);
     }
 
-    match BOOT_TYPE {
+    cmd.args(&profile.args);
+
+    // Control channel for scripted interaction, see `kleos_rewrite::qmp`.
+    // A socket left over from a previous run would stop QEMU from binding it.
+    let _ = fs::remove_file(&args.qmp_socket);
+    cmd.arg("-qmp")
+        .arg(format!("unix:{},server=on,wait=off", args.qmp_socket.display()));
+
+    match boot_type {
         BootType::UEFI => {
             cmd.arg("-bios")
                 .arg(ovmf_prebuilt::ovmf_pure_efi());
             cmd.arg("-drive")
                 .arg(format!("format=raw,file={uefi_path}"));
             
-            println!("UEFI img located at: {uefi_path}");
+            println!("UEFI img located at: {uefi_path} (profile: {})", profile.name);
         },
         BootType::BIOS => {
             cmd.arg("-drive")
                 .arg(format!("format=raw,file={bios_path}"));
 
-            println!("BIOS img located at: {bios_path}");
+            println!("BIOS img located at: {bios_path} (profile: {})", profile.name);
         },
     }
 
-    let _ = Debugger::wrap(cmd);
-
-    Ok(())
+    cmd
 }
-
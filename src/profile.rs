@@ -0,0 +1,134 @@
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use serde::Deserialize;
+
+/// Name of the profile every other profile inherits from
+const DEFAULT_PROFILE: &str = "default";
+
+/// The QEMU machine the kernel is booted on
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub name: String,
+    /// Passed to `-m`, e.g. `4G`
+    pub memory: String,
+    /// Passed to `-smp`
+    pub cpus: u32,
+    /// Passed to `-display`, e.g. `gtk,gl=on`, `sdl` or `none`
+    pub display: String,
+    /// Each passed to `-device`
+    pub devices: Vec<String>,
+    /// Each passed to `-drive`, in addition to the boot image
+    pub drives: Vec<String>,
+    /// Passed to `-nic`. QEMU adds its default NIC if unset
+    pub network: Option<String>,
+    /// Passed to QEMU as-is
+    pub args: Vec<String>,
+}
+
+impl Default for Profile {
+    fn default() -> Self {
+        Profile {
+            name: DEFAULT_PROFILE.to_string(),
+            memory: "4G".to_string(),
+            cpus: 1,
+            display: "gtk,gl=on,full-screen=on".to_string(),
+            devices: Vec::new(),
+            drives: Vec::new(),
+            network: None,
+            args: Vec::new(),
+        }
+    }
+}
+
+impl Profile {
+    /// Loads the profile called `name` (or the default profile) from `kleos.toml`,
+    /// or from the file at `KLEOS_CONFIG`.
+    ///
+    /// Unset fields are inherited from `[profile.default]`, then from the
+    /// built-in defaults. A missing config file is only an error if a
+    /// profile was explicitly asked for.
+    pub fn load(name: Option<&str>) -> Result<Self, String> {
+        let path = std::env::var_os("KLEOS_CONFIG")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("kleos.toml"));
+
+        let config = match fs::read_to_string(&path) {
+            Ok(config) => config,
+            Err(_) if name.is_none() => return Ok(Profile::default()),
+            Err(e) => return Err(format!("failed to read `{}`: {e}", path.display())),
+        };
+
+        let mut config: ConfigFile = toml::from_str(&config)
+            .map_err(|e| format!("failed to parse `{}`: {e}", path.display()))?;
+
+        let default = config.profile.remove(DEFAULT_PROFILE).unwrap_or_default();
+
+        let (name, profile) = match name {
+            None | Some(DEFAULT_PROFILE) => (DEFAULT_PROFILE, ProfileConfig::default()),
+            Some(name) => match config.profile.remove(name) {
+                Some(profile) => (name, profile),
+                None => {
+                    let available = std::iter::once(DEFAULT_PROFILE)
+                        .chain(config.profile.keys().map(String::as_str))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+
+                    return Err(format!("unknown profile `{name}` (available: {available})"));
+                },
+            },
+        };
+
+        Ok(profile.or(default).resolve(name))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profile: BTreeMap<String, ProfileConfig>,
+}
+
+/// A profile as written in `kleos.toml`, where every field is optional
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ProfileConfig {
+    memory: Option<String>,
+    cpus: Option<u32>,
+    display: Option<String>,
+    devices: Option<Vec<String>>,
+    drives: Option<Vec<String>>,
+    network: Option<String>,
+    args: Option<Vec<String>>,
+}
+
+impl ProfileConfig {
+    /// Fills in unset fields from `fallback`
+    fn or(self, fallback: ProfileConfig) -> ProfileConfig {
+        ProfileConfig {
+            memory: self.memory.or(fallback.memory),
+            cpus: self.cpus.or(fallback.cpus),
+            display: self.display.or(fallback.display),
+            devices: self.devices.or(fallback.devices),
+            drives: self.drives.or(fallback.drives),
+            network: self.network.or(fallback.network),
+            args: self.args.or(fallback.args),
+        }
+    }
+
+    /// Fills in unset fields from the built-in defaults
+    fn resolve(self, name: &str) -> Profile {
+        let default = Profile::default();
+
+        Profile {
+            name: name.to_string(),
+            memory: self.memory.unwrap_or(default.memory),
+            cpus: self.cpus.unwrap_or(default.cpus),
+            display: self.display.unwrap_or(default.display),
+            devices: self.devices.unwrap_or(default.devices),
+            drives: self.drives.unwrap_or(default.drives),
+            network: self.network.or(default.network),
+            args: self.args.unwrap_or(default.args),
+        }
+    }
+}
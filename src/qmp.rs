@@ -0,0 +1,345 @@
+//! A small client for the QEMU Machine Protocol
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+#[derive(Debug)]
+pub enum QmpError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// QEMU answered with an error
+    Command { class: String, desc: String },
+    /// QEMU sent something that isn't a QMP message
+    Protocol(String),
+}
+
+impl fmt::Display for QmpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QmpError::Io(e) => write!(f, "QMP I/O error: {e}"),
+            QmpError::Json(e) => write!(f, "QMP JSON error: {e}"),
+            QmpError::Command { class, desc } => write!(f, "QMP command failed: {class}: {desc}"),
+            QmpError::Protocol(msg) => write!(f, "QMP protocol error: {msg}"),
+        }
+    }
+}
+
+impl std::error::Error for QmpError { }
+
+impl From<io::Error> for QmpError {
+    fn from(e: io::Error) -> Self {
+        QmpError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for QmpError {
+    fn from(e: serde_json::Error) -> Self {
+        QmpError::Json(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, QmpError>;
+
+pub struct Qmp {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+    /// Asynchronous events received while waiting for command responses
+    events: Vec<Value>,
+}
+
+impl Qmp {
+    /// Connects to QEMU's QMP socket at `path` and enters command mode.
+    ///
+    /// QEMU creates the socket some time after it is started,
+    /// so this retries until `timeout` has passed.
+    pub fn connect(path: impl AsRef<Path>, timeout: Duration) -> Result<Self> {
+        let start = Instant::now();
+
+        let stream = loop {
+            match UnixStream::connect(path.as_ref()) {
+                Ok(stream) => break stream,
+                Err(e) if start.elapsed() >= timeout => return Err(e.into()),
+                Err(_) => thread::sleep(Duration::from_millis(50)),
+            }
+        };
+
+        let mut qmp = Qmp {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            events: Vec::new(),
+        };
+
+        // QEMU greets with `{"QMP": {"version": ..., "capabilities": [...]}}`
+        let greeting = qmp.read_message()?;
+        if greeting.get("QMP").is_none() {
+            return Err(QmpError::Protocol(format!("unexpected greeting: {greeting}")));
+        }
+
+        qmp.execute("qmp_capabilities", None)?;
+
+        Ok(qmp)
+    }
+
+    /// Runs `command` and returns its `return` value
+    pub fn execute(&mut self, command: &str, arguments: Option<Value>) -> Result<Value> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+
+        serde_json::to_writer(&mut self.writer, &request)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+
+        loop {
+            let mut message = self.read_message()?;
+
+            if message.get("event").is_some() {
+                self.events.push(message);
+                continue;
+            }
+
+            if let Some(ret) = message.get_mut("return") {
+                return Ok(ret.take());
+            }
+
+            if let Some(error) = message.get("error") {
+                let field = |name: &str| error.get(name)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string();
+
+                return Err(QmpError::Command { class: field("class"), desc: field("desc") });
+            }
+
+            return Err(QmpError::Protocol(format!("unexpected message: {message}")));
+        }
+    }
+
+    /// Takes the events QEMU has sent so far (e.g. `STOP`, `RESET`, `SHUTDOWN`)
+    pub fn take_events(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Presses `keys` together, then releases them.
+    ///
+    /// Keys are QEMU `QKeyCode`s, e.g. `["ctrl", "alt", "delete"]` or `["shift", "a"]`.
+    pub fn send_keys(&mut self, keys: &[&str]) -> Result<()> {
+        let keys: Vec<Value> = keys.iter()
+            .map(|key| json!({ "type": "qcode", "data": key }))
+            .collect();
+
+        self.execute("send-key", Some(json!({ "keys": keys })))?;
+
+        Ok(())
+    }
+
+    /// Types `text` one key press at a time, as on a US keyboard
+    pub fn type_text(&mut self, text: &str) -> Result<()> {
+        for c in text.chars() {
+            let (key, shift) = qcode(c)
+                .ok_or_else(|| QmpError::Protocol(format!("no key for {c:?}")))?;
+
+            if shift {
+                self.send_keys(&[ "shift", key ])?;
+            } else {
+                self.send_keys(&[ key ])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the current display contents to `path` (on the host) as a PPM image
+    pub fn screendump(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let filename = path.as_ref().to_string_lossy();
+        self.execute("screendump", Some(json!({ "filename": filename })))?;
+
+        Ok(())
+    }
+
+    /// Stops all vCPUs
+    pub fn pause(&mut self) -> Result<()> {
+        self.execute("stop", None).map(|_| ())
+    }
+
+    /// Resumes all vCPUs
+    pub fn resume(&mut self) -> Result<()> {
+        self.execute("cont", None).map(|_| ())
+    }
+
+    /// Resets the machine, as if the reset button was pressed
+    pub fn reset(&mut self) -> Result<()> {
+        self.execute("system_reset", None).map(|_| ())
+    }
+
+    /// Exits QEMU immediately
+    pub fn quit(&mut self) -> Result<()> {
+        self.execute("quit", None).map(|_| ())
+    }
+
+    /// QEMU's run state, e.g. `running`, `paused` or `shutdown`
+    pub fn status(&mut self) -> Result<String> {
+        let status = self.execute("query-status", None)?;
+
+        Ok(status["status"].as_str().unwrap_or_default().to_string())
+    }
+
+    /// Runs a human monitor command (as typed into QEMU's monitor) and returns its output
+    pub fn human_monitor_command(&mut self, command: &str) -> Result<String> {
+        let output = self.execute("human-monitor-command", Some(json!({ "command-line": command })))?;
+
+        Ok(output.as_str().unwrap_or_default().to_string())
+    }
+
+    /// Reads the general purpose and control registers of the current vCPU
+    pub fn registers(&mut self) -> Result<Registers> {
+        let output = self.human_monitor_command("info registers")?;
+
+        Ok(Registers::parse(&output))
+    }
+
+    fn read_message(&mut self) -> Result<Value> {
+        let mut line = String::new();
+
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(QmpError::Io(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
+/// Register values as reported by `info registers`, keyed by their
+/// (upper case) name, e.g. `RIP`, `RSP`, `CR2` or `EFL`
+#[derive(Clone, Debug, Default)]
+pub struct Registers(BTreeMap<String, u64>);
+
+impl Registers {
+    fn parse(output: &str) -> Self {
+        // Short names are padded before the `=`, e.g. `R8 =` or `ES =`
+        let mut output = output.to_string();
+        while output.contains(" =") {
+            output = output.replace(" =", "=");
+        }
+
+        let registers = output.split_whitespace()
+            .filter_map(|word| word.split_once('='))
+            .filter(|(name, _)| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric()))
+            .filter_map(|(name, value)| Some((name.to_string(), u64::from_str_radix(value, 16).ok()?)))
+            .collect();
+
+        Registers(registers)
+    }
+
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.0.get(&name.to_ascii_uppercase()).copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), *value))
+    }
+}
+
+/// Maps `c` to its `QKeyCode` on a US keyboard, and whether shift needs to be held
+fn qcode(c: char) -> Option<(&'static str, bool)> {
+    const LOWER: [&str; 26] = [
+        "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m",
+        "n", "o", "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z",
+    ];
+    const DIGITS: [&str; 10] = [ "0", "1", "2", "3", "4", "5", "6", "7", "8", "9" ];
+
+    let key = match c {
+        'a'..='z' => (LOWER[c as usize - 'a' as usize], false),
+        'A'..='Z' => (LOWER[c as usize - 'A' as usize], true),
+        '0'..='9' => (DIGITS[c as usize - '0' as usize], false),
+        ' '  => ("spc", false),
+        '\n' => ("ret", false),
+        '\t' => ("tab", false),
+        '-'  => ("minus", false),
+        '_'  => ("minus", true),
+        '='  => ("equal", false),
+        '+'  => ("equal", true),
+        '.'  => ("dot", false),
+        '>'  => ("dot", true),
+        ','  => ("comma", false),
+        '<'  => ("comma", true),
+        '/'  => ("slash", false),
+        '?'  => ("slash", true),
+        ';'  => ("semicolon", false),
+        ':'  => ("semicolon", true),
+        '\'' => ("apostrophe", false),
+        '"'  => ("apostrophe", true),
+        '!'  => ("1", true),
+        '@'  => ("2", true),
+        '#'  => ("3", true),
+        '$'  => ("4", true),
+        '%'  => ("5", true),
+        '^'  => ("6", true),
+        '&'  => ("7", true),
+        '*'  => ("8", true),
+        '('  => ("9", true),
+        ')'  => ("0", true),
+        _ => return None,
+    };
+
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_REGISTERS: &str = "\
+RAX=0000000000000001 RBX=0000000000000002 RCX=0000000000000003 RDX=0000000000000004
+RSI=0000000000000005 RDI=0000000000000006 RBP=0000010000007fc0 RSP=0000010000007f80
+R8 =0000000000000008 R9 =0000000000000009 R10=000000000000000a R11=000000000000000b
+R12=000000000000000c R13=000000000000000d R14=000000000000000e R15=000000000000000f
+RIP=0000008000012345 RFL=00000246 [---Z-P-] CPL=0 II=0 A20=1 SMM=0 HLT=1
+ES =0010 0000000000000000 ffffffff 00cf9300 DPL=0 DS   [-WA]
+CS =0008 0000000000000000 ffffffff 00af9b00 DPL=0 CS64 [-RA]
+GDT=     0000008000100000 00000037
+CR0=80010033 CR2=0000000000000000 CR3=0000000001000000 CR4=00000620
+EFER=0000000000000d01
+";
+
+    #[test]
+    fn parses_info_registers() {
+        let registers = Registers::parse(INFO_REGISTERS);
+
+        assert_eq!(registers.get("rax"), Some(1));
+        assert_eq!(registers.get("R8"), Some(8));
+        assert_eq!(registers.get("r15"), Some(0xf));
+        assert_eq!(registers.get("rip"), Some(0x8000012345));
+        assert_eq!(registers.get("rsp"), Some(0x10000007f80));
+        assert_eq!(registers.get("rfl"), Some(0x246));
+        assert_eq!(registers.get("cr3"), Some(0x1000000));
+        assert_eq!(registers.get("efer"), Some(0xd01));
+        assert_eq!(registers.get("es"), Some(0x10));
+        assert_eq!(registers.get("cs"), Some(0x8));
+        // Not a single value
+        assert_eq!(registers.get("gdt"), None);
+        assert_eq!(registers.get("xmm0"), None);
+    }
+
+    #[test]
+    fn maps_characters_to_qcodes() {
+        assert_eq!(qcode('a'), Some(("a", false)));
+        assert_eq!(qcode('Q'), Some(("q", true)));
+        assert_eq!(qcode('7'), Some(("7", false)));
+        assert_eq!(qcode('&'), Some(("7", true)));
+        assert_eq!(qcode('?'), Some(("slash", true)));
+        assert_eq!(qcode('\n'), Some(("ret", false)));
+        assert_eq!(qcode(' '), Some(("spc", false)));
+        assert_eq!(qcode('é'), None);
+    }
+}
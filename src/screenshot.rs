@@ -0,0 +1,298 @@
+//! Golden image tests for the kernel's framebuffer text rendering
+
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    time::Duration,
+};
+
+use kleos_rewrite::qmp::Qmp;
+
+use crate::{args::{Args, BootType}, crash::CrashScanner, log::RunLog, testing::TestOutcome};
+
+/// Must match `kernel::testing::SCREENSHOT_READY`
+const SCREENSHOT_READY: &str = "[kleos: screenshot ready]";
+
+const QMP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Boots the screenshot kernel, captures its framebuffer once it is done
+/// drawing and compares the capture against the golden image.
+///
+/// With `--bless`, the capture replaces the golden image instead.
+pub fn run(mut cmd: Command, boot_type: BootType, args: &Args, mut crashes: CrashScanner) -> io::Result<TestOutcome> {
+    let log = RunLog::create(&cmd)?;
+
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // Watch the serial output for the kernel saying it's done
+    let (ready_tx, ready_rx) = mpsc::channel();
+    let mut serial = String::new();
+
+    let tee = log.tee_with(child.stdout.take().unwrap(), move |output| {
+        crashes.feed(output);
+
+        serial.push_str(&String::from_utf8_lossy(output));
+
+        if serial.contains(SCREENSHOT_READY) {
+            let _ = ready_tx.send(());
+            serial.clear();
+        }
+    })?;
+
+    let outcome = screenshot(&mut child, boot_type, args, ready_rx);
+
+    // The output only ends once QEMU does
+    if outcome.is_err() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    log.finish_after(tee, outcome)
+}
+
+/// Waits for the kernel to be done drawing, then captures and compares its framebuffer
+fn screenshot(child: &mut Child, boot_type: BootType, args: &Args, ready: Receiver<()>) -> io::Result<TestOutcome> {
+    fs::create_dir_all(golden_dir())?;
+
+    let golden = golden_dir().join(format!("text-{boot_type}.ppm"));
+    let actual = golden.with_extension("actual.ppm");
+    let diff = golden.with_extension("diff.ppm");
+
+    match ready.recv_timeout(args.timeout) {
+        Ok(()) => {
+            let captured = capture(&args.qmp_socket, &actual);
+
+            // Don't leave QEMU running if it couldn't be told to quit
+            if captured.is_err() {
+                let _ = child.kill();
+            }
+
+            child.wait()?;
+            captured?;
+
+            compare(&actual, &golden, &diff, args.bless)
+        },
+        Err(RecvTimeoutError::Timeout) => {
+            child.kill()?;
+            child.wait()?;
+
+            Ok(TestOutcome::TimedOut)
+        },
+        // Serial output ended without the kernel getting to the end of the script
+        Err(RecvTimeoutError::Disconnected) => {
+            let status = child.wait()?;
+            Ok(TestOutcome::Unexpected(status.code()))
+        },
+    }
+}
+
+/// Dumps the display to `path` and exits QEMU
+fn capture(qmp_socket: &Path, path: &Path) -> io::Result<()> {
+    let mut qmp = Qmp::connect(qmp_socket, QMP_TIMEOUT).map_err(to_io)?;
+    qmp.screendump(path).map_err(to_io)?;
+    qmp.quit().map_err(to_io)?;
+
+    Ok(())
+}
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+}
+
+fn compare(actual: &Path, golden: &Path, diff: &Path, bless: bool) -> io::Result<TestOutcome> {
+    if bless {
+        fs::rename(actual, golden)?;
+        println!("Blessed golden image: {}", golden.display());
+
+        return Ok(TestOutcome::Passed);
+    }
+
+    if !golden.exists() {
+        println!("Missing golden image {}, run with `--screenshot --bless` to create it", golden.display());
+        println!("Screenshot saved to: {}", actual.display());
+
+        return Ok(TestOutcome::Failed);
+    }
+
+    let actual_image = Image::read_ppm(actual)?;
+    let golden_image = Image::read_ppm(golden)?;
+
+    if (actual_image.width, actual_image.height) != (golden_image.width, golden_image.height) {
+        println!(
+            "Screenshot is {}x{}, but the golden image is {}x{}",
+            actual_image.width, actual_image.height,
+            golden_image.width, golden_image.height,
+        );
+        println!("Screenshot saved to: {}", actual.display());
+
+        return Ok(TestOutcome::Failed);
+    }
+
+    let (diff_image, mismatches) = golden_image.diff(&actual_image);
+
+    if mismatches == 0 {
+        fs::remove_file(actual)?;
+        let _ = fs::remove_file(diff);
+
+        return Ok(TestOutcome::Passed);
+    }
+
+    diff_image.write_ppm(diff)?;
+
+    println!("{mismatches} pixels differ from {}", golden.display());
+    println!("Screenshot saved to: {}", actual.display());
+    println!("Differences (in red) saved to: {}", diff.display());
+
+    Ok(TestOutcome::Failed)
+}
+
+fn to_io(e: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+/// An RGB image, as written by QEMU's `screendump`
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    /// Reads a binary (`P6`) PPM with 8 bits per channel
+    fn read_ppm(path: &Path) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {msg}", path.display()));
+
+        let mut reader = BufReader::new(fs::File::open(path)?);
+
+        // The header is `P6`, width, height and max value, separated by whitespace
+        // (with `#` comments), followed by a single whitespace character.
+        let mut fields = Vec::new();
+        while fields.len() < 4 {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(invalid("truncated header"));
+            }
+
+            let line = line.split('#').next().unwrap();
+            fields.extend(line.split_whitespace().map(str::to_string));
+        }
+
+        if fields[0] != "P6" || fields[3] != "255" {
+            return Err(invalid("not an 8-bit binary PPM"));
+        }
+
+        let width: usize = fields[1].parse().map_err(|_| invalid("invalid width"))?;
+        let height: usize = fields[2].parse().map_err(|_| invalid("invalid height"))?;
+
+        let mut data = vec![0; width * height * 3];
+        reader.read_exact(&mut data)?;
+
+        let pixels = data.chunks_exact(3)
+            .map(|p| [p[0], p[1], p[2]])
+            .collect();
+
+        Ok(Image { width, height, pixels })
+    }
+
+    fn write_ppm(&self, path: &Path) -> io::Result<()> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+
+        write!(file, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for pixel in &self.pixels {
+            file.write_all(pixel)?;
+        }
+
+        file.flush()
+    }
+
+    /// Returns an image of `self` dimmed, with the pixels that differ
+    /// in `other` highlighted in red, and the number of differing pixels.
+    fn diff(&self, other: &Image) -> (Image, usize) {
+        let mut mismatches = 0;
+
+        let pixels = self.pixels.iter()
+            .zip(&other.pixels)
+            .map(|(a, b)| {
+                if a == b {
+                    a.map(|c| c / 3)
+                } else {
+                    mismatches += 1;
+                    [ 0xFF, 0x00, 0x00 ]
+                }
+            })
+            .collect();
+
+        (Image { width: self.width, height: self.height, pixels }, mismatches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2x1 image: one red pixel, one white pixel
+    const PPM: &[u8] = b"P6\n# screendump\n2 1\n255\n\xFF\x00\x00\xFF\xFF\xFF";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kleos-screenshot-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn reads_ppm() {
+        let dir = temp_dir("read");
+        let path = dir.join("image.ppm");
+        fs::write(&path, PPM).unwrap();
+
+        let image = Image::read_ppm(&path).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.pixels, [[0xFF, 0x00, 0x00], [0xFF, 0xFF, 0xFF]]);
+
+        fs::write(&path, &PPM[..PPM.len() - 1]).unwrap();
+        assert_eq!(Image::read_ppm(&path).err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+
+        fs::write(&path, b"P3\n2 1\n255\n").unwrap();
+        assert_eq!(Image::read_ppm(&path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn diffs_images() {
+        let golden = Image { width: 2, height: 1, pixels: vec![[0x30, 0x60, 0x90], [0xFF, 0xFF, 0xFF]] };
+        let actual = Image { width: 2, height: 1, pixels: vec![[0x30, 0x60, 0x90], [0xFF, 0xFF, 0xFE]] };
+
+        let (diff, mismatches) = golden.diff(&actual);
+        assert_eq!(mismatches, 1);
+        assert_eq!(diff.pixels, [[0x10, 0x20, 0x30], [0xFF, 0x00, 0x00]]);
+
+        assert_eq!(golden.diff(&golden).1, 0);
+    }
+
+    #[test]
+    fn compares_against_golden() {
+        let dir = temp_dir("compare");
+        let (actual, golden, diff) = (dir.join("actual.ppm"), dir.join("golden.ppm"), dir.join("diff.ppm"));
+
+        fs::write(&actual, PPM).unwrap();
+        assert_eq!(compare(&actual, &golden, &diff, false).unwrap(), TestOutcome::Failed);
+
+        fs::write(&golden, PPM).unwrap();
+        assert_eq!(compare(&actual, &golden, &diff, false).unwrap(), TestOutcome::Passed);
+        assert!(!actual.exists());
+
+        let mut changed = PPM.to_vec();
+        *changed.last_mut().unwrap() = 0;
+        fs::write(&actual, changed).unwrap();
+        assert_eq!(compare(&actual, &golden, &diff, false).unwrap(), TestOutcome::Failed);
+        assert_eq!(Image::read_ppm(&diff).unwrap().pixels, [[0x55, 0x00, 0x00], [0xFF, 0x00, 0x00]]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
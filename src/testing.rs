@@ -0,0 +1,93 @@
+use std::{fmt, io, process::{Child, Command, Stdio}, thread, time::{Duration, Instant}};
+
+use crate::{crash::CrashScanner, log::RunLog};
+
+// Must match `kernel::testing::QemuExitCode`. QEMU's `isa-debug-exit`
+// device exits with `(code << 1) | 1`.
+const QEMU_EXIT_SUCCESS: i32 = (0x10 << 1) | 1;
+const QEMU_EXIT_FAILED: i32 = (0x11 << 1) | 1;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+    TimedOut,
+    /// QEMU exited without going through `isa-debug-exit`,
+    /// e.g. after a triple fault or if it failed to start.
+    Unexpected(Option<i32>),
+}
+
+impl TestOutcome {
+    /// The exit status the runner process should report for this outcome
+    pub fn exit_code(self) -> i32 {
+        match self {
+            TestOutcome::Passed => 0,
+            TestOutcome::Failed => 1,
+            TestOutcome::TimedOut => 2,
+            TestOutcome::Unexpected(_) => 3,
+        }
+    }
+}
+
+impl fmt::Display for TestOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestOutcome::Passed => write!(f, "passed"),
+            TestOutcome::Failed => write!(f, "failed"),
+            TestOutcome::TimedOut => write!(f, "timed out"),
+            TestOutcome::Unexpected(Some(code)) => write!(f, "QEMU exited unexpectedly with code {code}"),
+            TestOutcome::Unexpected(None) => write!(f, "QEMU was terminated by a signal"),
+        }
+    }
+}
+
+/// Boots the test kernel and waits for it to exit QEMU, killing QEMU
+/// if it hasn't exited within `timeout`.
+///
+/// Serial output is streamed to the terminal and the run's log,
+/// so test progress is shown as it happens. A failing test's panic
+/// is reported by `crashes`.
+pub fn run(mut cmd: Command, timeout: Duration, mut crashes: CrashScanner) -> io::Result<TestOutcome> {
+    let log = RunLog::create(&cmd)?;
+
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let tee = log.tee_with(child.stdout.take().unwrap(), move |output| crashes.feed(output))?;
+    let outcome = wait(&mut child, timeout);
+
+    // The output only ends once QEMU does
+    if outcome.is_err() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+
+    log.finish_after(tee, outcome)
+}
+
+/// Waits for the test kernel to exit QEMU, or kills it after `timeout`
+fn wait(child: &mut Child, timeout: Duration) -> io::Result<TestOutcome> {
+    let start = Instant::now();
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(match status.code() {
+                Some(QEMU_EXIT_SUCCESS) => TestOutcome::Passed,
+                Some(QEMU_EXIT_FAILED) => TestOutcome::Failed,
+                code => TestOutcome::Unexpected(code),
+            });
+        }
+
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+
+            return Ok(TestOutcome::TimedOut);
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    }
+}
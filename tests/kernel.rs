@@ -0,0 +1,16 @@
+//! Boots the test kernel (`kernel/src/bin/tests.rs`) headless in QEMU
+//! through the runner's `--test` mode.
+
+use std::process::Command;
+
+#[test]
+fn kernel_tests() {
+    // BIOS and UEFI run one after the other in a single runner,
+    // since every run rotates the same `kleos.log`.
+    let status = Command::new(env!("CARGO_BIN_EXE_kleos-rewrite"))
+        .args([ "--test", "--boot", "both" ])
+        .status()
+        .expect("Failed to start the runner");
+
+    assert!(status.success(), "kernel tests failed: {status}");
+}
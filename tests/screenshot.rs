@@ -0,0 +1,16 @@
+//! Compares the screenshot kernel's (`kernel/src/bin/screenshot.rs`) framebuffer
+//! against the golden images in `tests/golden/` through the runner's `--screenshot` mode.
+
+use std::process::Command;
+
+// Runs once the golden images are committed, see `tests/golden/README.md`
+#[test]
+#[ignore = "no golden images yet, capture them with `cargo run -- --screenshot --bless --boot both`"]
+fn framebuffer_matches_golden_images() {
+    let status = Command::new(env!("CARGO_BIN_EXE_kleos-rewrite"))
+        .args([ "--screenshot", "--boot", "both" ])
+        .status()
+        .expect("Failed to start the runner");
+
+    assert!(status.success(), "framebuffer differs from the golden images: {status}");
+}